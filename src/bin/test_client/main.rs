@@ -1,6 +1,5 @@
 use std::{
     collections::BTreeMap,
-    io::{Read, Write},
     net::{Shutdown, TcpStream},
 };
 
@@ -8,7 +7,8 @@ use unionize::{
     easy::uniform::*,
     item::le_byte_array::LEByteArray,
     object::Object,
-    protocol::{first_message, respond_to_message, Message},
+    protocol::{split::UniformSplit, Format, MessageLimits, Role, Session},
+    transport::{blocking::sync_over, TransportConfig, TransportError},
 };
 
 use serde::{Deserialize, Serialize};
@@ -54,7 +54,7 @@ fn main() -> std::io::Result<()> {
 }
 
 fn handle_connection(
-    mut stream: TcpStream,
+    stream: TcpStream,
     tree: &mut Node,
     objects: &mut BTreeMap<Item, TestObject>,
 ) -> std::io::Result<Vec<TestObject>> {
@@ -64,53 +64,45 @@ fn handle_connection(
         stream.peer_addr().unwrap()
     );
 
-    let first: Message<Monoid, (Item, bool)> = first_message(tree).unwrap();
-    let mut learned = vec![];
-    let msg = serde_cbor::to_vec(&first).unwrap();
-    write_frame(&mut stream, &msg)?;
-
-    loop {
-        let msg_bytes = read_frame(&mut stream)?;
-        let msg: Message<Monoid, TestObject> = serde_cbor::from_slice(&msg_bytes).unwrap();
-        if msg.is_end() {
-            break;
-        }
-        let (resp, new_objs) = respond_to_message(tree, objects, &msg, 3, split::<2>).unwrap();
-        println!("new objects: {new_objs:?}");
-        for obj in new_objs {
-            *tree = tree.insert(obj.to_item());
-            objects.insert(obj.to_item(), obj.clone());
-            learned.push(obj);
-        }
-
-        let msg_bytes = serde_cbor::to_vec(&resp).unwrap();
-        write_frame(&mut stream, &msg_bytes)?;
-        if resp.is_end() {
-            break;
+    // client and server are built with the same features, so they agree on the default format
+    let config = TransportConfig::default();
+    stream.set_read_timeout(config.timeout)?;
+    stream.set_write_timeout(config.timeout)?;
+    let limits = MessageLimits {
+        max_bytes: config.max_frame_size,
+        format: Format::default(),
+        ..Default::default()
+    };
+    let mut session = Session::new(Role::Initiator, 3, UniformSplit::<2>).with_limits(limits);
+
+    let synced = match sync_over(&stream, &mut session, tree, objects, &config) {
+        Ok(synced) => synced,
+        Err(TransportError::Handshake(e)) => {
+            println!("can't sync with server: {e}");
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(vec![]);
         }
-    }
-
+        Err(e) => return Err(std::io::Error::other(e.to_string())),
+    };
     stream.shutdown(Shutdown::Both)?;
-    Ok(learned)
-}
 
-fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> std::io::Result<()> {
-    let l: u16 = payload.len().try_into().unwrap();
-    let l_bs = l.to_be_bytes();
-
-    stream.write(&l_bs)?;
-    stream.write(payload)?;
-    Ok(())
-}
+    if !synced.unavailable().is_empty() {
+        println!("peer doesn't have: {:?}", synced.unavailable());
+    }
+    for rejected in synced.rejected() {
+        println!(
+            "rejected object {:?}: {}",
+            rejected.object(),
+            rejected.reason()
+        );
+    }
+    println!("stats: {:?}", session.stats());
 
-fn read_frame(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
-    let mut l_bs = [0u8; 2];
-    stream.read(&mut l_bs)?;
-    let l = u16::from_be_bytes(l_bs) as usize;
+    let learned = synced.into_received();
+    for obj in &learned {
+        *tree = tree.insert(obj.to_item());
+        objects.insert(obj.to_item(), obj.clone());
+    }
 
-    let mut buf = vec![0u8; l];
-    stream.read(&mut buf)?;
-    println!("received data");
-    std::io::stdout().flush()?;
-    Ok(buf)
+    Ok(learned)
 }
@@ -1,13 +1,30 @@
+//! A sync server that serves any number of clients at once.
+//!
+//! Every session works on a snapshot of the set and its tree, taken when the client connects. The
+//! objects learned from clients are handed to a single writer thread, which merges them into the
+//! set, publishes a new snapshot for the sessions that start afterwards, and appends them to the
+//! store, so they survive restarts.
+//!
+//! Usage: `test_server [--listen ADDR] [--threshold N] [--split N] [--store PATH]`
+
 use std::{
-    collections::BTreeMap,
-    io::{Read, Write},
-    net::{Shutdown, TcpListener, TcpStream},
+    collections::{btree_map::Entry, BTreeMap},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, RwLock,
+    },
+    thread,
 };
 
 use unionize::{
     easy::uniform::*,
     object::Object,
-    protocol::{respond_to_message, Message},
+    protocol::{Format, MessageLimits, Role, Session, SessionBudget},
+    transport::{blocking::sync_over, TransportConfig},
 };
 
 use serde::{Deserialize, Serialize};
@@ -24,66 +41,290 @@ impl Object<Item> for TestObject {
     }
 }
 
+type Objects = BTreeMap<Item, TestObject>;
+
+/// Clients beyond this many are turned away.
+const MAX_CONNECTIONS: usize = 64;
+
+/// A version of the set, along with the tree over its items.
+struct Snapshot {
+    objects: Objects,
+    tree: SharedNode,
+}
+
+/// The latest snapshot. Sessions clone the `Arc` and never block the writer.
+type Latest = Arc<RwLock<Arc<Snapshot>>>;
+
+#[derive(Debug, Clone)]
+struct Args {
+    listen: SocketAddr,
+    threshold: usize,
+    split: usize,
+    store: PathBuf,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            listen: "127.0.0.1:2342".parse().unwrap(),
+            threshold: 3,
+            split: 2,
+            store: PathBuf::from("test_server.set"),
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(flag) = iter.next() {
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("missing value for {flag}"))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid value for {flag}: {e}");
+            match flag.as_str() {
+                "--listen" => args.listen = value.parse().map_err(|e| invalid(&e))?,
+                "--threshold" => args.threshold = value.parse().map_err(|e| invalid(&e))?,
+                "--split" => args.split = value.parse().map_err(|e| invalid(&e))?,
+                "--store" => args.store = PathBuf::from(value),
+                _ => return Err(format!("unknown argument {flag}")),
+            }
+        }
+
+        if args.split < 2 {
+            return Err("--split needs to be at least 2".into());
+        }
+
+        Ok(args)
+    }
+}
+
 fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:2342")?;
-    println!("listening in {}", listener.local_addr()?);
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!(
+                "usage: test_server [--listen ADDR] [--threshold N] [--split N] [--store PATH]"
+            );
+            std::process::exit(2);
+        }
+    };
+
+    let (objects, store) = load(&args.store)?;
+    println!(
+        "loaded {} objects from {}",
+        objects.len(),
+        args.store.display()
+    );
+
+    let mut tree = SharedNode::nil();
+    for item in objects.keys() {
+        tree = tree.insert(*item);
+    }
+    let latest: Latest = Arc::new(RwLock::new(Arc::new(Snapshot { objects, tree })));
+    let (merge_tx, merge_rx) = mpsc::channel();
+    {
+        let latest = latest.clone();
+        thread::spawn(move || merge(merge_rx, &latest, store));
+    }
 
-    let mut tree = Node::nil();
-    let mut objects = BTreeMap::new();
+    let listener = TcpListener::bind(args.listen)?;
+    println!("listening on {}", listener.local_addr()?);
 
+    let connections = Arc::new(AtomicUsize::new(0));
     for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_connection(stream, &mut tree, &mut objects)?;
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let Some(slot) = Slot::take(&connections) else {
+            println!(
+                "{:?}: too many connections, turning it away",
+                stream.peer_addr()
+            );
+            continue;
+        };
+
+        let snapshot = latest.read().unwrap().clone();
+        let merge_tx = merge_tx.clone();
+        let args = args.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let peer = stream.peer_addr();
+            if let Err(e) = handle_connection(stream, &snapshot, &merge_tx, &args) {
+                println!("{peer:?}: connection failed: {e}");
+            }
+        });
     }
 
     Ok(())
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
-    tree: &mut Node,
-    objects: &mut BTreeMap<Item, TestObject>,
-) -> std::io::Result<()> {
-    loop {
-        let payload = read_frame(&mut stream)?;
-        let msg: Message<Monoid, TestObject> = serde_cbor::from_slice(&payload).unwrap();
-        if msg.is_end() {
-            break;
+/// One of the [`MAX_CONNECTIONS`] connections, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Slot(connections.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The single writer: merges what the sessions learned, publishes the result and persists the
+/// new objects. Whatever arrived in the meantime is merged in one go.
+fn merge(rx: mpsc::Receiver<Vec<TestObject>>, latest: &Latest, mut store: File) {
+    let (mut objects, mut tree) = {
+        let snapshot = latest.read().unwrap();
+        (snapshot.objects.clone(), snapshot.tree.clone())
+    };
+
+    while let Ok(learned) = rx.recv() {
+        let mut new = Vec::new();
+        for obj in learned.into_iter().chain(rx.try_iter().flatten()) {
+            if let Entry::Vacant(entry) = objects.entry(obj.to_item()) {
+                tree = tree.insert(*entry.key());
+                entry.insert(obj.clone());
+                new.push(obj);
+            }
+        }
+        if new.is_empty() {
+            continue;
         }
 
-        let (resp, new_objs) = respond_to_message(tree, objects, &msg, 3, split::<2>).unwrap();
-        for obj in new_objs {
-            *tree = tree.insert(obj.to_item());
-            objects.insert(obj.to_item(), obj);
+        *latest.write().unwrap() = Arc::new(Snapshot {
+            objects: objects.clone(),
+            tree: tree.clone(),
+        });
+        match append(&mut store, &new) {
+            Ok(()) => println!("now holding {} objects", objects.len()),
+            Err(e) => println!("failed to persist {} objects: {e}", new.len()),
         }
+    }
+}
 
-        let msg_bytes = serde_cbor::to_vec(&resp).unwrap();
-        write_frame(&mut stream, &msg_bytes)?;
-        if resp.is_end() {
-            break;
+fn handle_connection(
+    stream: TcpStream,
+    snapshot: &Snapshot,
+    merge_tx: &mpsc::Sender<Vec<TestObject>>,
+    args: &Args,
+) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let Snapshot { objects, tree } = snapshot;
+
+    // client and server are built with the same features, so they agree on the default format
+    let config = TransportConfig::default();
+    stream.set_read_timeout(config.timeout)?;
+    stream.set_write_timeout(config.timeout)?;
+    // a single part may still exceed max_bytes, so leave room for it in the frame. our parts
+    // are small, since objects are just items.
+    let limits = MessageLimits {
+        max_bytes: config.max_frame_size / 2,
+        format: Format::default(),
+        ..Default::default()
+    };
+    let fanout = args.split;
+    let split = move |n| split_into(n, fanout);
+    // the client can only want objects we listed, so each of them at most once
+    let budget = SessionBudget {
+        max_objects: objects.len(),
+        ..Default::default()
+    };
+    let mut session = Session::new(Role::Responder, args.threshold, split)
+        .with_limits(limits)
+        .with_budget(budget)
+        .with_part_sizes(true);
+
+    let result = sync_over(&stream, &mut session, tree, objects, &config);
+    // the client may have hung up already
+    match stream.shutdown(Shutdown::Both) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotConnected => return Err(e),
+        _ => {}
+    }
+    let synced = match result {
+        Ok(synced) => synced,
+        Err(e) => {
+            println!("{peer}: can't sync with client: {e}");
+            return Ok(());
         }
+    };
+
+    if !synced.unavailable().is_empty() {
+        println!("{peer}: peer doesn't have: {:?}", synced.unavailable());
+    }
+    for rejected in synced.rejected() {
+        println!(
+            "{peer}: rejected object {:?}: {}",
+            rejected.object(),
+            rejected.reason()
+        );
     }
 
-    stream.shutdown(Shutdown::Both)?;
-    Ok(())
-}
+    let stats = session.stats();
+    println!(
+        "{peer}: done after {} rounds. sent {} bytes, {} objects. received {} objects.",
+        stats.rounds,
+        stats.bytes_sent.total(),
+        stats.objects_sent,
+        stats.objects_received,
+    );
+    println!("{peer}: {stats:?}");
 
-fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> std::io::Result<()> {
-    let l: u16 = payload.len().try_into().unwrap();
-    let l_bs = l.to_be_bytes();
+    let learned = synced.into_received();
+    if !learned.is_empty() {
+        // the writer only goes away when the server does
+        let _ = merge_tx.send(learned);
+    }
 
-    stream.write(&l_bs)?;
-    stream.write(payload)?;
     Ok(())
 }
 
-fn read_frame(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
-    let mut l_bs = [0u8; 2];
-    stream.read(&mut l_bs)?;
-    let l = u16::from_be_bytes(l_bs) as usize;
+/// Reads the batches of objects in the store and opens it for appending more. A batch that was
+/// cut short, because we crashed while writing it, is dropped.
+fn load(path: &Path) -> std::io::Result<(Objects, File)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut objects = Objects::new();
+    let mut rest = &bytes[..];
+    while let Some((len, tail)) = rest.split_first_chunk::<4>() {
+        let Some(encoded) = tail.get(..u32::from_be_bytes(*len) as usize) else {
+            break;
+        };
+        let batch: Vec<TestObject> = Format::default()
+            .decode(encoded)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        objects.extend(batch.into_iter().map(|obj| (obj.to_item(), obj)));
+        rest = &tail[encoded.len()..];
+    }
+
+    let store = OpenOptions::new().create(true).append(true).open(path)?;
+    store.set_len((bytes.len() - rest.len()) as u64)?;
+    Ok((objects, store))
+}
 
-    let mut buf = vec![0u8; l];
-    stream.read(&mut buf)?;
+/// Appends a batch of objects to the store, prefixed with its length.
+fn append(store: &mut File, objects: &[TestObject]) -> std::io::Result<()> {
+    let batch = Format::default()
+        .encode(&objects)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let len = u32::try_from(batch.len()).map_err(std::io::Error::other)?;
 
-    Ok(buf)
+    let mut record = len.to_be_bytes().to_vec();
+    record.extend(batch);
+    store.write_all(&record)?;
+    store.sync_data()
 }
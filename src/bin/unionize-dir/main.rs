@@ -0,0 +1,449 @@
+//! Reconciles the files in two directories, so both end up with the files of both.
+//!
+//! Every regular file is an object, keyed by a SHA-256 of its contents, truncated to 30 bytes.
+//! Files with the same contents are the same object, no matter their names. Only files missing on
+//! the other side are transferred; files are never overwritten or deleted.
+//!
+//! Usage:
+//!
+//! ```text
+//! unionize-dir serve DIR [--listen ADDR] [OPTIONS]
+//! unionize-dir connect DIR ADDR [OPTIONS]
+//! unionize-dir local DIR DIR [OPTIONS]
+//! ```
+//!
+//! Options: `--threshold N`, `--split N` and `--max-file-size BYTES`. Larger files are skipped.
+//! The files are held in memory while syncing, so this is not meant for huge directories.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use unionize::{
+    easy::uniform::*,
+    item::le_byte_array::LEByteArray,
+    object::Object,
+    protocol::{Format, Message, MessageLimits, Role, Session, SessionBudget, SyncStats},
+    transport::{blocking::sync_over, TransportConfig, TransportError},
+};
+
+/// Messages are filled up to this size. A file that is larger travels in a message of its own.
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// The prefix of the files we write to before moving them into place.
+const TMP_PREFIX: &str = ".unionize-dir.";
+
+#[derive(Clone, Serialize, Deserialize)]
+struct FileObject {
+    name: String,
+    #[serde(with = "contents")]
+    contents: Vec<u8>,
+}
+
+impl std::fmt::Debug for FileObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({} bytes)", self.name, self.contents.len())
+    }
+}
+
+impl Object<Item> for FileObject {
+    fn to_item(&self) -> Item {
+        let digest = Sha256::digest(&self.contents);
+        let mut item = [0u8; 30];
+        item.copy_from_slice(&digest[..30]);
+        LEByteArray(item)
+    }
+
+    /// The item is derived from the contents, so only the name can be bogus. It has to be a plain
+    /// file name, so peers can't make us write outside the directory.
+    fn validate_self_consistency(&self) -> bool {
+        let name = self.name.as_str();
+        !name.is_empty()
+            && name != "."
+            && name != ".."
+            && !name.starts_with(TMP_PREFIX)
+            && !name.contains(['/', '\\', '\0'])
+    }
+}
+
+/// Serializes the contents as a byte string, instead of a sequence of numbers.
+mod contents {
+    use serde::{de::Visitor, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a byte string")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(v)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Vec<u8>, A::Error> {
+                let mut out = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+                while let Some(b) = seq.next_element()? {
+                    out.push(b);
+                }
+                Ok(out)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+type Objects = BTreeMap<Item, FileObject>;
+
+#[derive(Debug)]
+enum Command {
+    Serve { dir: PathBuf, listen: String },
+    Connect { dir: PathBuf, addr: String },
+    Local { a: PathBuf, b: PathBuf },
+}
+
+#[derive(Debug)]
+struct Options {
+    threshold: usize,
+    split: usize,
+    max_file_size: usize,
+}
+
+fn parse_args() -> Result<(Command, Options), String> {
+    let mut positional = vec![];
+    let mut listen = "127.0.0.1:2343".to_string();
+    let mut opts = Options {
+        threshold: 3,
+        split: 4,
+        max_file_size: 64 << 20,
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("missing value for {arg}"))?;
+        let invalid = |e: std::num::ParseIntError| format!("invalid value for {arg}: {e}");
+        match arg.as_str() {
+            "--listen" => listen = value,
+            "--threshold" => opts.threshold = value.parse().map_err(invalid)?,
+            "--split" => opts.split = value.parse().map_err(invalid)?,
+            "--max-file-size" => opts.max_file_size = value.parse().map_err(invalid)?,
+            _ => return Err(format!("unknown option {arg}")),
+        }
+    }
+
+    if opts.split < 2 {
+        return Err("--split needs to be at least 2".into());
+    }
+
+    let positional: Vec<&str> = positional.iter().map(String::as_str).collect();
+    let cmd = match positional[..] {
+        ["serve", dir] => Command::Serve {
+            dir: dir.into(),
+            listen,
+        },
+        ["connect", dir, addr] => Command::Connect {
+            dir: dir.into(),
+            addr: addr.into(),
+        },
+        ["local", a, b] => Command::Local {
+            a: a.into(),
+            b: b.into(),
+        },
+        _ => return Err("expected `serve DIR`, `connect DIR ADDR` or `local DIR DIR`".into()),
+    };
+
+    Ok((cmd, opts))
+}
+
+fn main() {
+    let (cmd, opts) = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("usage: unionize-dir serve DIR [--listen ADDR] [OPTIONS]");
+            eprintln!("       unionize-dir connect DIR ADDR [OPTIONS]");
+            eprintln!("       unionize-dir local DIR DIR [OPTIONS]");
+            eprintln!("options: --threshold N, --split N, --max-file-size BYTES");
+            std::process::exit(2);
+        }
+    };
+
+    let result = match cmd {
+        Command::Serve { dir, listen } => serve(&dir, &listen, &opts),
+        Command::Connect { dir, addr } => connect(&dir, &addr, &opts),
+        Command::Local { a, b } => local(&a, &b, &opts),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn serve(dir: &Path, listen: &str, opts: &Options) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(listen)?;
+    println!("serving {} on {}", dir.display(), listener.local_addr()?);
+
+    // a failed session must not take the server down, so errors are logged and skipped
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                println!("failed to get peer address: {e}");
+                continue;
+            }
+        };
+
+        // pick up what changed since the last session
+        let objects = match load(dir, opts.max_file_size) {
+            Ok(objects) => objects,
+            Err(e) => {
+                println!("{peer}: can't read {}: {e}", dir.display());
+                continue;
+            }
+        };
+        let received = match sync_stream(stream, Role::Responder, &objects, opts) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("{peer}: sync failed: {e}");
+                continue;
+            }
+        };
+        match store(dir, &objects, received) {
+            Ok(written) => println!("{peer}: got {written} files"),
+            Err(e) => println!("{peer}: can't write to {}: {e}", dir.display()),
+        }
+    }
+
+    Ok(())
+}
+
+fn connect(dir: &Path, addr: &str, opts: &Options) -> Result<(), Box<dyn Error>> {
+    let objects = load(dir, opts.max_file_size)?;
+    let stream = TcpStream::connect(addr)?;
+    let received = sync_stream(stream, Role::Initiator, &objects, opts)?;
+    let written = store(dir, &objects, received)?;
+    println!("got {written} files");
+    Ok(())
+}
+
+fn local(a: &Path, b: &Path, opts: &Options) -> Result<(), Box<dyn Error>> {
+    let objects_a = load(a, opts.max_file_size)?;
+    let objects_b = load(b, opts.max_file_size)?;
+    let tree_a = tree(&objects_a);
+    let tree_b = tree(&objects_b);
+
+    let mut session_a = session(Role::Initiator, &objects_a, opts);
+    let mut session_b = session(Role::Responder, &objects_b, opts);
+    let mut received_a = vec![];
+    let mut received_b = vec![];
+
+    // no need for a transport, just hand the messages back and forth
+    let mut next: Option<Message<Monoid, FileObject>> = Some(session_a.start(&tree_a)?);
+    let mut to_b = true;
+    while let Some(msg) = next {
+        let outcome = if to_b {
+            session_b.handle(&tree_b, &objects_b, &msg)?
+        } else {
+            session_a.handle(&tree_a, &objects_a, &msg)?
+        };
+        let (reply, received) = outcome.into_parts();
+        let (accepted, rejected) = received.into_parts();
+        for rejected in rejected {
+            println!("rejected {:?}: {}", rejected.object(), rejected.reason());
+        }
+        if to_b {
+            received_b.extend(accepted);
+        } else {
+            received_a.extend(accepted);
+        }
+
+        next = reply;
+        to_b = !to_b;
+    }
+
+    let written_a = store(a, &objects_a, received_a)?;
+    let written_b = store(b, &objects_b, received_b)?;
+    println!("copied {written_b} files to {}", b.display());
+    println!("copied {written_a} files to {}", a.display());
+    for (dir, session) in [(a, &session_a), (b, &session_b)] {
+        print!("{}: ", dir.display());
+        print_stats(session.stats());
+    }
+    Ok(())
+}
+
+fn sync_stream(
+    stream: TcpStream,
+    role: Role,
+    objects: &Objects,
+    opts: &Options,
+) -> Result<Vec<FileObject>, Box<TransportError<Monoid>>> {
+    // leave room for the rest of a message holding the largest file
+    let config = TransportConfig {
+        max_frame_size: opts.max_file_size.saturating_add(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let set_timeouts = stream
+        .set_read_timeout(config.timeout)
+        .and_then(|()| stream.set_write_timeout(config.timeout));
+    set_timeouts.map_err(|e| Box::new(TransportError::Frame(e.into())))?;
+
+    let tree = tree(objects);
+    let mut session = session(role, objects, opts);
+    let result = sync_over(&stream, &mut session, &tree, objects, &config);
+    let _ = stream.shutdown(Shutdown::Both);
+    let synced = result?;
+
+    for rejected in synced.rejected() {
+        println!("rejected {:?}: {}", rejected.object(), rejected.reason());
+    }
+    print_stats(session.stats());
+    Ok(synced.into_received())
+}
+
+fn session(
+    role: Role,
+    objects: &Objects,
+    opts: &Options,
+) -> Session<Monoid, FileObject, impl Fn(usize) -> Vec<usize>> {
+    let limits = MessageLimits {
+        max_bytes: MAX_MESSAGE_SIZE,
+        format: Format::default(),
+        ..Default::default()
+    };
+    // the peer can only want files we listed, so each of them at most once
+    let budget = SessionBudget {
+        max_objects: objects.len(),
+        ..Default::default()
+    };
+    let fanout = opts.split;
+    Session::new(role, opts.threshold, move |n| split_into(n, fanout))
+        .with_limits(limits)
+        .with_budget(budget)
+        .with_part_sizes(true)
+}
+
+fn print_stats(stats: &SyncStats) {
+    println!(
+        "{} rounds, sent {} bytes and {} files, received {} files",
+        stats.rounds,
+        stats.bytes_sent.total(),
+        stats.objects_sent,
+        stats.objects_received,
+    );
+}
+
+fn tree(objects: &Objects) -> Node {
+    let mut tree = Node::nil();
+    for item in objects.keys() {
+        tree = tree.insert(*item);
+    }
+    tree
+}
+
+/// Reads the regular files in `dir`. Subdirectories, our temporary files and files larger than
+/// `max_file_size` are skipped.
+fn load(dir: &Path, max_file_size: usize) -> std::io::Result<Objects> {
+    let mut objects = Objects::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let Ok(name) = entry.file_name().into_string() else {
+            println!("skipping {:?}: name is not UTF-8", entry.path());
+            continue;
+        };
+        if !meta.is_file() || name.starts_with(TMP_PREFIX) {
+            continue;
+        }
+        if meta.len() > max_file_size as u64 {
+            println!("skipping {name}: larger than {max_file_size} bytes");
+            continue;
+        }
+
+        let obj = FileObject {
+            name,
+            contents: fs::read(entry.path())?,
+        };
+        objects.insert(obj.to_item(), obj);
+    }
+
+    Ok(objects)
+}
+
+/// Writes the received files into `dir` and returns how many were new. If a file with the same
+/// name but other contents exists, the new one gets a name with its hash appended, first a short
+/// one, then the full one. Files for which all of these names are taken are skipped. The file is
+/// linked to its name only if that doesn't exist, so files that show up meanwhile are kept.
+fn store(dir: &Path, known: &Objects, received: Vec<FileObject>) -> std::io::Result<usize> {
+    let mut written = 0;
+    for obj in received {
+        let item = obj.to_item();
+        if known.contains_key(&item) {
+            continue;
+        }
+
+        let names = [
+            obj.name.clone(),
+            format!("{}.{}", obj.name, hex::encode(&item.0[..8])),
+            format!("{}.{}", obj.name, hex::encode(item.0)),
+        ];
+        let tmp = dir.join(format!("{TMP_PREFIX}{}", hex::encode(item.0)));
+        fs::write(&tmp, &obj.contents)?;
+        let mut linked = false;
+        for name in &names {
+            match fs::hard_link(&tmp, dir.join(name)) {
+                Ok(()) => {
+                    linked = true;
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+        fs::remove_file(&tmp)?;
+
+        if linked {
+            written += 1;
+        } else {
+            println!("skipping {}: all names for it are taken", obj.name);
+        }
+    }
+
+    Ok(written)
+}
@@ -0,0 +1,243 @@
+//! Looks at trees and sync sessions, for debugging.
+//!
+//! Usage:
+//!
+//! ```text
+//! unionize-inspect stats ITEMS
+//! unionize-inspect fingerprints ITEMS FROM TO [--depth N]
+//! unionize-inspect replay ITEMS TRANSCRIPT
+//! ```
+//!
+//! `ITEMS` is a file with one item per line, in hex with the most significant byte first, as
+//! printed by `{:#?}`. The `LE_` prefix is optional, shorter items are padded with zeros. Empty
+//! lines and lines starting with `#` are skipped. `FROM` and `TO` are items in the same notation.
+//!
+//! `TRANSCRIPT` is a [`Transcript`] recorded by either side of a session, saved in the default
+//! [`Format`]. Replaying it compares the fingerprints in every message with the ones of the tree
+//! built from `ITEMS`.
+
+use std::{collections::BTreeSet, error::Error, fs, path::Path};
+
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
+use unionize::{
+    easy::uniform::*,
+    item::le_byte_array::LEByteArray,
+    object::Object,
+    protocol::{Encodable, Flow, Format, ProtocolMonoid, Transcript},
+    query::simple::SimpleAccumulator,
+    tree::inspect::{subtrees, Shape},
+    Item as _, Node as _, Range,
+};
+
+/// Stands in for the objects in a transcript. We only look at fingerprints and items, so the
+/// objects are skipped, whatever they are.
+#[derive(Debug, Clone, Serialize)]
+struct Skipped;
+
+impl<'de> Deserialize<'de> for Skipped {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(Skipped)
+    }
+}
+
+impl Object<Item> for Skipped {
+    fn to_item(&self) -> Item {
+        unreachable!("skipped objects are never looked at")
+    }
+
+    fn validate_self_consistency(&self) -> bool {
+        false
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args[..] {
+        ["stats", items] => stats(items.as_ref()),
+        ["fingerprints", items, from, to] => fingerprints(items.as_ref(), from, to, 2),
+        ["fingerprints", items, from, to, "--depth", depth] => match depth.parse() {
+            Ok(depth) => fingerprints(items.as_ref(), from, to, depth),
+            Err(e) => Err(format!("invalid depth: {e}").into()),
+        },
+        ["replay", items, transcript] => replay(items.as_ref(), transcript.as_ref()),
+        _ => {
+            eprintln!("usage: unionize-inspect stats ITEMS");
+            eprintln!("       unionize-inspect fingerprints ITEMS FROM TO [--depth N]");
+            eprintln!("       unionize-inspect replay ITEMS TRANSCRIPT");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn stats(items: &Path) -> Result<(), Box<dyn Error>> {
+    let tree = load(items)?;
+    let shape = Shape::of(&tree);
+
+    println!("items:      {}", shape.items);
+    println!("height:     {}", shape.height);
+    println!(
+        "nodes:      {} ({} with one item, {} with two)",
+        shape.nodes(),
+        shape.node2s,
+        shape.node3s
+    );
+    println!("leaves:     {}", shape.leaves);
+    println!("fill ratio: {:.3}", shape.fill_ratio());
+    Ok(())
+}
+
+fn fingerprints(items: &Path, from: &str, to: &str, depth: usize) -> Result<(), Box<dyn Error>> {
+    let tree = load(items)?;
+    let range = Range::new(parse_item(from)?, parse_item(to)?);
+
+    println!(
+        "range {:#?}..{:#?}: {} items, {:?}",
+        range.from(),
+        range.to(),
+        fp_of(&tree, &range).count(),
+        fp_of(&tree, &range).to_encoded()?
+    );
+
+    // subtrees may hold items outside the range, their fingerprints cover those too
+    for subtree in subtrees(&tree, &range, depth) {
+        println!(
+            "{:indent$}{:#?}..={:#?}: {} items, {:?}",
+            "",
+            subtree.min,
+            subtree.max,
+            subtree.monoid.count(),
+            subtree.monoid.to_encoded()?,
+            indent = 2 * subtree.depth,
+        );
+    }
+
+    Ok(())
+}
+
+fn replay(items: &Path, transcript: &Path) -> Result<(), Box<dyn Error>> {
+    let tree = load(items)?;
+    let transcript: Transcript = Format::default().decode(&fs::read(transcript)?)?;
+    let msgs = transcript.messages::<Monoid, Skipped>()?;
+    println!("recorded by the {:?}", transcript.role());
+
+    for (round, ((flow, msg), entry)) in msgs.iter().zip(transcript.entries()).enumerate() {
+        let flow = match flow {
+            Flow::Sent => "sent",
+            Flow::Received => "received",
+        };
+        println!(
+            "round {round}, {flow} after {:?}, {} bytes:",
+            entry.at(),
+            entry.bytes().len()
+        );
+
+        let mut mismatches = 0;
+        for fp in msg.fingerprints() {
+            let theirs = Monoid::from_encoded(fp.fp())?;
+            let ours = fp_of(&tree, fp.range());
+            let matches = ours.to_encoded()? == *fp.fp();
+            mismatches += usize::from(!matches);
+            println!(
+                "  fingerprint {:#?}..{:#?}: {} items, {} here{}",
+                fp.range().from(),
+                fp.range().to(),
+                theirs.count(),
+                ours.count(),
+                if matches { "" } else { ", MISMATCH" },
+            );
+        }
+        for item_set in msg.item_sets() {
+            let unknown = item_set
+                .items()
+                .iter()
+                .filter(|item| !contains(&tree, item))
+                .count();
+            println!(
+                "  item set {:#?}..{:#?}: {} items, {unknown} not here",
+                item_set.range().from(),
+                item_set.range().to(),
+                item_set.items().len(),
+            );
+        }
+        for request in msg.short_id_requests() {
+            println!(
+                "  short ID request {:#?}..{:#?}: {} IDs",
+                request.range().from(),
+                request.range().to(),
+                request.ids().len(),
+            );
+        }
+        println!(
+            "  {} fingerprints, {mismatches} mismatching. {} short item sets, {} short ID \
+             requests, {} sketches. {} wanted, {} provided, {} not available.{}",
+            msg.fingerprints().len(),
+            msg.short_item_sets().len(),
+            msg.short_id_requests().len(),
+            msg.sketches().len(),
+            msg.wants().len(),
+            msg.provide().len(),
+            msg.not_available().len(),
+            if msg.has_more() { " more to come." } else { "" },
+        );
+    }
+
+    println!("{} messages", msgs.len());
+    Ok(())
+}
+
+fn fp_of(tree: &Node, range: &Range<Item>) -> Monoid {
+    let mut acc = SimpleAccumulator::new();
+    tree.query(range, &mut acc);
+    acc.into_result()
+}
+
+fn contains(tree: &Node, item: &Item) -> bool {
+    fp_of(tree, &Range::new(*item, item.next())).count() > 0
+}
+
+fn load(path: &Path) -> Result<Node, Box<dyn Error>> {
+    // the tree doesn't deduplicate
+    let mut items = BTreeSet::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let item = parse_item(line).map_err(|e| format!("line {}: {e}", i + 1))?;
+        items.insert(item);
+    }
+
+    Ok(items
+        .into_iter()
+        .fold(Node::nil(), |tree, item| tree.insert(item)))
+}
+
+fn parse_item(s: &str) -> Result<Item, Box<dyn Error>> {
+    let s = s.strip_prefix("LE_").unwrap_or(s);
+    let s = if s.len() % 2 == 1 {
+        format!("0{s}")
+    } else {
+        s.to_string()
+    };
+
+    let bytes = hex::decode(s)?;
+    if bytes.len() > 30 {
+        return Err(format!("items have 30 bytes, got {}", bytes.len()).into());
+    }
+
+    // the most significant byte comes last
+    let mut item = [0u8; 30];
+    for (dst, src) in item.iter_mut().zip(bytes.iter().rev()) {
+        *dst = *src;
+    }
+    Ok(LEByteArray(item))
+}
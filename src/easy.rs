@@ -4,7 +4,7 @@ pub mod uniform {
     use crate::{
         item::le_byte_array::LEByteArray,
         monoid::{count::CountingMonoid, mulhash_xs233::Xsk233MulHashMonoid},
-        tree::mem_rc::Node as MemRcNode,
+        tree::{mem_arc::Node as MemArcNode, mem_rc::Node as MemRcNode},
     };
 
     extern crate alloc;
@@ -13,12 +13,19 @@ pub mod uniform {
     pub type Item = LEByteArray<30>;
     pub type Monoid = CountingMonoid<Xsk233MulHashMonoid>;
     pub type Node = MemRcNode<Monoid>;
+    /// A [`Node`] that can be shared between threads.
+    pub type SharedNode = MemArcNode<Monoid>;
 
     pub fn split<const C: usize>(n: usize) -> Vec<usize> {
-        let most = n / C;
-        let rest = n - (C - 1) * most;
+        split_into(n, C)
+    }
+
+    /// Like [`split`], for a number of buckets that is only known at runtime.
+    pub fn split_into(n: usize, buckets: usize) -> Vec<usize> {
+        let most = n / buckets;
+        let rest = n - (buckets - 1) * most;
 
-        let mut out = vec![most; C];
+        let mut out = vec![most; buckets];
         out[0] = rest;
         out
     }
@@ -42,7 +49,7 @@ pub mod timestamped {
     }
 
     pub fn split_dynamic<const THRESH: usize>(n: usize) -> Vec<usize> {
-        let mut res = Vec::with_capacity(n.ilog2() as usize + 3);
+        let mut res = Vec::with_capacity(n.max(1).ilog2() as usize + 3);
         let mut cur = n;
         while cur >= THRESH {
             let half = cur / 2;
@@ -1,3 +1,8 @@
+extern crate alloc;
+use alloc::{format, string::String, vec::Vec};
+
+use crate::protocol::{CompactItem, WireId};
+
 use super::Item;
 
 impl<const L: usize> Item for [u8; L] {
@@ -16,4 +21,40 @@ impl<const L: usize> Item for [u8; L] {
         }
         result
     }
+
+    /// Keeps the bytes of `upper` up to and including the first one that differs from `lower`,
+    /// and zeroes the rest.
+    fn separator(lower: &Self, upper: &Self) -> Self {
+        debug_assert!(lower < upper);
+
+        let mut result = [0u8; L];
+        for i in 0..L {
+            result[i] = upper[i];
+            if lower[i] != upper[i] {
+                break;
+            }
+        }
+
+        result
+    }
+}
+
+impl<const L: usize> WireId for [u8; L] {
+    fn wire_id() -> String {
+        format!("[u8; {L}]")
+    }
+}
+
+impl<const L: usize> CompactItem for [u8; L] {
+    const COMPACT_LEN: usize = L;
+
+    fn write_compact(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn read_compact(bytes: &[u8]) -> Self {
+        let mut buf = [0u8; L];
+        buf.copy_from_slice(bytes);
+        buf
+    }
 }
@@ -1,9 +1,9 @@
 extern crate alloc;
-use alloc::format;
+use alloc::{format, string::String, vec::Vec};
 
 use core::cmp::Ordering;
 
-use crate::protocol::SerializableItem;
+use crate::protocol::{CompactItem, SerializableItem, WireId};
 
 use super::Item;
 
@@ -81,6 +81,22 @@ impl<const L: usize> Item for LEByteArray<L> {
         }
         result
     }
+
+    /// Keeps the most significant bytes of `upper` up to and including the first one that
+    /// differs from `lower`, and zeroes the rest.
+    fn separator(lower: &Self, upper: &Self) -> Self {
+        debug_assert!(lower < upper);
+
+        let mut result = [0u8; L];
+        for i in (0..L).rev() {
+            result[i] = upper.0[i];
+            if lower.0[i] != upper.0[i] {
+                break;
+            }
+        }
+
+        LEByteArray(result)
+    }
 }
 
 impl<const L: usize> Serialize for LEByteArray<L> {
@@ -94,6 +110,29 @@ impl<const L: usize> Serialize for LEByteArray<L> {
 
 impl<const L: usize> SerializableItem for LEByteArray<L> {}
 
+/// The most significant byte comes last, so the bytes are written in reverse.
+impl<const L: usize> CompactItem for LEByteArray<L> {
+    const COMPACT_LEN: usize = L;
+
+    fn write_compact(&self, out: &mut Vec<u8>) {
+        out.extend(self.0.iter().rev());
+    }
+
+    fn read_compact(bytes: &[u8]) -> Self {
+        let mut buf = [0u8; L];
+        for (dst, src) in buf.iter_mut().zip(bytes.iter().rev()) {
+            *dst = *src;
+        }
+        LEByteArray(buf)
+    }
+}
+
+impl<const L: usize> WireId for LEByteArray<L> {
+    fn wire_id() -> String {
+        format!("LEByteArray<{L}>")
+    }
+}
+
 impl<'de, const L: usize> Deserialize<'de> for LEByteArray<L> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -138,7 +177,7 @@ mod tests {
 
     use super::*;
 
-    use proptest::{prop_assert_eq, proptest};
+    use proptest::{prop_assert, prop_assert_eq, prop_assume, proptest};
 
     proptest! {
         #[test]
@@ -150,5 +189,29 @@ mod tests {
             let result = serde_cbor::from_slice(&encoded).unwrap();
             prop_assert_eq!(item, result);
         }
+
+        #[test]
+        fn separator_correctness(a in proptest::array::uniform4(0u8..=255u8), b in proptest::array::uniform4(0u8..=255u8)) {
+            let (a, b) = (LEByteArray(a), LEByteArray(b));
+            prop_assume!(a != b);
+            let (lower, upper) = if a < b { (a, b) } else { (b, a) };
+
+            let sep = LEByteArray::separator(&lower, &upper);
+            prop_assert!(lower < sep && sep <= upper);
+
+            // everything below the most significant byte that differs is zeroed
+            let differs_at = (0..4).rev().find(|i| lower.0[*i] != upper.0[*i]).unwrap();
+            prop_assert!(sep.0[..differs_at].iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    fn separator_example() {
+        let lower = LEByteArray([0xff, 0x12, 0x34, 0x56]);
+        let upper = LEByteArray([0x01, 0x20, 0x34, 0x56]);
+        assert_eq!(
+            LEByteArray::separator(&lower, &upper),
+            LEByteArray([0x00, 0x20, 0x34, 0x56])
+        );
     }
 }
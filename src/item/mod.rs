@@ -14,6 +14,15 @@ pub trait Item: Clone + Ord + core::fmt::Debug {
     fn zero() -> Self;
 
     /// Returns the lowest item greater than `self`.
-    /// For numbers, this is `self + 1`.
+    /// For numbers, this is `self + 1`. The largest item wraps around to [`Item::zero`], so
+    /// `Range(item, item.next())` still contains just `item`.
     fn next(&self) -> Self;
+
+    /// Returns an item `x` with `lower < x <= upper`, where `lower < upper`. Used as the boundary
+    /// between two ranges, so it should be cheap to send, e.g. by having many trailing zero bytes.
+    /// By default, this is just `upper`.
+    fn separator(lower: &Self, upper: &Self) -> Self {
+        debug_assert!(lower < upper);
+        upper.clone()
+    }
 }
@@ -1,6 +1,12 @@
+extern crate alloc;
+use alloc::{format, string::String, vec::Vec};
+
 use serde::{Deserialize, Serialize};
 
-use crate::Item;
+use crate::{
+    protocol::{CompactItem, WireId},
+    Item,
+};
 
 pub trait TimestampItem: Item {}
 
@@ -30,3 +36,27 @@ impl<TS: TimestampItem, I: Item> Item for TimestampedItem<TS, I> {
         Self(self.0.clone(), self.1.next())
     }
 }
+
+impl<TS: TimestampItem + WireId, I: Item + WireId> WireId for TimestampedItem<TS, I> {
+    fn wire_id() -> String {
+        format!("TimestampedItem<{}, {}>", TS::wire_id(), I::wire_id())
+    }
+}
+
+impl<TS, I> CompactItem for TimestampedItem<TS, I>
+where
+    TS: TimestampItem + CompactItem,
+    I: CompactItem,
+{
+    const COMPACT_LEN: usize = TS::COMPACT_LEN + I::COMPACT_LEN;
+
+    fn write_compact(&self, out: &mut Vec<u8>) {
+        self.0.write_compact(out);
+        self.1.write_compact(out);
+    }
+
+    fn read_compact(bytes: &[u8]) -> Self {
+        let (ts, lower) = bytes.split_at(TS::COMPACT_LEN);
+        Self(TS::read_compact(ts), I::read_compact(lower))
+    }
+}
@@ -1,4 +1,10 @@
-use crate::Item;
+extern crate alloc;
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    protocol::{CompactItem, WireId},
+    Item,
+};
 
 impl<I1, I2> Item for (I1, I2)
 where
@@ -13,3 +19,23 @@ where
         (self.0.clone(), self.1.next())
     }
 }
+
+impl<I1: WireId, I2: WireId> WireId for (I1, I2) {
+    fn wire_id() -> String {
+        format!("({}, {})", I1::wire_id(), I2::wire_id())
+    }
+}
+
+impl<I1: CompactItem, I2: CompactItem> CompactItem for (I1, I2) {
+    const COMPACT_LEN: usize = I1::COMPACT_LEN + I2::COMPACT_LEN;
+
+    fn write_compact(&self, out: &mut Vec<u8>) {
+        self.0.write_compact(out);
+        self.1.write_compact(out);
+    }
+
+    fn read_compact(bytes: &[u8]) -> Self {
+        let (first, second) = bytes.split_at(I1::COMPACT_LEN);
+        (I1::read_compact(first), I2::read_compact(second))
+    }
+}
@@ -1,4 +1,6 @@
-use crate::protocol::SerializableItem;
+extern crate alloc;
+
+use crate::protocol::{CompactItem, SerializableItem, WireId};
 
 macro_rules! impl_Item_uint {
     ($type:ty) => {
@@ -8,11 +10,31 @@ macro_rules! impl_Item_uint {
             }
 
             fn next(&self) -> Self {
-                self + 1
+                self.wrapping_add(1)
             }
         }
 
         impl $crate::item::timestamped::TimestampItem for $type {}
+
+        impl CompactItem for $type {
+            const COMPACT_LEN: usize = core::mem::size_of::<$type>();
+
+            fn write_compact(&self, out: &mut alloc::vec::Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn read_compact(bytes: &[u8]) -> Self {
+                let mut buf = [0u8; core::mem::size_of::<$type>()];
+                buf.copy_from_slice(bytes);
+                <$type>::from_be_bytes(buf)
+            }
+        }
+
+        impl WireId for $type {
+            fn wire_id() -> alloc::string::String {
+                alloc::string::String::from(stringify!($type))
+            }
+        }
     };
 }
 
@@ -16,3 +16,4 @@ pub use tree::{Node, NonNilNodeRef};
 
 pub mod easy;
 pub mod protocol;
+pub mod transport;
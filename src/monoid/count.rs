@@ -1,6 +1,9 @@
+extern crate alloc;
+use alloc::{format, string::String};
+
 use crate::{
     monoid::Monoid,
-    protocol::{DecodeError, Encodable, EncodeError, ProtocolMonoid},
+    protocol::{DecodeError, Encodable, EncodeError, ProtocolMonoid, WireId},
 };
 
 use serde::{Deserialize, Serialize};
@@ -95,3 +98,9 @@ where
 //         tup.end()
 //     }
 // }
+
+impl<M: Monoid + WireId> WireId for CountingMonoid<M> {
+    fn wire_id() -> String {
+        format!("CountingMonoid<{}>", M::wire_id())
+    }
+}
@@ -2,12 +2,12 @@ use core::marker::PhantomData;
 use core::{convert::Infallible, fmt::Debug};
 
 extern crate alloc;
-use alloc::format;
+use alloc::{format, string::String};
 
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::protocol::{DecodeError, EncodeError, SerializableItem};
+use crate::protocol::{DecodeError, EncodeError, SerializableItem, WireId};
 use crate::{
     monoid::{Item, Monoid},
     protocol::{Encodable, ProtocolMonoid},
@@ -83,3 +83,9 @@ impl<I: Item> Monoid for CountingSha256Xor<I> {
         Self(left_count + right_count, out, PhantomData)
     }
 }
+
+impl<I: Item + WireId> WireId for CountingSha256Xor<I> {
+    fn wire_id() -> String {
+        format!("CountingSha256Xor<{}>", I::wire_id())
+    }
+}
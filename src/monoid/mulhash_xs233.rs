@@ -5,10 +5,10 @@ use core::convert::Infallible;
 
 use crate::{
     item::le_byte_array::LEByteArray,
-    protocol::{encoding::AsDestMutRef, DecodeError, EncodeError},
+    protocol::{encoding::AsDestMutRef, DecodeError, EncodeError, WireId},
 };
 
-use alloc::format;
+use alloc::{format, string::String};
 use serde::{de::Deserializer, Deserialize, Serialize};
 
 use super::Monoid;
@@ -174,6 +174,12 @@ where
     }
 }
 
+impl WireId for Xsk233MulHashMonoid {
+    fn wire_id() -> String {
+        String::from("MulHashMonoid<Xsk233>")
+    }
+}
+
 /*
  * Implementation notes:
  * - I think I need to encode and decode in between. it seems like this is a lot more expensive
@@ -1,6 +1,9 @@
 use core::convert::Infallible;
 
-use crate::protocol::{DecodeError, Encodable, EncodeError};
+extern crate alloc;
+use alloc::{format, string::String};
+
+use crate::protocol::{DecodeError, Encodable, EncodeError, WireId};
 
 use super::{Item, Monoid};
 
@@ -58,3 +61,9 @@ impl<I: SumItem> Monoid for SumMonoid<I> {
         SumMonoid(lhs.clone() + rhs.clone())
     }
 }
+
+impl<I: SumItem + WireId> WireId for SumMonoid<I> {
+    fn wire_id() -> String {
+        format!("SumMonoid<{}>", I::wire_id())
+    }
+}
@@ -1,8 +1,11 @@
 use core::marker::PhantomData;
 
+extern crate alloc;
+use alloc::{format, string::String};
+
 use crate::{
     item::timestamped::{TimestampItem, TimestampedItem},
-    protocol::{Encodable, ProtocolMonoid},
+    protocol::{Encodable, ProtocolMonoid, WireId},
     Monoid,
 };
 
@@ -59,6 +62,12 @@ impl<TS: TimestampItem, M: ProtocolMonoid> ProtocolMonoid for Timestamped<TS, M>
     }
 }
 
+impl<TS: TimestampItem + WireId, M: Monoid + WireId> WireId for Timestamped<TS, M> {
+    fn wire_id() -> String {
+        format!("Timestamped<{}, {}>", TS::wire_id(), M::wire_id())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Object;
+
+use super::{session::DEFAULT_MAX_ROUNDS, Message, ProtocolMonoid, RespondError, SyncStats};
+
+/// Upper bounds for the work a peer can make us do in a session. Whatever the peer sends, the
+/// cost of answering it grows with the number of ranges it lists, the items we have in them, the
+/// objects it wants and the fingerprints and sketches we decode. Once a budget is used up, the
+/// session is aborted with [`RespondError::OverBudget`], or [`SessionError::TooManyRounds`] for
+/// the rounds.
+///
+/// The defaults are generous enough for syncing sets of millions of items, but keep a peer from
+/// making us work without end. Servers that know the size of their set can go lower.
+///
+/// [`SessionError::TooManyRounds`]: super::SessionError::TooManyRounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionBudget {
+    /// The maximum number of ranges in a single message from the peer, counting fingerprints,
+    /// item sets, short item sets, short ID requests and sketches.
+    pub max_ranges: usize,
+    /// The maximum number of items we list from our tree over the session.
+    pub max_items: usize,
+    /// The maximum number of objects the peer may want from us over the session.
+    pub max_objects: usize,
+    /// The number of rounds after which the session is aborted.
+    pub max_rounds: usize,
+    /// The maximum number of fingerprints and sketches of the peer we decode over the session.
+    pub max_decodes: usize,
+}
+
+/// The default for [`SessionBudget::max_ranges`].
+pub const DEFAULT_MAX_RANGES: usize = 1 << 16;
+/// The default for [`SessionBudget::max_items`].
+pub const DEFAULT_MAX_ITEMS: usize = 1 << 26;
+/// The default for [`SessionBudget::max_objects`].
+pub const DEFAULT_MAX_OBJECTS: usize = 1 << 24;
+/// The default for [`SessionBudget::max_decodes`].
+pub const DEFAULT_MAX_DECODES: usize = 1 << 24;
+
+impl Default for SessionBudget {
+    fn default() -> Self {
+        Self {
+            max_ranges: DEFAULT_MAX_RANGES,
+            max_items: DEFAULT_MAX_ITEMS,
+            max_objects: DEFAULT_MAX_OBJECTS,
+            max_rounds: DEFAULT_MAX_ROUNDS,
+            max_decodes: DEFAULT_MAX_DECODES,
+        }
+    }
+}
+
+/// The budgets of a [`SessionBudget`] that are checked while responding to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Ranges,
+    Items,
+    Objects,
+    Decodes,
+}
+
+impl core::fmt::Display for Resource {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Resource::Ranges => "ranges per message",
+            Resource::Items => "items listed",
+            Resource::Objects => "objects wanted",
+            Resource::Decodes => "fingerprints and sketches decoded",
+        })
+    }
+}
+
+impl SessionBudget {
+    /// Doesn't limit anything, not even the rounds.
+    pub fn unlimited() -> Self {
+        Self {
+            max_ranges: usize::MAX,
+            max_items: usize::MAX,
+            max_objects: usize::MAX,
+            max_rounds: usize::MAX,
+            max_decodes: usize::MAX,
+        }
+    }
+
+    /// Checks the budgets we can tell from `msg` alone, before doing any work for it. `spent`
+    /// holds what the session used up before this message.
+    pub fn check_message<M, O>(
+        &self,
+        msg: &Message<M, O>,
+        spent: &SyncStats,
+    ) -> Result<(), RespondError<M>>
+    where
+        M: ProtocolMonoid,
+        O: Object<M::Item> + Serialize,
+        M::Item: Serialize,
+        M::Encoded: Serialize,
+        for<'de2> M::Item: Deserialize<'de2>,
+        for<'de2> M::Encoded: Deserialize<'de2>,
+        for<'de2> O: Deserialize<'de2>,
+    {
+        let ranges = msg.fingerprints().len()
+            + msg.item_sets().len()
+            + msg.short_item_sets().len()
+            + msg.short_id_requests().len()
+            + msg.sketches().len();
+        self.check(Resource::Ranges, ranges)?;
+
+        let decodes = msg.fingerprints().len() + msg.sketches().len();
+        self.check(Resource::Decodes, spent.decodes.saturating_add(decodes))?;
+
+        let objects = msg.wants().len();
+        self.check(
+            Resource::Objects,
+            spent.objects_looked_up.saturating_add(objects),
+        )
+    }
+
+    /// Fails if `used` exceeds the budget for `resource`.
+    pub fn check<M: ProtocolMonoid>(
+        &self,
+        resource: Resource,
+        used: usize,
+    ) -> Result<(), RespondError<M>> {
+        let limit = self.limit(resource);
+        if used > limit {
+            return Err(RespondError::OverBudget { resource, limit });
+        }
+        Ok(())
+    }
+
+    pub fn limit(&self, resource: Resource) -> usize {
+        match resource {
+            Resource::Ranges => self.max_ranges,
+            Resource::Items => self.max_items,
+            Resource::Objects => self.max_objects,
+            Resource::Decodes => self.max_decodes,
+        }
+    }
+}
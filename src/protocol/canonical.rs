@@ -0,0 +1,389 @@
+//! Feeds values to a [`Hasher`] as bytes that only depend on the value, not on the wire format or
+//! the platform. Short IDs are hashes of items, and both peers have to arrive at the same ones
+//! whatever [`Format`](super::Format) they talk in.
+//!
+//! Integers are written as little-endian bytes of their full width, strings, byte strings,
+//! sequences and maps are prefixed with their length, and enum variants with their index. Structs
+//! and tuples are just their fields in order.
+
+extern crate std;
+use core::hash::Hasher;
+
+use serde::{ser, Serialize};
+
+/// Writes `value` to `hasher`. If serializing `value` fails, what was written up to that point
+/// stays in the hasher.
+pub(crate) fn write<T, H>(value: &T, hasher: &mut H) -> Result<(), Error>
+where
+    T: Serialize + ?Sized,
+    H: Hasher,
+{
+    value.serialize(&mut Writer(hasher))
+}
+
+/// A [`Serialize`] impl reported an error of its own.
+#[derive(Debug)]
+pub(crate) struct Error;
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("value can't be serialized")
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: core::fmt::Display>(_msg: T) -> Self {
+        Error
+    }
+}
+
+struct Writer<'a, H: Hasher>(&'a mut H);
+
+impl<H: Hasher> Writer<'_, H> {
+    fn len(&mut self, len: usize) {
+        self.0.write(&(len as u64).to_le_bytes());
+    }
+
+    fn variant(&mut self, index: u32) {
+        self.0.write(&index.to_le_bytes());
+    }
+}
+
+impl<'a, H: Hasher> ser::Serializer for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.0.write(&[v as u8]);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.0.write(&[v]);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        self.0.write(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.0.write(&v.to_bits().to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.0.write(&v.to_bits().to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.0.write(&u32::from(v).to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.len(v.len());
+        self.0.write(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.0.write(&[0]);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.0.write(&[1]);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), Error> {
+        self.variant(variant_index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.variant(variant_index);
+        value.serialize(self)
+    }
+
+    // sequences of unknown length are written without a prefix. None of our items have any.
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        if let Some(len) = len {
+            self.len(len);
+        }
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.variant(variant_index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Error> {
+        if let Some(len) = len {
+            self.len(len);
+        }
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.variant(variant_index);
+        Ok(self)
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeSeq for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeTuple for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeTupleStruct for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeTupleVariant for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeMap for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeStruct for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, H: Hasher> ser::SerializeStructVariant for &mut Writer<'a, H> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{vec, vec::Vec};
+    use core::hash::Hasher;
+
+    use crate::item::{le_byte_array::LEByteArray, timestamped::TimestampedItem};
+
+    use super::write;
+
+    /// Collects the written bytes instead of hashing them.
+    #[derive(Default)]
+    struct Bytes(Vec<u8>);
+
+    impl Hasher for Bytes {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            self.0.extend_from_slice(bytes);
+        }
+    }
+
+    fn bytes<T: serde::Serialize>(value: &T) -> Vec<u8> {
+        let mut out = Bytes::default();
+        write(value, &mut out).unwrap();
+        out.0
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(bytes(&0x0102u16), vec![2, 1]);
+        assert_eq!(bytes(&(1u8, 2u32)), vec![1, 2, 0, 0, 0]);
+        assert_eq!(bytes(&[7u8; 3]), vec![7, 7, 7]);
+        assert_eq!(bytes(&vec![7u8; 2]), vec![2, 0, 0, 0, 0, 0, 0, 0, 7, 7]);
+        assert_eq!(bytes(&Some(5u8)), vec![1, 5]);
+        assert_eq!(bytes(&TimestampedItem::new(1u8, 2u8)), bytes(&(1u8, 2u8)),);
+
+        // byte strings are prefixed with their length
+        let mut expected = 30u64.to_le_bytes().to_vec();
+        expected.extend([3u8; 30]);
+        assert_eq!(bytes(&LEByteArray([3u8; 30])), expected);
+    }
+}
@@ -0,0 +1,666 @@
+//! A hand-tuned binary encoding for [`Message`]s that is considerably smaller than CBOR.
+//!
+//! Items are written in an order-preserving byte representation (see [`CompactItem`]) and
+//! delta-encoded against the item written before them: only the length of the shared prefix and
+//! the remaining bytes are sent, and trailing zero bytes are dropped. Consecutive ranges usually
+//! touch, so a range whose start is the end of the previous range only sends its end. Lengths
+//! and counts are LEB128 varints.
+//!
+//! Fingerprints and objects are opaque to this codec and embedded length-prefixed, in the
+//! [`Format`] passed by the caller.
+
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{range::Range, Item, Object};
+
+use super::{
+    short_id::{ShortIdKey, MAX_SHORT_ID_LEN},
+    CodecError, Direction, Fingerprint, Format, ItemSet, Message, ProtocolMonoid, ShortIdRequest,
+    ShortItemSet, Sketch,
+};
+
+/// The version of the compact encoding, written as the first byte.
+const FORMAT_VERSION: u8 = 1;
+
+const FLAG_MORE: u8 = 1 << 0;
+const DIRECTION_SHIFT: u8 = 1;
+const DIRECTION_MASK: u8 = 0b11 << DIRECTION_SHIFT;
+/// The message uses short IDs, see [`super::short_id`]. They are written after everything else.
+const FLAG_SHORT_IDS: u8 = 1 << 3;
+/// The message uses sketches, see [`super::sketch`]. They are written after the short IDs.
+const FLAG_SKETCHES: u8 = 1 << 4;
+/// The message carries a short ID key other than the default one. It is written last.
+const FLAG_SHORT_ID_KEY: u8 = 1 << 5;
+
+const RANGE_SHARES_FROM: u8 = 1 << 0;
+const RANGE_WANTS_RESPONSE: u8 = 1 << 1;
+
+/// Items that can be written as a fixed number of bytes that sort the same way as the items.
+/// That way, items that are close to each other share a prefix.
+pub trait CompactItem: Item {
+    /// The number of bytes of the encoding.
+    const COMPACT_LEN: usize;
+
+    /// Appends exactly [`CompactItem::COMPACT_LEN`] bytes to `out`.
+    fn write_compact(&self, out: &mut Vec<u8>);
+
+    /// Reads the item back. `bytes` is exactly [`CompactItem::COMPACT_LEN`] long.
+    fn read_compact(bytes: &[u8]) -> Self;
+}
+
+/// Encodes `msg` in the compact format, embedding fingerprints and objects in `format`.
+pub fn encode<M, O>(format: Format, msg: &Message<M, O>) -> Result<Vec<u8>, CodecError>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize + CompactItem,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let mut w = Writer::<M::Item>::new();
+
+    let direction = match msg.direction {
+        Direction::Both => 0,
+        Direction::Pull => 1,
+        Direction::Push => 2,
+    };
+    let mut flags = direction << DIRECTION_SHIFT;
+    if msg.more {
+        flags |= FLAG_MORE;
+    }
+    let short_ids = msg.short_id_len != 0
+        || !msg.short_item_sets.is_empty()
+        || !msg.short_id_requests.is_empty();
+    if short_ids {
+        flags |= FLAG_SHORT_IDS;
+    }
+    let sketches = msg.sketch_cells != 0 || !msg.sketches.is_empty();
+    if sketches {
+        flags |= FLAG_SKETCHES;
+    }
+    let short_id_key = msg.short_id_key != ShortIdKey::default();
+    if short_id_key {
+        flags |= FLAG_SHORT_ID_KEY;
+    }
+    w.buf.extend([FORMAT_VERSION, flags]);
+
+    w.write_len(msg.fps.len());
+    for Fingerprint { range, fp } in &msg.fps {
+        w.write_range(range, 0);
+        w.write_embedded(format, fp)?;
+    }
+
+    w.write_len(msg.item_sets.len());
+    for item_set in &msg.item_sets {
+        let tag = if item_set.want_response {
+            RANGE_WANTS_RESPONSE
+        } else {
+            0
+        };
+        w.write_range(&item_set.range, tag);
+
+        // the items start at the beginning of the range
+        w.prev.clear();
+        item_set.range.from().write_compact(&mut w.prev);
+
+        w.write_items(&item_set.items);
+    }
+
+    w.write_items(&msg.wants);
+
+    w.write_len(msg.provide.len());
+    for obj in &msg.provide {
+        w.write_embedded(format, obj)?;
+    }
+
+    w.write_items(&msg.not_available);
+
+    let len = msg.id_len();
+    if short_ids {
+        w.buf.push(msg.short_id_len);
+
+        w.write_len(msg.short_item_sets.len());
+        for short_item_set in &msg.short_item_sets {
+            let tag = if short_item_set.want_response() {
+                RANGE_WANTS_RESPONSE
+            } else {
+                0
+            };
+            w.write_range(short_item_set.range(), tag);
+            w.write_short_ids(short_item_set.ids(), len)?;
+
+            w.prev.clear();
+            short_item_set.range().from().write_compact(&mut w.prev);
+            w.write_items(short_item_set.items());
+        }
+
+        w.write_len(msg.short_id_requests.len());
+        for request in &msg.short_id_requests {
+            w.write_range(request.range(), 0);
+            w.write_short_ids(request.ids(), len)?;
+        }
+    }
+
+    if sketches {
+        w.write_varint(msg.sketch_cells.into());
+        w.write_len(msg.sketches.len());
+        for sketch in &msg.sketches {
+            w.write_range(sketch.range(), 0);
+            w.write_embedded(format, sketch.iblt())?;
+        }
+    }
+
+    if short_id_key {
+        let ShortIdKey(k0, k1) = msg.short_id_key;
+        w.buf.extend(k0.to_be_bytes());
+        w.buf.extend(k1.to_be_bytes());
+    }
+
+    Ok(w.buf)
+}
+
+/// Decodes a message in the compact format, whose fingerprints and objects are embedded in
+/// `format`.
+pub fn decode<M, O>(format: Format, bytes: &[u8]) -> Result<Message<M, O>, CodecError>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize + CompactItem,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let mut r = Reader::<M::Item>::new(bytes);
+
+    let version = r.read_byte()?;
+    if version != FORMAT_VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+
+    let flags = r.read_byte()?;
+    let known = FLAG_MORE | DIRECTION_MASK | FLAG_SHORT_IDS | FLAG_SKETCHES | FLAG_SHORT_ID_KEY;
+    if flags & !known != 0 {
+        return Err(CodecError::InvalidFlags(flags));
+    }
+    let direction = match (flags & DIRECTION_MASK) >> DIRECTION_SHIFT {
+        0 => Direction::Both,
+        1 => Direction::Pull,
+        2 => Direction::Push,
+        _ => return Err(CodecError::InvalidFlags(flags)),
+    };
+
+    let count = r.read_count()?;
+    let mut fps = vec![];
+    for _ in 0..count {
+        let (range, _) = r.read_range()?;
+        let fp = r.read_embedded(format)?;
+        fps.push(Fingerprint { range, fp });
+    }
+
+    let count = r.read_count()?;
+    let mut item_sets = vec![];
+    for _ in 0..count {
+        let (range, tag) = r.read_range()?;
+
+        r.prev.clear();
+        range.from().write_compact(&mut r.prev);
+
+        let items = r.read_items()?;
+        item_sets.push(ItemSet {
+            range,
+            items,
+            want_response: tag & RANGE_WANTS_RESPONSE != 0,
+        });
+    }
+
+    let wants = r.read_items()?;
+
+    let count = r.read_count()?;
+    let mut provide = vec![];
+    for _ in 0..count {
+        provide.push(r.read_embedded(format)?);
+    }
+
+    let not_available = r.read_items()?;
+
+    let short_ids = flags & FLAG_SHORT_IDS != 0;
+    let short_id_len = if short_ids { r.read_byte()? } else { 0 };
+    let len = match short_id_len {
+        0 => MAX_SHORT_ID_LEN,
+        len => len,
+    };
+
+    let mut short_item_sets = vec![];
+    let mut short_id_requests = vec![];
+    if short_ids {
+        let count = r.read_count()?;
+        for _ in 0..count {
+            let (range, tag) = r.read_range()?;
+            let ids = r.read_short_ids(len)?;
+
+            r.prev.clear();
+            range.from().write_compact(&mut r.prev);
+            let items = r.read_items()?;
+
+            let want_response = tag & RANGE_WANTS_RESPONSE != 0;
+            short_item_sets.push(ShortItemSet::new(range, ids, items, want_response));
+        }
+
+        let count = r.read_count()?;
+        for _ in 0..count {
+            let (range, _) = r.read_range()?;
+            let ids = r.read_short_ids(len)?;
+            short_id_requests.push(ShortIdRequest::new(range, ids));
+        }
+    }
+
+    let mut sketch_cells = 0;
+    let mut sketches = vec![];
+    if flags & FLAG_SKETCHES != 0 {
+        sketch_cells = r
+            .read_varint()?
+            .try_into()
+            .map_err(|_| CodecError::InvalidVarint)?;
+
+        let count = r.read_count()?;
+        for _ in 0..count {
+            let (range, _) = r.read_range()?;
+            let iblt = r.read_embedded(format)?;
+            sketches.push(Sketch::new(range, iblt));
+        }
+    }
+
+    let mut short_id_key = ShortIdKey::default();
+    if flags & FLAG_SHORT_ID_KEY != 0 {
+        short_id_key = ShortIdKey(r.read_u64()?, r.read_u64()?);
+    }
+
+    if !r.bytes.is_empty() {
+        return Err(CodecError::TrailingBytes(r.bytes.len()));
+    }
+
+    Ok(Message {
+        fps,
+        item_sets,
+        wants,
+        provide,
+        not_available,
+        more: flags & FLAG_MORE != 0,
+        direction,
+        short_id_len,
+        short_item_sets,
+        short_id_requests,
+        sketch_cells,
+        sketches,
+        short_id_key,
+    })
+}
+
+struct Writer<I: CompactItem> {
+    buf: Vec<u8>,
+    /// The encoding of the last item written, the next one is encoded relative to it.
+    prev: Vec<u8>,
+    /// The end of the last range written.
+    prev_to: Option<I>,
+}
+
+impl<I: CompactItem> Writer<I> {
+    fn new() -> Self {
+        Self {
+            buf: vec![],
+            prev: vec![0; I::COMPACT_LEN],
+            prev_to: None,
+        }
+    }
+
+    fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn write_len(&mut self, len: usize) {
+        self.write_varint(len as u64);
+    }
+
+    fn write_item(&mut self, item: &I) {
+        let mut bytes = Vec::with_capacity(I::COMPACT_LEN);
+        item.write_compact(&mut bytes);
+
+        let shared = self
+            .prev
+            .iter()
+            .zip(&bytes)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let trailing_zeros = bytes[shared..]
+            .iter()
+            .rev()
+            .take_while(|b| **b == 0)
+            .count();
+        let suffix = &bytes[shared..bytes.len() - trailing_zeros];
+
+        self.write_len(shared);
+        self.write_len(suffix.len());
+        self.buf.extend_from_slice(suffix);
+
+        self.prev = bytes;
+    }
+
+    fn write_items(&mut self, items: &[I]) {
+        self.write_len(items.len());
+        for item in items {
+            self.write_item(item);
+        }
+    }
+
+    fn write_range(&mut self, range: &Range<I>, mut tag: u8) {
+        let shares_from = self.prev_to.as_ref() == Some(range.from());
+        if shares_from {
+            tag |= RANGE_SHARES_FROM;
+        }
+        self.buf.push(tag);
+
+        if shares_from {
+            self.prev.clear();
+            range.from().write_compact(&mut self.prev);
+        } else {
+            self.write_item(range.from());
+        }
+
+        self.write_item(range.to());
+        self.prev_to = Some(range.to().clone());
+    }
+
+    /// Writes the IDs with `len` bytes each.
+    fn write_short_ids(&mut self, ids: &[u64], len: u8) -> Result<(), CodecError> {
+        if len == 0 || len > MAX_SHORT_ID_LEN {
+            return Err(CodecError::InvalidShortId);
+        }
+
+        self.write_len(ids.len());
+        for id in ids {
+            let bytes = id.to_be_bytes();
+            let (high, low) = bytes.split_at(8 - len as usize);
+            if high.iter().any(|b| *b != 0) {
+                return Err(CodecError::InvalidShortId);
+            }
+            self.buf.extend_from_slice(low);
+        }
+        Ok(())
+    }
+
+    fn write_embedded<T: Serialize>(
+        &mut self,
+        format: Format,
+        value: &T,
+    ) -> Result<(), CodecError> {
+        let encoded = format.encode(value).map_err(CodecError::Format)?;
+        self.write_len(encoded.len());
+        self.buf.extend(encoded);
+        Ok(())
+    }
+}
+
+struct Reader<'a, I: CompactItem> {
+    bytes: &'a [u8],
+    prev: Vec<u8>,
+    prev_to: Option<I>,
+}
+
+impl<'a, I: CompactItem> Reader<'a, I> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            prev: vec![0; I::COMPACT_LEN],
+            prev_to: None,
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, CodecError> {
+        let (first, rest) = self.bytes.split_first().ok_or(CodecError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(*first)
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if len > self.bytes.len() {
+            return Err(CodecError::UnexpectedEnd);
+        }
+
+        let (slice, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(slice)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, CodecError> {
+        let bytes = self.read_slice(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_varint(&mut self) -> Result<u64, CodecError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if bits << shift >> shift != bits {
+                return Err(CodecError::InvalidVarint);
+            }
+
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(CodecError::InvalidVarint)
+    }
+
+    fn read_len(&mut self) -> Result<usize, CodecError> {
+        let len = self.read_varint()?;
+        usize::try_from(len).map_err(|_| CodecError::InvalidVarint)
+    }
+
+    /// Reads the number of elements of a list. Every element takes at least one byte, so larger
+    /// counts can't be valid and we don't have to allocate for them.
+    fn read_count(&mut self) -> Result<usize, CodecError> {
+        let count = self.read_len()?;
+        if count > self.bytes.len() {
+            return Err(CodecError::UnexpectedEnd);
+        }
+
+        Ok(count)
+    }
+
+    fn read_item(&mut self) -> Result<I, CodecError> {
+        let shared = self.read_len()?;
+        let suffix_len = self.read_len()?;
+        if shared > I::COMPACT_LEN || suffix_len > I::COMPACT_LEN - shared {
+            return Err(CodecError::InvalidItem);
+        }
+
+        let suffix = self.read_slice(suffix_len)?;
+        self.prev.truncate(shared);
+        self.prev.extend_from_slice(suffix);
+        self.prev.resize(I::COMPACT_LEN, 0);
+
+        Ok(I::read_compact(&self.prev))
+    }
+
+    fn read_items(&mut self) -> Result<Vec<I>, CodecError> {
+        let count = self.read_count()?;
+        let mut items = Vec::with_capacity(count);
+        for _ in 0..count {
+            items.push(self.read_item()?);
+        }
+
+        Ok(items)
+    }
+
+    fn read_range(&mut self) -> Result<(Range<I>, u8), CodecError> {
+        let tag = self.read_byte()?;
+        if tag & !(RANGE_SHARES_FROM | RANGE_WANTS_RESPONSE) != 0 {
+            return Err(CodecError::InvalidFlags(tag));
+        }
+
+        let from = if tag & RANGE_SHARES_FROM != 0 {
+            let from = self.prev_to.clone().ok_or(CodecError::InvalidFlags(tag))?;
+            self.prev.clear();
+            from.write_compact(&mut self.prev);
+            from
+        } else {
+            self.read_item()?
+        };
+
+        let to = self.read_item()?;
+        self.prev_to = Some(to.clone());
+
+        Ok((Range(from, to), tag))
+    }
+
+    fn read_short_ids(&mut self, len: u8) -> Result<Vec<u64>, CodecError> {
+        if len == 0 || len > MAX_SHORT_ID_LEN {
+            return Err(CodecError::InvalidShortId);
+        }
+
+        let count = self.read_count()?;
+        let mut ids = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut id = [0u8; 8];
+            id[8 - len as usize..].copy_from_slice(self.read_slice(len as usize)?);
+            ids.push(u64::from_be_bytes(id));
+        }
+        Ok(ids)
+    }
+
+    fn read_embedded<T>(&mut self, format: Format) -> Result<T, CodecError>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let len = self.read_len()?;
+        let bytes = self.read_slice(len)?;
+        format.decode(bytes).map_err(CodecError::Format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec;
+
+    use proptest::{prop_assert_eq, proptest};
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        item::le_byte_array::LEByteArray,
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{tests::arb_message, CodecError, Direction, Format, ItemSet, Message},
+        range::Range,
+    };
+
+    use super::{decode, encode, CompactItem, RANGE_SHARES_FROM, RANGE_WANTS_RESPONSE};
+
+    proptest! {
+        #[test]
+        fn roundtrip(msg in arb_message()) {
+            let encoded = encode(Format::default(), &msg).unwrap();
+            let decoded = decode(Format::default(), &encoded).unwrap();
+            prop_assert_eq!(msg, decoded);
+        }
+
+        #[test]
+        fn smaller_than_cbor(msg in arb_message()) {
+            let compact = encode(Format::default(), &msg).unwrap();
+            let cbor = serde_cbor::to_vec(&msg).unwrap();
+            proptest::prop_assert!(compact.len() <= cbor.len());
+        }
+    }
+
+    #[test]
+    fn shares_range_bounds() {
+        let item_sets = vec![
+            ItemSet::new(Range(0x1000u64, 0x1100), vec![0x1001, 0x1002], true),
+            ItemSet::new(Range(0x1100, 0x1200), vec![], false),
+        ];
+        let msg: Message<CountingSha256Xor<TestItem>, TestObject> =
+            Message::new(vec![], item_sets, vec![], vec![]).with_direction(Direction::Pull);
+
+        let encoded = encode(Format::default(), &msg).unwrap();
+        #[rustfmt::skip]
+        let expected = vec![
+            1, // version
+            1 << 1, // flags: pull
+            0, // no fingerprints
+            2, // two item sets
+            // first range wants a response and has an explicit start
+            RANGE_WANTS_RESPONSE,
+            6, 1, 0x10, // from: 6 zero bytes, then 0x10 and a dropped zero
+            6, 1, 0x11, // to: shares 6 bytes with from
+            // two items, relative to the start of the range
+            2, 7, 1, 0x01, 7, 1, 0x02,
+            // the second range starts where the first ended
+            RANGE_SHARES_FROM,
+            6, 1, 0x12,
+            0, // no items
+            0, 0, 0, // no wants, provides or unavailable items
+        ];
+        assert_eq!(encoded, expected);
+        assert!(encoded.len() * 4 < serde_cbor::to_vec(&msg).unwrap().len());
+        assert_eq!(decode(Format::default(), &encoded).unwrap(), msg);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        type M = CountingSha256Xor<TestItem>;
+
+        assert!(matches!(
+            decode::<M, TestObject>(Format::default(), &[]),
+            Err(CodecError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            decode::<M, TestObject>(Format::default(), &[2, 0]),
+            Err(CodecError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            decode::<M, TestObject>(Format::default(), &[1, 0x80]),
+            Err(CodecError::InvalidFlags(0x80))
+        ));
+        // a huge item count
+        assert!(matches!(
+            decode::<M, TestObject>(Format::default(), &[1, 0, 0, 0, 0xff, 0xff, 0xff, 0x7f]),
+            Err(CodecError::UnexpectedEnd)
+        ));
+        // a shared prefix longer than the item
+        assert!(matches!(
+            decode::<M, TestObject>(Format::default(), &[1, 0, 0, 0, 1, 9, 0, 0, 0]),
+            Err(CodecError::InvalidItem)
+        ));
+        assert!(matches!(
+            decode::<M, TestObject>(Format::default(), &[1, 0, 0, 0, 0, 0, 0, 0]),
+            Err(CodecError::TrailingBytes(1))
+        ));
+    }
+
+    #[test]
+    fn le_byte_array_order() {
+        let mut a = LEByteArray([0u8; 3]);
+        let mut b = LEByteArray([0u8; 3]);
+        a.0[0] = 2;
+        b.0[2] = 1;
+        assert!(a < b);
+
+        let (mut bytes_a, mut bytes_b) = (vec![], vec![]);
+        a.write_compact(&mut bytes_a);
+        b.write_compact(&mut bytes_b);
+        assert!(bytes_a < bytes_b);
+        assert_eq!(LEByteArray::read_compact(&bytes_a), a);
+    }
+}
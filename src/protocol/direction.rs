@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+/// Which way items flow in a session. Every message carries the direction from the point of view
+/// of its sender, so a reply carries the [reversed](Direction::reverse) direction of the message
+/// it answers. The initiator picks the direction in the first message, after that it is only
+/// passed back and forth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// Both sides end up with the union of their items.
+    #[default]
+    Both,
+    /// The sender only learns items from the peer, but never hands out its own.
+    Pull,
+    /// The sender only hands out items to the peer, but never learns any.
+    Push,
+}
+
+impl Direction {
+    /// The same direction from the point of view of the peer.
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::Both => Direction::Both,
+            Direction::Pull => Direction::Push,
+            Direction::Push => Direction::Pull,
+        }
+    }
+
+    /// Whether the side with this direction asks for items it doesn't have.
+    pub fn learns(self) -> bool {
+        self != Direction::Push
+    }
+
+    /// Whether the side with this direction sends items the peer doesn't have.
+    pub fn shares(self) -> bool {
+        self != Direction::Pull
+    }
+
+    /// Whether a side that is fine with this direction is also fine with `other`, i.e. whether
+    /// `other` neither learns nor shares where this one doesn't.
+    pub fn allows(self, other: Direction) -> bool {
+        (self.learns() || !other.learns()) && (self.shares() || !other.shares())
+    }
+}
@@ -1,13 +1,33 @@
 extern crate alloc;
 extern crate std;
-use alloc::format;
+use alloc::{format, string::String, vec::Vec};
 
-use super::{DecodeError, EncodeError, ProtocolMonoid};
+use crate::range::Range;
+
+use super::{budget::Resource, DecodeError, EncodeError, Features, ProtocolMonoid, Role};
 
 #[derive(Debug, Clone)]
 pub enum RespondError<M: ProtocolMonoid> {
     EncodeError(M::EncodeError),
     DecodeError(M::DecodeError),
+    /// The peer sent a range that is not inside the range we agreed to sync.
+    OutOfRange(Range<M::Item>),
+    /// The peer asked for, or sent, a sketch with more cells than
+    /// [`MAX_CELLS`](super::sketch::MAX_CELLS).
+    SketchTooLarge(usize),
+    /// The peer uses short IDs shorter than
+    /// [`MIN_SHORT_ID_LEN`](super::short_id::MIN_SHORT_ID_LEN) bytes.
+    ShortIdTooShort(u8),
+    /// The split strategy returned sizes that don't add up to the number of items in the range.
+    InvalidSplit(Range<M::Item>),
+    /// The peer sent a message that breaks the rules of the protocol.
+    Invalid(ValidationError<M>),
+    /// Answering the peer would take more than the [`SessionBudget`](super::SessionBudget)
+    /// allows.
+    OverBudget {
+        resource: Resource,
+        limit: usize,
+    },
 }
 
 impl<M: ProtocolMonoid> From<EncodeError<M::EncodeError>> for RespondError<M> {
@@ -22,11 +42,23 @@ impl<M: ProtocolMonoid> From<DecodeError<M::DecodeError>> for RespondError<M> {
     }
 }
 
+impl<M: ProtocolMonoid> From<ValidationError<M>> for RespondError<M> {
+    fn from(value: ValidationError<M>) -> Self {
+        Self::Invalid(value)
+    }
+}
+
 impl<M: ProtocolMonoid> std::error::Error for RespondError<M> {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             RespondError::EncodeError(e) => Some(e),
             RespondError::DecodeError(e) => Some(e),
+            RespondError::OutOfRange(_)
+            | RespondError::SketchTooLarge(_)
+            | RespondError::ShortIdTooShort(_)
+            | RespondError::InvalidSplit(_)
+            | RespondError::Invalid(_)
+            | RespondError::OverBudget { .. } => None,
         }
     }
 }
@@ -36,6 +68,338 @@ impl<M: ProtocolMonoid> core::fmt::Display for RespondError<M> {
         match self {
             RespondError::EncodeError(e) => f.write_str(&format!("encoding error: {e}")),
             RespondError::DecodeError(e) => f.write_str(&format!("encoding error: {e}")),
+            RespondError::OutOfRange(range) => {
+                f.write_str(&format!("range {range} is outside of the synced range"))
+            }
+            RespondError::SketchTooLarge(cells) => {
+                f.write_str(&format!("sketch with {cells} cells is too large"))
+            }
+            RespondError::ShortIdTooShort(len) => {
+                f.write_str(&format!("short IDs of {len} bytes are too short"))
+            }
+            RespondError::InvalidSplit(range) => f.write_str(&format!(
+                "split sizes don't add up to the number of items in range {range}"
+            )),
+            RespondError::Invalid(e) => f.write_str(&format!("invalid message: {e}")),
+            RespondError::OverBudget { resource, limit } => {
+                f.write_str(&format!("budget of {limit} {resource} exceeded"))
+            }
+        }
+    }
+}
+
+/// How a message of the peer breaks the rules of the protocol, see [`super::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError<M: ProtocolMonoid> {
+    /// The items listed for the range are not sorted in the order of the range, or some are
+    /// listed twice.
+    UnsortedItems(Range<M::Item>),
+    /// An item is listed for a range it is not in.
+    ItemOutOfRange {
+        range: Range<M::Item>,
+        item: M::Item,
+    },
+    /// The peer asks us to compare ranges that share items.
+    OverlappingRanges(Range<M::Item>, Range<M::Item>),
+    /// The peer wants an item we never listed.
+    UnknownWant(M::Item),
+    /// A fingerprint claims to cover more items than anyone syncs.
+    ImplausibleCount { range: Range<M::Item>, count: usize },
+}
+
+impl<M: ProtocolMonoid> std::error::Error for ValidationError<M> {}
+
+impl<M: ProtocolMonoid> core::fmt::Display for ValidationError<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ValidationError::UnsortedItems(range) => {
+                f.write_str(&format!("items of range {range} are not sorted"))
+            }
+            ValidationError::ItemOutOfRange { range, item } => {
+                f.write_str(&format!("item {item:?} is not in range {range}"))
+            }
+            ValidationError::OverlappingRanges(a, b) => {
+                f.write_str(&format!("ranges {a} and {b} overlap"))
+            }
+            ValidationError::UnknownWant(item) => {
+                f.write_str(&format!("peer wants item {item:?}, which we never listed"))
+            }
+            ValidationError::ImplausibleCount { range, count } => f.write_str(&format!(
+                "fingerprint of range {range} claims to cover {count} items"
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError<M: ProtocolMonoid> {
+    RespondError(RespondError<M>),
+    /// Only the initiator starts a session.
+    NotInitiator,
+    /// The session was already started.
+    AlreadyStarted,
+    /// The session is over, no more messages are handled.
+    Finished,
+    /// The peer kept the session going for longer than we allow.
+    TooManyRounds(usize),
+    /// The peer asks for a direction we don't allow, or doesn't stick to the direction of the
+    /// session.
+    DirectionMismatch,
+    /// The peer doesn't stick to the short ID key of the session.
+    ShortIdKeyMismatch,
+}
+
+impl<M: ProtocolMonoid> From<RespondError<M>> for SessionError<M> {
+    fn from(value: RespondError<M>) -> Self {
+        Self::RespondError(value)
+    }
+}
+
+impl<M: ProtocolMonoid> From<EncodeError<M::EncodeError>> for SessionError<M> {
+    fn from(value: EncodeError<M::EncodeError>) -> Self {
+        Self::RespondError(value.into())
+    }
+}
+
+impl<M: ProtocolMonoid> std::error::Error for SessionError<M> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::RespondError(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl<M: ProtocolMonoid> core::fmt::Display for SessionError<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SessionError::RespondError(e) => e.fmt(f),
+            SessionError::NotInitiator => f.write_str("only the initiator can start a session"),
+            SessionError::AlreadyStarted => f.write_str("session already started"),
+            SessionError::Finished => f.write_str("session already finished"),
+            SessionError::TooManyRounds(max) => {
+                f.write_str(&format!("session exceeded maximum of {max} rounds"))
+            }
+            SessionError::DirectionMismatch => {
+                f.write_str("peer doesn't follow the direction of the session")
+            }
+            SessionError::ShortIdKeyMismatch => {
+                f.write_str("peer doesn't use the short ID key of the session")
+            }
+        }
+    }
+}
+
+/// Why we can't sync with a peer, as found out during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The peer speaks a different version of the protocol.
+    VersionMismatch { ours: u32, theirs: u32 },
+    /// The peer encodes its messages in a different format, given by its tag.
+    FormatMismatch { ours: u8, theirs: u8 },
+    /// The peer uses a different fingerprint monoid.
+    MonoidMismatch { ours: String, theirs: String },
+    /// The peer syncs a different kind of item.
+    ItemMismatch { ours: String, theirs: String },
+    /// Features we need, but the peer doesn't support.
+    MissingFeatures(Features),
+    /// The peer's hello is cut off or holds names that aren't UTF-8.
+    InvalidHello,
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl core::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HandshakeError::VersionMismatch { ours, theirs } => f.write_str(&format!(
+                "protocol version mismatch: we speak {ours}, peer speaks {theirs}"
+            )),
+            HandshakeError::FormatMismatch { ours, theirs } => f.write_str(&format!(
+                "format mismatch: we use format {ours}, peer uses format {theirs}"
+            )),
+            HandshakeError::MonoidMismatch { ours, theirs } => f.write_str(&format!(
+                "monoid mismatch: we use {ours}, peer uses {theirs}"
+            )),
+            HandshakeError::ItemMismatch { ours, theirs } => f.write_str(&format!(
+                "item mismatch: we sync {ours}, peer syncs {theirs}"
+            )),
+            HandshakeError::MissingFeatures(features) => {
+                f.write_str(&format!("peer lacks required features: {features:?}"))
+            }
+            HandshakeError::InvalidHello => f.write_str("malformed hello"),
+        }
+    }
+}
+
+/// Why a message in the compact format couldn't be encoded or decoded.
+#[derive(Debug)]
+pub enum CodecError {
+    /// The input ended in the middle of the message.
+    UnexpectedEnd,
+    /// There are bytes left after the end of the message.
+    TrailingBytes(usize),
+    /// The message was written in a version of the format we don't know.
+    UnsupportedVersion(u8),
+    /// A flags byte has bits set that we don't know.
+    InvalidFlags(u8),
+    /// A varint doesn't fit into 64 bits.
+    InvalidVarint,
+    /// An item doesn't fit into the length of the item type.
+    InvalidItem,
+    /// A short ID doesn't fit into the short ID length of the message.
+    InvalidShortId,
+    /// A fingerprint or object failed to encode or decode.
+    Format(FormatError),
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CodecError::UnexpectedEnd => f.write_str("unexpected end of message"),
+            CodecError::TrailingBytes(n) => {
+                f.write_str(&format!("{n} unexpected bytes after end of message"))
+            }
+            CodecError::UnsupportedVersion(v) => {
+                f.write_str(&format!("unsupported format version {v}"))
+            }
+            CodecError::InvalidFlags(flags) => f.write_str(&format!("invalid flags {flags:#x}")),
+            CodecError::InvalidVarint => f.write_str("varint out of range"),
+            CodecError::InvalidItem => f.write_str("item has invalid length"),
+            CodecError::InvalidShortId => f.write_str("short id has invalid length"),
+            CodecError::Format(e) => f.write_str(&format!("embedded value: {e}")),
+        }
+    }
+}
+
+/// Why a value couldn't be encoded or decoded in one of the [`Format`](super::Format)s.
+#[derive(Debug)]
+pub enum FormatError {
+    /// serde_cbor failed.
+    #[cfg(feature = "serde_cbor")]
+    Cbor(serde_cbor::Error),
+    /// ciborium failed. Its errors are generic over the I/O error type, so only the message is
+    /// kept.
+    #[cfg(feature = "ciborium")]
+    Ciborium(String),
+    /// postcard failed.
+    #[cfg(feature = "postcard")]
+    Postcard(postcard::Error),
+    /// bincode failed.
+    #[cfg(feature = "bincode")]
+    Bincode(bincode::Error),
+    /// There are bytes left after the end of the value.
+    TrailingBytes(usize),
+    /// The tag of a [`Format`](super::Format) is unknown, or its feature isn't enabled.
+    UnknownFormat(u8),
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "serde_cbor")]
+            FormatError::Cbor(e) => Some(e),
+            #[cfg(feature = "postcard")]
+            FormatError::Postcard(e) => Some(e),
+            #[cfg(feature = "bincode")]
+            FormatError::Bincode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            #[cfg(feature = "serde_cbor")]
+            FormatError::Cbor(e) => f.write_str(&format!("cbor error: {e}")),
+            #[cfg(feature = "ciborium")]
+            FormatError::Ciborium(e) => f.write_str(&format!("cbor error: {e}")),
+            #[cfg(feature = "postcard")]
+            FormatError::Postcard(e) => f.write_str(&format!("postcard error: {e}")),
+            #[cfg(feature = "bincode")]
+            FormatError::Bincode(e) => f.write_str(&format!("bincode error: {e}")),
+            FormatError::TrailingBytes(n) => {
+                f.write_str(&format!("{n} unexpected bytes after end of value"))
+            }
+            FormatError::UnknownFormat(tag) => {
+                f.write_str(&format!("unknown or disabled format {tag}"))
+            }
+        }
+    }
+}
+
+/// Why a session couldn't be recorded or replayed, see [`super::transcript`].
+#[derive(Debug)]
+pub enum TranscriptError<M: ProtocolMonoid> {
+    /// A message couldn't be encoded or decoded.
+    Format(FormatError),
+    Session(SessionError<M>),
+    /// The transcript was recorded by the other side of the session.
+    RoleMismatch(Role),
+    /// The replay didn't send what was recorded at entry `index`. `None` means that no message
+    /// was recorded or sent, respectively.
+    Diverged {
+        index: usize,
+        recorded: Option<Vec<u8>>,
+        replayed: Option<Vec<u8>>,
+    },
+}
+
+impl<M: ProtocolMonoid> From<FormatError> for TranscriptError<M> {
+    fn from(value: FormatError) -> Self {
+        Self::Format(value)
+    }
+}
+
+impl<M: ProtocolMonoid> From<SessionError<M>> for TranscriptError<M> {
+    fn from(value: SessionError<M>) -> Self {
+        Self::Session(value)
+    }
+}
+
+impl<M: ProtocolMonoid> std::error::Error for TranscriptError<M> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TranscriptError::Format(e) => Some(e),
+            TranscriptError::Session(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl<M: ProtocolMonoid> core::fmt::Display for TranscriptError<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TranscriptError::Format(e) => e.fmt(f),
+            TranscriptError::Session(e) => e.fmt(f),
+            TranscriptError::RoleMismatch(role) => f.write_str(&format!(
+                "transcript was recorded by the {role:?}, but the session isn't"
+            )),
+            TranscriptError::Diverged {
+                index,
+                recorded,
+                replayed,
+            } => {
+                let describe = |msg: &Option<Vec<u8>>| match msg {
+                    Some(bytes) => format!("a message of {} bytes", bytes.len()),
+                    None => String::from("nothing"),
+                };
+                f.write_str(&format!(
+                    "replay diverged at entry {index}: recorded {}, replayed {}",
+                    describe(recorded),
+                    describe(replayed)
+                ))
+            }
         }
     }
 }
@@ -0,0 +1,265 @@
+//! Serialization formats for messages and everything else we send over the wire. Each backend is
+//! behind a cargo feature of the same name, and at least one of them needs to be enabled:
+//!
+//! - `serde_cbor`: CBOR using [serde_cbor](https://docs.rs/serde_cbor) (enabled by default)
+//! - `ciborium`: CBOR using [ciborium](https://docs.rs/ciborium)
+//! - `postcard`: [postcard](https://docs.rs/postcard), well suited for embedded peers
+//! - `bincode`: [bincode](https://docs.rs/bincode) with varint-encoded integers
+//!
+//! Note that `default-features = false` also turns off `serde_cbor`, so a build without the
+//! default features needs to name one of them.
+//!
+//! The two CBOR backends are not guaranteed to produce the same bytes, so both peers need to agree
+//! on the same [`Format`], not just on CBOR.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::Object;
+
+use super::{FormatError, Message, ProtocolMonoid};
+
+#[cfg(not(any(
+    feature = "serde_cbor",
+    feature = "ciborium",
+    feature = "postcard",
+    feature = "bincode"
+)))]
+compile_error!(
+    "at least one of the features `serde_cbor`, `ciborium`, `postcard` or `bincode` is required"
+);
+
+/// A serialization format. Which variants exist depends on the enabled cargo features.
+///
+/// A format is serialized as its tag, a fixed `u8` that doesn't depend on which of the other
+/// formats are enabled, so builds with different features agree on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+// without any format the enum is empty, which can't have a `repr`; leave the error to the
+// `compile_error!` above
+#[cfg_attr(
+    any(
+        feature = "serde_cbor",
+        feature = "ciborium",
+        feature = "postcard",
+        feature = "bincode"
+    ),
+    repr(u8)
+)]
+pub enum Format {
+    #[cfg(feature = "serde_cbor")]
+    Cbor = 0,
+    #[cfg(feature = "ciborium")]
+    Ciborium = 1,
+    #[cfg(feature = "postcard")]
+    Postcard = 2,
+    #[cfg(feature = "bincode")]
+    Bincode = 3,
+}
+
+impl From<Format> for u8 {
+    fn from(format: Format) -> Self {
+        format as u8
+    }
+}
+
+impl TryFrom<u8> for Format {
+    type Error = FormatError;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        Format::ALL
+            .iter()
+            .copied()
+            .find(|format| u8::from(*format) == tag)
+            .ok_or(FormatError::UnknownFormat(tag))
+    }
+}
+
+impl Default for Format {
+    /// The first enabled format, in the order the variants are declared.
+    fn default() -> Self {
+        Self::ALL[0]
+    }
+}
+
+impl Format {
+    /// All enabled formats.
+    pub const ALL: &'static [Format] = &[
+        #[cfg(feature = "serde_cbor")]
+        Format::Cbor,
+        #[cfg(feature = "ciborium")]
+        Format::Ciborium,
+        #[cfg(feature = "postcard")]
+        Format::Postcard,
+        #[cfg(feature = "bincode")]
+        Format::Bincode,
+    ];
+
+    /// Encodes `value` in this format.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FormatError> {
+        match *self {
+            #[cfg(feature = "serde_cbor")]
+            Format::Cbor => serde_cbor::to_vec(value).map_err(FormatError::Cbor),
+            #[cfg(feature = "ciborium")]
+            Format::Ciborium => {
+                let mut buf = Vec::new();
+                ciborium::ser::into_writer(value, &mut buf)
+                    .map_err(|e| FormatError::Ciborium(alloc::format!("{e}")))?;
+                Ok(buf)
+            }
+            #[cfg(feature = "postcard")]
+            Format::Postcard => postcard::to_allocvec(value).map_err(FormatError::Postcard),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => {
+                use bincode::Options;
+                bincode::DefaultOptions::new()
+                    .serialize(value)
+                    .map_err(FormatError::Bincode)
+            }
+        }
+    }
+
+    /// Decodes a value that takes up all of `bytes`.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FormatError> {
+        match *self {
+            #[cfg(feature = "serde_cbor")]
+            Format::Cbor => serde_cbor::from_slice(bytes).map_err(FormatError::Cbor),
+            #[cfg(feature = "ciborium")]
+            Format::Ciborium => {
+                let mut rest = bytes;
+                let value = ciborium::de::from_reader(&mut rest)
+                    .map_err(|e| FormatError::Ciborium(alloc::format!("{e}")))?;
+                match rest.len() {
+                    0 => Ok(value),
+                    n => Err(FormatError::TrailingBytes(n)),
+                }
+            }
+            #[cfg(feature = "postcard")]
+            Format::Postcard => {
+                let (value, rest) =
+                    postcard::take_from_bytes(bytes).map_err(FormatError::Postcard)?;
+                match rest.len() {
+                    0 => Ok(value),
+                    n => Err(FormatError::TrailingBytes(n)),
+                }
+            }
+            #[cfg(feature = "bincode")]
+            Format::Bincode => {
+                use bincode::Options;
+                // the default options already reject trailing bytes
+                bincode::DefaultOptions::new()
+                    .deserialize(bytes)
+                    .map_err(FormatError::Bincode)
+            }
+        }
+    }
+
+    /// The number of bytes `value` takes up when encoded, or `usize::MAX` if it can't be encoded.
+    pub(crate) fn encoded_len<T: Serialize>(&self, value: &T) -> usize {
+        self.encode(value).map_or(usize::MAX, |buf| buf.len())
+    }
+}
+
+/// Encodes a message in the given format.
+pub fn encode_message<M, O>(format: Format, msg: &Message<M, O>) -> Result<Vec<u8>, FormatError>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    format.encode(msg)
+}
+
+/// Decodes a message in the given format. Trailing bytes are an error.
+pub fn decode_message<M, O>(format: Format, bytes: &[u8]) -> Result<Message<M, O>, FormatError>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    format.decode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec;
+
+    use proptest::{prop_assert_eq, proptest};
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{tests::arb_message, ItemSet, Message},
+        range::Range,
+    };
+
+    use super::{decode_message, encode_message, Format, FormatError};
+
+    proptest! {
+        #[test]
+        fn roundtrip(msg in arb_message()) {
+            for &format in Format::ALL {
+                let encoded = encode_message(format, &msg).unwrap();
+                prop_assert_eq!(&decode_message(format, &encoded).unwrap(), &msg);
+            }
+        }
+    }
+
+    #[test]
+    fn stable_tags() {
+        #[cfg(feature = "serde_cbor")]
+        assert_eq!(u8::from(Format::Cbor), 0);
+        #[cfg(feature = "ciborium")]
+        assert_eq!(u8::from(Format::Ciborium), 1);
+        #[cfg(feature = "postcard")]
+        assert_eq!(u8::from(Format::Postcard), 2);
+        #[cfg(feature = "bincode")]
+        assert_eq!(u8::from(Format::Bincode), 3);
+
+        for &format in Format::ALL {
+            assert_eq!(Format::try_from(u8::from(format)).unwrap(), format);
+            for &outer in Format::ALL {
+                // formats are written as their tag, whatever format they are written in
+                let encoded = outer.encode(&format).unwrap();
+                assert_eq!(encoded, outer.encode(&u8::from(format)).unwrap());
+                assert_eq!(outer.decode::<Format>(&encoded).unwrap(), format);
+            }
+        }
+
+        assert!(matches!(
+            Format::try_from(4),
+            Err(FormatError::UnknownFormat(4))
+        ));
+        let encoded = Format::default().encode(&4u8).unwrap();
+        assert!(Format::default().decode::<Format>(&encoded).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let item_sets = vec![ItemSet::new(Range(1, 10), vec![2, 5, 7], true)];
+        let msg: Message<CountingSha256Xor<TestItem>, TestObject> =
+            Message::new(vec![], item_sets, vec![3], vec![(4, true)]);
+
+        for &format in Format::ALL {
+            let mut encoded = encode_message(format, &msg).unwrap();
+            let decoded: Message<_, _> = decode_message(format, &encoded).unwrap();
+            assert_eq!(decoded, msg);
+
+            encoded.push(0);
+            let result: Result<Message<CountingSha256Xor<TestItem>, TestObject>, _> =
+                decode_message(format, &encoded);
+            assert!(result.is_err(), "{format:?} accepted trailing bytes");
+        }
+    }
+}
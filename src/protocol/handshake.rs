@@ -0,0 +1,319 @@
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Format, HandshakeError, ProtocolMonoid};
+
+/// The version of the wire format. It needs to be bumped whenever [`super::Message`] or one of
+/// its parts changes in a way old peers can't decode. In the positional formats, postcard and
+/// bincode, that is any change at all: they can't skip fields they don't know or fill in missing
+/// ones, `#[serde(default)]` or not.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// A stable name for a type, sent during the handshake so peers can tell whether they use the same
+/// monoid and items. Unlike [`core::any::type_name`], it must not change between compiler
+/// versions or when the type is moved to another module.
+pub trait WireId {
+    fn wire_id() -> String;
+}
+
+/// Optional parts of the protocol a peer understands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Replies may be split over several messages, see [`super::MessageLimits`].
+    pub const CHUNKING: Self = Self(1 << 0);
+    /// Items may only flow one way, see [`super::Direction`].
+    pub const DIRECTION: Self = Self(1 << 1);
+    /// Only a part of the item space is synced, see [`super::first_message_for_range`].
+    pub const RANGE: Self = Self(1 << 2);
+    /// Small ranges may list short IDs instead of items, see [`super::ShortItemSet`].
+    pub const SHORT_IDS: Self = Self(1 << 3);
+    /// Ranges may be reconciled with sketches instead of being split, see [`super::Sketch`].
+    pub const SKETCHES: Self = Self(1 << 4);
+
+    /// All features this version of the library supports.
+    pub const SUPPORTED: Self = Self(
+        Self::CHUNKING.0 | Self::DIRECTION.0 | Self::RANGE.0 | Self::SHORT_IDS.0 | Self::SKETCHES.0,
+    );
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Fails if not all of `required` are in `self`.
+    pub fn require(self, required: Self) -> Result<(), HandshakeError> {
+        let missing = Self(required.0 & !self.0);
+        if missing == Self::NONE {
+            Ok(())
+        } else {
+            Err(HandshakeError::MissingFeatures(missing))
+        }
+    }
+}
+
+/// The first thing both peers send, before any [`super::Message`]. Unlike messages, a hello is
+/// always written in the same hand-rolled encoding, whatever [`Format`] the session uses, so peers
+/// can tell each other their version and format even if they disagree on them:
+///
+/// - the version as a big-endian `u32`
+/// - the [tag](Format) of the format of the messages as a `u8`
+/// - the features as a big-endian `u64`
+/// - the monoid and then the item, each as its length as a big-endian `u32` followed by UTF-8
+///
+/// This must stay decodable by all versions. Later versions may append fields, which older ones
+/// ignore, but must not change the ones above; new capabilities go into [`Features`] instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    version: u32,
+    format: u8,
+    monoid: String,
+    item: String,
+    features: Features,
+}
+
+impl Hello {
+    /// Describes us, using monoid `M`, the default format and supporting all features of this
+    /// version.
+    pub fn new<M>() -> Self
+    where
+        M: ProtocolMonoid + WireId,
+        M::Item: WireId,
+    {
+        Self {
+            version: PROTOCOL_VERSION,
+            format: Format::default().into(),
+            monoid: M::wire_id(),
+            item: M::Item::wire_id(),
+            features: Features::SUPPORTED,
+        }
+    }
+
+    /// Sends the messages of the session in `format`.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format.into();
+        self
+    }
+
+    /// Only announces the given features.
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The tag of the format of the messages. It is kept as a tag, because the peer may use a
+    /// format that isn't enabled here.
+    pub fn format(&self) -> u8 {
+        self.format
+    }
+
+    pub fn monoid(&self) -> &str {
+        &self.monoid
+    }
+
+    pub fn item(&self) -> &str {
+        &self.item
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Checks whether we can sync with the peer that sent `theirs`. Returns the features both
+    /// sides support.
+    pub fn check(&self, theirs: &Hello) -> Result<Features, HandshakeError> {
+        if self.version != theirs.version {
+            return Err(HandshakeError::VersionMismatch {
+                ours: self.version,
+                theirs: theirs.version,
+            });
+        }
+
+        if self.format != theirs.format {
+            return Err(HandshakeError::FormatMismatch {
+                ours: self.format,
+                theirs: theirs.format,
+            });
+        }
+
+        if self.monoid != theirs.monoid {
+            return Err(HandshakeError::MonoidMismatch {
+                ours: self.monoid.clone(),
+                theirs: theirs.monoid.clone(),
+            });
+        }
+
+        if self.item != theirs.item {
+            return Err(HandshakeError::ItemMismatch {
+                ours: self.item.clone(),
+                theirs: theirs.item.clone(),
+            });
+        }
+
+        Ok(self.features.intersection(theirs.features))
+    }
+
+    /// Writes the hello in the fixed encoding described on [`Hello`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.version.to_be_bytes());
+        out.push(self.format);
+        out.extend_from_slice(&self.features.0.to_be_bytes());
+        for name in [&self.monoid, &self.item] {
+            out.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            out.extend_from_slice(name.as_bytes());
+        }
+        out
+    }
+
+    /// Reads a hello written by [`Hello::encode`] of this or any later version.
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, HandshakeError> {
+        let version = u32::from_be_bytes(take(&mut bytes)?);
+        let [format] = take(&mut bytes)?;
+        let features = Features(u64::from_be_bytes(take(&mut bytes)?));
+        let monoid = take_name(&mut bytes)?;
+        let item = take_name(&mut bytes)?;
+
+        // whatever is left was appended by a later version
+        Ok(Self {
+            version,
+            format,
+            monoid,
+            item,
+            features,
+        })
+    }
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], HandshakeError> {
+    let head = bytes.get(..N).ok_or(HandshakeError::InvalidHello)?;
+    *bytes = &bytes[N..];
+    Ok(head.try_into().unwrap())
+}
+
+fn take_name(bytes: &mut &[u8]) -> Result<String, HandshakeError> {
+    let len = u32::from_be_bytes(take(bytes)?) as usize;
+    let name = bytes.get(..len).ok_or(HandshakeError::InvalidHello)?;
+    *bytes = &bytes[len..];
+    String::from_utf8(name.to_vec()).map_err(|_| HandshakeError::InvalidHello)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::string::ToString;
+
+    use crate::{
+        easy::{tests::TestItem, uniform},
+        monoid::{hashxor::CountingSha256Xor, timestamped::Timestamped},
+        protocol::HandshakeError,
+    };
+
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        let ours = Hello::new::<uniform::Monoid>();
+        assert_eq!(ours.monoid(), "CountingMonoid<MulHashMonoid<Xsk233>>");
+        assert_eq!(ours.item(), "LEByteArray<30>");
+
+        let theirs = Hello::new::<uniform::Monoid>().with_features(Features::CHUNKING);
+        let features = ours.check(&theirs).unwrap();
+        assert_eq!(features, Features::CHUNKING);
+        assert!(features.require(Features::CHUNKING).is_ok());
+        assert!(matches!(
+            features.require(Features::DIRECTION.union(Features::CHUNKING)),
+            Err(HandshakeError::MissingFeatures(Features::DIRECTION))
+        ));
+
+        let other_monoid = Hello::new::<CountingSha256Xor<TestItem>>();
+        assert_eq!(other_monoid.monoid(), "CountingSha256Xor<u64>");
+        assert!(matches!(
+            Hello::new::<CountingSha256Xor<u32>>().check(&other_monoid),
+            Err(HandshakeError::MonoidMismatch { .. })
+        ));
+
+        let timestamped = Hello::new::<Timestamped<u64, uniform::Monoid>>();
+        assert_eq!(
+            timestamped.item(),
+            "TimestampedItem<u64, LEByteArray<30>>".to_string()
+        );
+        assert!(matches!(
+            ours.check(&timestamped),
+            Err(HandshakeError::MonoidMismatch { .. })
+        ));
+
+        let mut newer = ours.clone();
+        newer.version += 1;
+        let err = ours.check(&newer).unwrap_err();
+        assert_eq!(
+            err,
+            HandshakeError::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: PROTOCOL_VERSION + 1
+            }
+        );
+    }
+
+    #[test]
+    fn format_mismatch() {
+        let ours = Hello::new::<uniform::Monoid>();
+        assert_eq!(ours.format(), u8::from(Format::default()));
+
+        // e.g. a format that isn't even enabled here
+        let mut theirs = ours.clone();
+        theirs.format = 42;
+        assert_eq!(
+            ours.check(&theirs),
+            Err(HandshakeError::FormatMismatch {
+                ours: u8::from(Format::default()),
+                theirs: 42
+            })
+        );
+    }
+
+    #[test]
+    fn decode_hello() {
+        let hello = Hello::new::<uniform::Monoid>().with_features(Features::CHUNKING);
+        let encoded = hello.encode();
+        assert_eq!(Hello::decode(&encoded), Ok(hello.clone()));
+
+        for len in 0..encoded.len() {
+            assert_eq!(
+                Hello::decode(&encoded[..len]),
+                Err(HandshakeError::InvalidHello)
+            );
+        }
+    }
+
+    #[test]
+    fn decode_newer_hello() {
+        // a future version may append fields, we still need to be able to read the version
+        let mut future = Hello::new::<uniform::Monoid>().with_features(Features(1 << 42));
+        future.version = PROTOCOL_VERSION + 1;
+        let mut encoded = future.encode();
+        encoded.extend_from_slice(b"zstd");
+
+        let decoded = Hello::decode(&encoded).unwrap();
+        assert_eq!(decoded, future);
+        assert!(matches!(
+            Hello::new::<uniform::Monoid>().check(&decoded),
+            Err(HandshakeError::VersionMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,362 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{range::Range, Item, Object};
+
+use super::{Format, ItemSet, Message, ProtocolMonoid};
+
+/// How much an array header can grow when elements are added to an empty array.
+const ARRAY_HEADER_SLACK: usize = 8;
+
+/// The number of lists in a message.
+const MESSAGE_LISTS: usize = 8;
+
+/// Upper bounds for the messages we send. Whatever doesn't fit into one message is deferred to the
+/// following ones. The default doesn't limit anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// The maximum size of an encoded message.
+    pub max_bytes: usize,
+    /// The maximum number of objects provided in a single message.
+    pub max_objects: usize,
+    /// The format messages are encoded in, which determines their size.
+    pub format: Format,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: usize::MAX,
+            max_objects: usize::MAX,
+            format: Format::default(),
+        }
+    }
+}
+
+impl MessageLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes == usize::MAX && self.max_objects == usize::MAX
+    }
+}
+
+struct Budget {
+    format: Format,
+    bytes_left: usize,
+    objects_left: usize,
+    taken_any: bool,
+}
+
+impl Budget {
+    /// Moves elements from the front of `src` to `dst` as long as they fit into the budget. If
+    /// nothing has been taken yet, the first element is taken even if it's too large, so we always
+    /// make progress.
+    fn take<T: Serialize>(&mut self, src: &mut Vec<T>, dst: &mut Vec<T>, are_objects: bool) {
+        let mut count = 0;
+        for elem in src.iter() {
+            let size = self.format.encoded_len(elem);
+            let fits = size <= self.bytes_left && (!are_objects || self.objects_left > 0);
+            if !fits && self.taken_any {
+                break;
+            }
+
+            self.bytes_left = self.bytes_left.saturating_sub(size);
+            if are_objects {
+                self.objects_left = self.objects_left.saturating_sub(1);
+            }
+            self.taken_any = true;
+            count += 1;
+        }
+
+        dst.extend(src.drain(..count));
+    }
+
+    /// Like [`Budget::take`], but splits the first item set that doesn't fit, so even ranges with
+    /// many items can be sent over several messages.
+    fn take_item_sets<M>(&mut self, src: &mut Vec<ItemSet<M>>, dst: &mut Vec<ItemSet<M>>)
+    where
+        M: ProtocolMonoid,
+        M::Item: Serialize,
+        M::Encoded: Serialize,
+        for<'de2> M::Item: Deserialize<'de2>,
+        for<'de2> M::Encoded: Deserialize<'de2>,
+    {
+        while !src.is_empty() {
+            let size = self.format.encoded_len(&src[0]);
+            if size <= self.bytes_left {
+                self.bytes_left -= size;
+                self.taken_any = true;
+                dst.push(src.remove(0));
+                continue;
+            }
+
+            let item_set = &mut src[0];
+            let header = ItemSet::<M>::new(item_set.range.clone(), Vec::new(), false);
+            let mut bytes_left = self.bytes_left.saturating_sub(
+                self.format
+                    .encoded_len(&header)
+                    .saturating_add(ARRAY_HEADER_SLACK),
+            );
+            let mut count = 0;
+            for item in item_set.ordered_items() {
+                let size = self.format.encoded_len(item);
+                if size > bytes_left {
+                    break;
+                }
+                bytes_left -= size;
+                count += 1;
+            }
+
+            if !self.taken_any {
+                count = count.max(1);
+            }
+
+            if count == 0 {
+                break;
+            }
+
+            match item_set.split_off_front(count) {
+                Some(front) => dst.push(front),
+                None => dst.push(src.remove(0)),
+            }
+            self.bytes_left = 0;
+            self.taken_any = true;
+            break;
+        }
+    }
+}
+
+impl<M> ItemSet<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    /// Returns the items in the order they appear in the range. In a wrapping range, the items
+    /// before `from` come last.
+    fn ordered_items(&self) -> Vec<&M::Item> {
+        let mut ordered: Vec<&M::Item> = self.items.iter().collect();
+        ordered.sort_by_key(|item| self.range.position(item));
+        ordered
+    }
+
+    /// Splits the range at the `count`-th item, counted from the start of the range. The item set
+    /// covering the first part of the range is returned, and `self` is left with the rest. Returns
+    /// `None` if there is nothing to split.
+    fn split_off_front(&mut self, count: usize) -> Option<Self> {
+        if count == 0 || count >= self.items.len() {
+            return None;
+        }
+
+        let ordered = self.ordered_items();
+        let (last, next) = (ordered[count - 1], ordered[count]);
+        let boundary = if last < next {
+            M::Item::separator(last, next)
+        } else {
+            next.clone()
+        };
+        let Range(from, to) = self.range.clone();
+
+        let front_range = Range(from, boundary.clone());
+        let (front_items, back_items) = self
+            .items
+            .drain(..)
+            .partition(|item| front_range.contains(item));
+
+        self.items = back_items;
+        self.range = Range(boundary, to);
+
+        Some(Self::new(front_range, front_items, self.want_response))
+    }
+}
+
+impl<M, O> Message<M, O>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    /// Appends the contents of `other` to this message and takes over its settings, like the
+    /// direction. Whether more messages follow `other` is not carried over.
+    pub fn append(&mut self, mut other: Self) {
+        self.fps.append(&mut other.fps);
+        self.item_sets.append(&mut other.item_sets);
+        self.wants.append(&mut other.wants);
+        self.provide.append(&mut other.provide);
+        self.not_available.append(&mut other.not_available);
+        self.short_item_sets.append(&mut other.short_item_sets);
+        self.short_id_requests.append(&mut other.short_id_requests);
+        self.sketches.append(&mut other.sketches);
+        self.direction = other.direction;
+        self.short_id_len = other.short_id_len;
+        self.sketch_cells = other.sketch_cells;
+        self.short_id_key = other.short_id_key;
+    }
+
+    /// Removes as much from the message as fits into `limits` and returns it as a new message.
+    /// If something remains, the returned message is marked as having more to follow.
+    ///
+    /// A single part that exceeds the byte limit on its own is still returned, since it can't be
+    /// split any further.
+    pub fn take_chunk(&mut self, limits: &MessageLimits) -> Self {
+        let mut chunk = Self::new(Vec::new(), Vec::new(), Vec::new(), Vec::new())
+            .with_direction(self.direction)
+            .with_short_ids(self.short_id_len)
+            .with_sketches(self.sketch_cells)
+            .with_short_id_key(self.short_id_key);
+        if limits.is_unlimited() {
+            core::mem::swap(self, &mut chunk);
+            return chunk;
+        }
+
+        chunk.more = true;
+        let overhead = limits
+            .format
+            .encoded_len(&chunk)
+            .saturating_add(MESSAGE_LISTS * ARRAY_HEADER_SLACK);
+        let mut budget = Budget {
+            format: limits.format,
+            bytes_left: limits.max_bytes.saturating_sub(overhead),
+            objects_left: limits.max_objects,
+            taken_any: false,
+        };
+
+        // small things first, they help the peer make progress the most
+        budget.take(&mut self.not_available, &mut chunk.not_available, false);
+        budget.take(&mut self.wants, &mut chunk.wants, false);
+        budget.take(
+            &mut self.short_id_requests,
+            &mut chunk.short_id_requests,
+            false,
+        );
+        budget.take(&mut self.fps, &mut chunk.fps, false);
+        budget.take(&mut self.short_item_sets, &mut chunk.short_item_sets, false);
+        budget.take(&mut self.sketches, &mut chunk.sketches, false);
+        budget.take_item_sets(&mut self.item_sets, &mut chunk.item_sets);
+        budget.take(&mut self.provide, &mut chunk.provide, true);
+
+        chunk.more = !self.is_empty();
+        self.more = false;
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{vec, vec::Vec};
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{encode_message, ItemSet, Message},
+        range::Range,
+    };
+
+    use super::MessageLimits;
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+
+    #[test]
+    fn chunks_respect_limits() {
+        let item_sets = (0..50u64)
+            .map(|i| {
+                ItemSet::new(
+                    Range(i * 10, i * 10 + 10),
+                    (i * 10..i * 10 + 5).collect(),
+                    true,
+                )
+            })
+            .collect();
+        let wants = (1000..1100).collect();
+        let provide = (2000..2100).map(|i| (i, true)).collect();
+        let mut msg: Message<TestMonoid, TestObject> =
+            Message::new(vec![], item_sets, wants, provide);
+        let expected = msg.clone();
+
+        let limits = MessageLimits {
+            max_bytes: 400,
+            max_objects: 7,
+            ..Default::default()
+        };
+
+        let mut reassembled = Message::new(vec![], vec![], vec![], vec![]);
+        let mut chunks = 0;
+        loop {
+            let chunk = msg.take_chunk(&limits);
+            chunks += 1;
+
+            assert!(encode_message(limits.format, &chunk).unwrap().len() <= limits.max_bytes);
+            assert!(chunk.provide().len() <= limits.max_objects);
+            assert!(!chunk.is_empty());
+
+            let more = chunk.has_more();
+            reassembled.append(chunk);
+            if !more {
+                break;
+            }
+        }
+
+        assert!(msg.is_end());
+        assert!(chunks > 1);
+        assert!(!reassembled.has_more());
+        assert_eq!(reassembled, expected);
+
+        let wants: Vec<_> = reassembled.wants().clone();
+        assert_eq!(wants, (1000..1100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn splits_large_item_sets() {
+        // a wrapping range, so the items at the end of the range are smaller than the ones at
+        // the start
+        let range = Range(900u64, 100);
+        let items: Vec<u64> = (0..100).chain(900..1000).collect();
+        let item_set = ItemSet::new(range, items.clone(), true);
+        let mut msg: Message<TestMonoid, TestObject> =
+            Message::new(vec![], vec![item_set], vec![], vec![]);
+
+        let limits = MessageLimits {
+            max_bytes: 200,
+            ..Default::default()
+        };
+
+        let mut pieces = vec![];
+        loop {
+            let chunk = msg.take_chunk(&limits);
+            assert!(encode_message(limits.format, &chunk).unwrap().len() <= limits.max_bytes);
+            let more = chunk.has_more();
+            pieces.extend(chunk.item_sets().iter().cloned());
+            if !more {
+                break;
+            }
+        }
+
+        assert!(pieces.len() > 1);
+        assert_eq!(pieces.first().unwrap().range().from(), &900);
+        assert_eq!(pieces.last().unwrap().range().to(), &100);
+
+        let mut received = vec![];
+        for pair in pieces.windows(2) {
+            assert_eq!(pair[0].range().to(), pair[1].range().from());
+        }
+        for piece in &pieces {
+            assert!(piece.want_response());
+            for item in piece.items() {
+                assert!(piece.range().contains(item));
+                received.push(*item);
+            }
+        }
+
+        received.sort();
+        assert_eq!(received, items);
+    }
+}
@@ -1,22 +1,74 @@
 extern crate alloc;
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeSet, vec, vec::Vec};
 
 extern crate std;
+use std::{cell::Cell, time::Instant};
 
 pub mod encoding;
 pub use encoding::{DecodeError, Encodable, EncodeError};
 
+pub mod budget;
+pub use budget::{Resource, SessionBudget};
+
+mod canonical;
+
+pub mod compact;
+pub use compact::CompactItem;
+
+pub mod direction;
+pub use direction::Direction;
+
 pub mod error;
-pub use error::RespondError;
+pub use error::{
+    CodecError, FormatError, HandshakeError, RespondError, SessionError, TranscriptError,
+    ValidationError,
+};
+
+pub mod format;
+pub use format::{decode_message, encode_message, Format};
+
+pub mod handshake;
+pub use handshake::{Features, Hello, WireId, PROTOCOL_VERSION};
+
+pub mod limits;
+pub use limits::MessageLimits;
+
+pub mod received;
+pub use received::{Received, RejectReason, Rejected};
+
+pub mod session;
+pub use session::{Outcome, Role, Session};
+
+pub mod short_id;
+pub use short_id::{ShortIdKey, ShortIdRequest, ShortItemSet};
+
+pub mod sketch;
+pub use sketch::Sketch;
+
+pub mod split;
+pub use split::SplitStrategy;
+
+pub mod stats;
+pub use stats::{PartSizes, SyncStats};
+
+pub mod transcript;
+pub use transcript::{Flow, Recorder, Transcript};
+
+pub mod tuning;
+pub use tuning::{AdaptiveTuning, FixedTuning, Observations, Tuning};
+
+pub mod validate;
+pub use validate::validate;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    easy,
     item::Item,
     monoid::Monoid,
     query::{
-        item_filter::ItemFilterAccumulator, items::ItemsAccumulator, simple::SimpleAccumulator,
-        split::SplitAccumulator,
+        iblt::IbltAccumulator, item_filter::ItemFilterAccumulator, items::ItemsAccumulator,
+        simple::SimpleAccumulator, split::SplitAccumulator,
     },
     range::Range,
     Node, NonNilNodeRef, Object, ObjectStore,
@@ -120,6 +172,9 @@ where
     }
 }
 
+/// The fields added after the first four are `#[serde(default)]`, so that peers using a CBOR
+/// format can decode messages of older versions. Postcard and bincode are positional and can't,
+/// which is why any change to the fields needs a new [`PROTOCOL_VERSION`].
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(bound = "M::Item: Serialize, for<'de2> M::Item: Deserialize<'de2>")]
 pub struct Message<M, O>
@@ -136,6 +191,32 @@ where
     item_sets: Vec<ItemSet<M>>,
     wants: Vec<M::Item>,
     provide: Vec<O>,
+    /// Items the peer wanted, but that we don't have (anymore).
+    #[serde(default)]
+    not_available: Vec<M::Item>,
+    /// Set if parts of the response didn't fit into this message and will follow in later ones.
+    /// The peer must not end the session before they arrived.
+    #[serde(default)]
+    more: bool,
+    /// Which way items flow, from the point of view of the sender.
+    #[serde(default)]
+    direction: Direction,
+    /// The length of the short IDs in bytes, or zero if item sets list full items.
+    #[serde(default)]
+    short_id_len: u8,
+    #[serde(default)]
+    short_item_sets: Vec<ShortItemSet<M>>,
+    #[serde(default)]
+    short_id_requests: Vec<ShortIdRequest<M>>,
+    /// The number of cells of the sketches the sender would like to receive, or zero if ranges
+    /// should be split instead.
+    #[serde(default)]
+    sketch_cells: u32,
+    #[serde(default)]
+    sketches: Vec<Sketch<M>>,
+    /// The key of the short IDs in item sets, requests and sketches, picked by the initiator.
+    #[serde(default)]
+    short_id_key: ShortIdKey,
 }
 
 impl<M, O> Message<M, O>
@@ -159,14 +240,71 @@ where
             item_sets,
             wants,
             provide,
+            not_available: vec![],
+            more: false,
+            direction: Direction::Both,
+            short_id_len: 0,
+            short_item_sets: vec![],
+            short_id_requests: vec![],
+            sketch_cells: 0,
+            sketches: vec![],
+            short_id_key: ShortIdKey::default(),
         }
     }
 
+    /// Sets the items that the peer asked for, but that we can't provide.
+    pub fn with_not_available(mut self, not_available: Vec<M::Item>) -> Self {
+        self.not_available = not_available;
+        self
+    }
+
+    /// Sets which way items flow, from our point of view.
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Sets the length of the short IDs for the item sets of small ranges, see [`short_id`]. Zero
+    /// turns them off. The peer replies with the same length, and refuses lengths below
+    /// [`short_id::MIN_SHORT_ID_LEN`].
+    pub fn with_short_ids(mut self, len: u8) -> Self {
+        self.short_id_len = len;
+        self
+    }
+
+    /// Sets the number of cells of the sketches that are sent instead of splitting ranges, see
+    /// [`sketch`]. Zero turns them off. The peer replies with the same number.
+    pub fn with_sketches(mut self, cells: u32) -> Self {
+        self.sketch_cells = cells;
+        self
+    }
+
+    /// Sets the key of the short IDs, see [`short_id`]. The peer replies with the same key.
+    pub fn with_short_id_key(mut self, key: ShortIdKey) -> Self {
+        self.short_id_key = key;
+        self
+    }
+
     pub fn is_end(&self) -> bool {
+        self.is_empty() && !self.more
+    }
+
+    /// Whether the message has no contents. Unlike [`Message::is_end`], this ignores whether more
+    /// messages follow.
+    pub fn is_empty(&self) -> bool {
         self.fps.is_empty()
             && self.item_sets.is_empty()
             && self.wants.is_empty()
             && self.provide.is_empty()
+            && self.not_available.is_empty()
+            && self.short_item_sets.is_empty()
+            && self.short_id_requests.is_empty()
+            && self.sketches.is_empty()
+    }
+
+    /// Whether the sender has more to send after this message.
+    pub fn has_more(&self) -> bool {
+        self.more
     }
 
     pub fn fingerprints(&self) -> &Vec<Fingerprint<M>> {
@@ -184,6 +322,51 @@ where
     pub fn provide(&self) -> &Vec<O> {
         &self.provide
     }
+
+    pub fn not_available(&self) -> &Vec<M::Item> {
+        &self.not_available
+    }
+
+    /// Which way items flow, from the point of view of the sender.
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The length of the short IDs in bytes, or zero if they are not used.
+    pub fn short_id_len(&self) -> u8 {
+        self.short_id_len
+    }
+
+    pub fn short_item_sets(&self) -> &Vec<ShortItemSet<M>> {
+        &self.short_item_sets
+    }
+
+    pub fn short_id_requests(&self) -> &Vec<ShortIdRequest<M>> {
+        &self.short_id_requests
+    }
+
+    pub fn short_id_key(&self) -> ShortIdKey {
+        self.short_id_key
+    }
+
+    /// The length of the short IDs in requests and sketches. Without short item sets, these use
+    /// the full length.
+    pub fn id_len(&self) -> u8 {
+        match self.short_id_len {
+            0 => short_id::MAX_SHORT_ID_LEN,
+            len => len,
+        }
+    }
+
+    /// The number of cells of the sketches the sender would like to receive, or zero if it
+    /// doesn't use them.
+    pub fn sketch_cells(&self) -> u32 {
+        self.sketch_cells
+    }
+
+    pub fn sketches(&self) -> &Vec<Sketch<M>> {
+        &self.sketches
+    }
 }
 
 pub fn first_message<O, M, N>(root: &N) -> Result<Message<M, O>, EncodeError<M::EncodeError>>
@@ -204,9 +387,17 @@ where
             let rev_range = range.reverse();
             let full_monoid = root.monoid().to_encoded()?;
 
+            // if the tree holds both the smallest and the largest item, the fingerprint already
+            // covers everything, and the item set would overlap with it
+            let item_sets = if range.is_full() {
+                vec![]
+            } else {
+                vec![ItemSet::new(rev_range, vec![], true)]
+            };
+
             Message::new(
                 vec![Fingerprint::new(range, full_monoid)],
-                vec![ItemSet::new(rev_range, vec![], true)],
+                item_sets,
                 vec![],
                 vec![],
             )
@@ -226,37 +417,221 @@ where
     Ok(msg)
 }
 
-pub fn respond_to_message<O, M, N, S>(
+/// Returns the first message of a session that only syncs the items in `range`. Both sides need
+/// to pass the same range to [`respond_to_message`], which makes sure nothing outside of it is
+/// exchanged.
+pub fn first_message_for_range<O, M, N>(
+    root: &N,
+    range: &Range<M::Item>,
+) -> Result<Message<M, O>, EncodeError<M::EncodeError>>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    N: Node<M>,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let mut acc = SimpleAccumulator::new();
+    root.query(range, &mut acc);
+    let monoid = acc.into_result();
+
+    let msg = if monoid.count() == 0 {
+        Message::new(
+            vec![],
+            vec![ItemSet::new(range.clone(), vec![], true)],
+            vec![],
+            vec![],
+        )
+    } else {
+        Message::new(
+            vec![Fingerprint::new(range.clone(), monoid.to_encoded()?)],
+            vec![],
+            vec![],
+            vec![],
+        )
+    };
+
+    Ok(msg)
+}
+
+/// Our reply to a message of the peer, and the objects it provided.
+pub type Response<M, O> = Result<(Message<M, O>, Received<O>), RespondError<M>>;
+
+/// Processes a message from the peer and computes our response. `round` is the number of
+/// messages that have been exchanged so far and is passed on to the split strategy.
+///
+/// `requested` holds the items we asked the peer for in earlier messages. Provided objects are
+/// only accepted if they are in there, see [`Received::check`].
+///
+/// Only items in `sync_range` are exchanged. If the peer sends a range that reaches outside of
+/// it, we fail with [`RespondError::OutOfRange`], and wanted items outside of it are reported as
+/// not available. Pass a full range, e.g. `Range(zero, zero)`, to sync everything.
+///
+/// Messages that break the rules of the protocol are rejected before we look at our tree, see
+/// [`validate`]. This doesn't check whether the peer only wants items we listed to it, because we
+/// don't know what earlier messages listed: every wanted object in `sync_range` is handed out.
+/// Only [`Session`] enforces this, with [`validate::check_wants`]; callers of this function need
+/// to do the same if they care.
+#[allow(clippy::too_many_arguments)]
+pub fn respond_to_message<O, M, N, S, Sp>(
     root: &N,
     object_store: &S,
     msg: &Message<M, O>,
+    requested: &BTreeSet<M::Item>,
+    sync_range: &Range<M::Item>,
     threshold: usize,
-    split: fn(usize) -> Vec<usize>,
-) -> Result<(Message<M, O>, Vec<O>), RespondError<M>>
+    split: &Sp,
+    round: usize,
+) -> Response<M, O>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    S: ObjectStore<M::Item, O>,
+    N: Node<M>,
+    Sp: SplitStrategy<M> + ?Sized,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    respond_to_message_with(
+        root,
+        object_store,
+        msg,
+        requested,
+        sync_range,
+        &mut FixedTuning::new(threshold, split),
+        round,
+        &SessionBudget::unlimited(),
+        &mut SyncStats::default(),
+    )
+}
+
+/// Like [`respond_to_message`], but `tuning` decides per range whether to list its items and how
+/// to split it, and is told what we learn about the differences along the way. The fingerprints
+/// we compare and the time spent decoding and encoding them are added to `stats`.
+///
+/// `stats` also tells how much of `budget` earlier messages of the session used up. If answering
+/// `msg` would exceed it, we fail with [`RespondError::OverBudget`].
+#[allow(clippy::too_many_arguments)]
+pub fn respond_to_message_with<O, M, N, S, T>(
+    root: &N,
+    object_store: &S,
+    msg: &Message<M, O>,
+    requested: &BTreeSet<M::Item>,
+    sync_range: &Range<M::Item>,
+    tuning: &mut T,
+    round: usize,
+    budget: &SessionBudget,
+    stats: &mut SyncStats,
+) -> Response<M, O>
 where
     M: ProtocolMonoid,
     O: Object<M::Item> + Serialize,
     S: ObjectStore<M::Item, O>,
     N: Node<M>,
+    T: Tuning<M> + ?Sized,
     M::Item: Serialize,
     M::Encoded: Serialize,
     for<'de2> M::Item: Deserialize<'de2>,
     for<'de2> M::Encoded: Deserialize<'de2>,
     for<'de2> O: Deserialize<'de2>,
 {
+    // caps the number of fingerprints and sketches before we decode them
+    budget.check_message(msg, stats)?;
+    validate(msg, sync_range)?;
+
+    stats.decodes += msg.fps.len() + msg.sketches.len();
+    let start = Instant::now();
+    let their_fps = msg
+        .fps
+        .iter()
+        .map(|fp| M::from_encoded(&fp.fp))
+        .collect::<Result<Vec<M>, _>>()?;
+    stats.decode_time += start.elapsed();
+    validate::check_counts(&msg.fps, &their_fps)?;
+
     let mut fingerprints = vec![];
     let mut item_sets = vec![];
+    let mut short_item_sets = vec![];
+    let mut short_id_requests = vec![];
+    let mut sketches = vec![];
     let mut wants = vec![];
 
+    // the direction from our point of view
+    let direction = msg.direction.reverse();
+    let short_id_len = msg.short_id_len;
+    let id_len = msg.id_len();
+    let key = msg.short_id_key;
+    let sketch_cells = msg.sketch_cells as usize;
+
+    // the peer picks the ranges, so listing their items is where it can make us work the hardest
+    let enumerated = Cell::new(stats.items_enumerated);
+    let items_in = |range: &Range<M::Item>| {
+        let mut acc = ItemsAccumulator::new();
+        root.query(range, &mut acc);
+        let items = acc.into_results();
+        enumerated.set(enumerated.get().saturating_add(items.len()));
+        budget.check(Resource::Items, enumerated.get())?;
+        Ok::<_, RespondError<M>>(items)
+    };
+
+    // the item set we send for a range that is small enough to be compared item by item
+    let small_item_set = |range: &Range<M::Item>,
+                          item_sets: &mut Vec<ItemSet<M>>,
+                          short_item_sets: &mut Vec<ShortItemSet<M>>| {
+        if !direction.shares() {
+            // the peer won't want any of our items, so it's enough to ask for theirs
+            item_sets.push(ItemSet::new(range.clone(), vec![], true));
+        } else if short_id_len > 0 {
+            short_item_sets.push(ShortItemSet::from_items(
+                range.clone(),
+                items_in(range)?,
+                &key,
+                short_id_len,
+                direction.learns(),
+            ));
+        } else {
+            item_sets.push(ItemSet::new(
+                range.clone(),
+                items_in(range)?,
+                direction.learns(),
+            ));
+        }
+        Ok::<_, RespondError<M>>(())
+    };
+
+    let fp_of = |range: &Range<M::Item>| {
+        let mut acc = SimpleAccumulator::new();
+        root.query(range, &mut acc);
+        acc.into_result()
+    };
+
+    // the depth of a range with that many items, see `tuning`
+    let total = fp_of(sync_range).count();
+    let depth_of = |count: usize| tuning::depth(total, count);
+
     let dummy_encoded_fp = M::neutral().to_encoded()?;
     let mut prep_raw = vec![];
     let mut prep_parts = vec![];
 
-    let provide = object_store
-        .get_batch(&msg.wants)
-        .into_iter()
-        .map(|opt_obj| opt_obj.unwrap().clone())
-        .collect();
+    // the peer may ask for items we deleted in the meantime, or for items that never existed.
+    // we tell them instead of failing the whole response.
+    let mut provide = vec![];
+    let mut not_available = vec![];
+    stats.objects_looked_up += msg.wants.len();
+    for (item, opt_obj) in msg.wants.iter().zip(object_store.get_batch(&msg.wants)) {
+        match opt_obj {
+            Some(obj) if direction.shares() && sync_range.contains(item) => {
+                provide.push(obj.clone())
+            }
+            _ => not_available.push(item.clone()),
+        }
+    }
 
     for item_set in msg.item_sets() {
         let ItemSet {
@@ -265,65 +640,164 @@ where
             want_response,
         } = item_set;
 
-        let mut dedup_acc = ItemFilterAccumulator::new(items);
+        if direction.learns() {
+            let mut dedup_acc = ItemFilterAccumulator::new(items);
 
-        // query_range() returns None if there are no items, in which case we don't need to add
-        // anything anyways
-        if let Some(dedup_query_range) = dedup_acc.query_range() {
-            root.query(&dedup_query_range, &mut dedup_acc);
-            wants.extend(dedup_acc.result().cloned());
+            // query_range() returns None if there are no items, in which case we don't need to
+            // add anything anyways
+            if let Some(dedup_query_range) = dedup_acc.query_range() {
+                root.query(&dedup_query_range, &mut dedup_acc);
+                let new = dedup_acc.result().count();
+                tuning.observe_items(items.len(), new);
+                wants.extend(dedup_acc.result().cloned());
+            }
         }
 
-        if *want_response {
-            let mut acc = ItemsAccumulator::new();
-            root.query(range, &mut acc);
-            item_sets.push(ItemSet::new(range.clone(), acc.into_results(), false));
+        if *want_response && direction.shares() {
+            item_sets.push(ItemSet::new(range.clone(), items_in(range)?, false));
         }
     }
 
-    for Fingerprint { range, fp } in msg.fingerprints() {
-        let their_fp = M::from_encoded(fp)?;
-        let mut my_fp_acc = SimpleAccumulator::new();
-        root.query(range, &mut my_fp_acc);
-        let my_fp = my_fp_acc.into_result();
+    for short_item_set in msg.short_item_sets() {
+        let range = short_item_set.range();
+        let ours = items_in(range)?;
+        let (unknown, missing) = short_item_set.compare(&ours, &key, id_len);
+
+        if direction.learns() {
+            let mut dedup_acc = ItemFilterAccumulator::new(short_item_set.items());
+            let mut new = unknown.len();
+            if let Some(dedup_query_range) = dedup_acc.query_range() {
+                root.query(&dedup_query_range, &mut dedup_acc);
+                new += dedup_acc.result().count();
+                wants.extend(dedup_acc.result().cloned());
+            }
 
+            let listed = short_item_set.ids().len() + short_item_set.items().len();
+            tuning.observe_items(listed, new);
+
+            if !unknown.is_empty() {
+                short_id_requests.push(ShortIdRequest::new(range.clone(), unknown));
+            }
+        }
+
+        if short_item_set.want_response() && direction.shares() && !missing.is_empty() {
+            let missing = missing.into_iter().cloned().collect();
+            item_sets.push(ItemSet::new(range.clone(), missing, false));
+        }
+    }
+
+    if direction.shares() {
+        for request in msg.short_id_requests() {
+            let ours = items_in(request.range())?;
+            item_sets.extend(request.resolve(ours, &key, id_len));
+        }
+    }
+
+    // the ranges that differ and are too large to be compared item by item
+    let mut to_split = vec![];
+
+    for (Fingerprint { range, .. }, their_fp) in msg.fingerprints().iter().zip(their_fps) {
+        let my_fp = fp_of(range);
+        let depth = depth_of(my_fp.count().max(their_fp.count()));
+        tuning.observe_fingerprint(depth, my_fp != their_fp);
+        stats.fingerprints_compared += 1;
         if my_fp != their_fp {
-            if my_fp.count() < threshold {
-                let mut acc = ItemsAccumulator::new();
+            stats.fingerprint_mismatches += 1;
+        }
+
+        if my_fp != their_fp {
+            // empty ranges can't be split, whatever the threshold
+            if my_fp.count() < tuning.threshold(depth).max(1) {
+                small_item_set(range, &mut item_sets, &mut short_item_sets)?;
+            } else if sketch::worth_sketching(my_fp.count(), their_fp.count(), sketch_cells) {
+                let mut acc = IbltAccumulator::new(sketch_cells, key, id_len);
                 root.query(range, &mut acc);
-                item_sets.push(ItemSet::new(range.clone(), acc.into_results(), true));
-                continue;
+                sketches.push(Sketch::new(range.clone(), acc.into_result()));
+            } else {
+                to_split.push((range.clone(), my_fp, depth));
             }
+        }
+    }
 
-            let splits = split(my_fp.count());
-            let mut acc = SplitAccumulator::new(range, &splits);
-            root.query(range, &mut acc);
-            let results = acc.results();
-            let ranges = acc.ranges();
-            for (i, fp) in results.iter().enumerate() {
-                let sub_range = &ranges[i];
-                if fp.count() < threshold {
-                    let mut acc = ItemsAccumulator::new();
-                    root.query(sub_range, &mut acc);
-                    item_sets.push(ItemSet::new(sub_range.clone(), acc.into_results(), true));
+    for sketch in msg.sketches() {
+        let range = sketch.range();
+        let mut acc = IbltAccumulator::new(sketch.iblt().len(), key, id_len);
+        root.query(range, &mut acc);
+        let difference = acc.into_result().subtract(sketch.iblt());
+
+        match difference.and_then(|difference| difference.decode()) {
+            Some((ours_only, theirs_only)) => {
+                if direction.shares() && !ours_only.is_empty() {
+                    let ours = ShortIdRequest::new(range.clone(), ours_only);
+                    item_sets.extend(ours.resolve(items_in(range)?, &key, id_len));
+                }
+                if direction.learns() && !theirs_only.is_empty() {
+                    short_id_requests.push(ShortIdRequest::new(range.clone(), theirs_only));
+                }
+            }
+            // the difference is too large, so we go back to splitting the range
+            None => {
+                let my_fp = fp_of(range);
+                let depth = depth_of(my_fp.count());
+                if my_fp.count() < tuning.threshold(depth).max(1) {
+                    small_item_set(range, &mut item_sets, &mut short_item_sets)?;
                 } else {
-                    prep_parts.push(Fingerprint::new(
-                        sub_range.clone(),
-                        dummy_encoded_fp.clone(),
-                    ));
-                    prep_raw.push(fp.clone());
+                    to_split.push((range.clone(), my_fp, depth));
                 }
             }
         }
     }
 
+    for (range, my_fp, depth) in &to_split {
+        let mut splits = tuning.split(range, my_fp, *depth, round);
+
+        // empty buckets would end up with bogus ranges, and a single bucket means we would
+        // send back the fingerprint we just received and never make progress.
+        splits.retain(|size| *size > 0);
+        if splits.len() < 2 {
+            splits = easy::uniform::split::<2>(my_fp.count());
+        }
+
+        let mut acc = SplitAccumulator::new(range, &splits);
+        root.query(range, &mut acc);
+        if acc.overflowed() {
+            return Err(RespondError::InvalidSplit(range.clone()));
+        }
+
+        let results = acc.results();
+        let ranges = acc.ranges();
+        for (i, fp) in results.iter().enumerate() {
+            let sub_range = &ranges[i];
+            if fp.count() < tuning.threshold(depth_of(fp.count())).max(1) {
+                small_item_set(sub_range, &mut item_sets, &mut short_item_sets)?;
+            } else {
+                prep_parts.push(Fingerprint::new(
+                    sub_range.clone(),
+                    dummy_encoded_fp.clone(),
+                ));
+                prep_raw.push(fp.clone());
+            }
+        }
+    }
+
+    stats.items_enumerated = enumerated.get();
+
+    let start = Instant::now();
     <M as Encodable>::batch_encode(&prep_raw, &mut prep_parts)?;
+    stats.encode_time += start.elapsed();
     fingerprints.extend(prep_parts.into_iter());
 
-    Ok((
-        Message::new(fingerprints, item_sets, wants, provide),
-        msg.provide.clone(),
-    ))
+    let mut reply = Message::new(fingerprints, item_sets, wants, provide)
+        .with_not_available(not_available)
+        .with_direction(direction)
+        .with_short_ids(short_id_len)
+        .with_sketches(msg.sketch_cells)
+        .with_short_id_key(key);
+    reply.short_item_sets = short_item_sets;
+    reply.short_id_requests = short_id_requests;
+    reply.sketches = sketches;
+
+    Ok((reply, Received::check(&msg.provide, requested)))
 }
 
 #[cfg(test)]
@@ -341,20 +815,36 @@ mod tests {
 
     use crate::{
         easy::{
-            tests::{TestMonoid, TestNode, TestObject},
+            tests::{TestItem, TestMonoid, TestNode, TestObject},
             uniform::split as uniform_split,
         },
         item::le_byte_array::LEByteArray,
-        monoid::{count::CountingMonoid, mulhash_xs233::MulHashMonoid},
+        monoid::{count::CountingMonoid, hashxor::CountingSha256Xor, mulhash_xs233::MulHashMonoid},
+        query::iblt::Iblt,
+        tree::mem_rc::Node,
         Monoid, Range,
     };
 
-    use proptest::{prelude::prop, prop_assert, prop_assert_eq, prop_compose, proptest};
+    use proptest::{
+        prelude::{prop, Just, Strategy},
+        prop_assert, prop_assert_eq, prop_compose, prop_oneof, proptest,
+    };
+
+    use super::{
+        split::UniformSplit, Direction, Encodable, Fingerprint, ItemSet, Message, ShortIdKey,
+        ShortIdRequest, ShortItemSet, Sketch,
+    };
 
-    use super::{Encodable, Fingerprint, ItemSet, Message};
+    // summing monoids collide easily, e.g. {1, 4} and {2, 3} have the same fingerprint, so we
+    // also check the protocol with hashes.
+    type HashMonoid = CountingSha256Xor<TestItem>;
+    type HashNode = Node<HashMonoid>;
 
     proptest! {
+        // sums of items collide, so this fails for some sets, like it always has. run it with
+        // `--ignored`; hashed_protocol_correctness below checks the same with a hashing monoid.
         #[test]
+        #[ignore]
         fn protocol_correctness(items_party_a in prop::collection::vec(1..1000u64, 1..100usize), items_party_b in prop::collection::vec(1..1000u64, 1..100usize)) {
             println!("---test run---");
 
@@ -386,6 +876,9 @@ mod tests {
 
             let mut missing_items_a = vec![];
             let mut missing_items_b = vec![];
+            let mut requested_a = BTreeSet::new();
+            let mut requested_b = BTreeSet::new();
+            let mut round = 0;
 
             loop {
                 println!("a msg: {msg:?}");
@@ -394,8 +887,11 @@ mod tests {
                 }
 
                 println!("b-----");
-                let (resp, new_objects) = super::respond_to_message(&root_b, &object_store_b, &msg, 3, uniform_split::<2>).unwrap();
-                missing_items_b.extend(new_objects.into_iter().map(|(item, _)| item));
+                round += 1;
+                let (resp, received) = super::respond_to_message(&root_b, &object_store_b, &msg, &requested_b, &Range::full(), 3, &uniform_split::<2>, round).unwrap();
+                prop_assert!(received.rejected().is_empty());
+                missing_items_b.extend(received.into_accepted().into_iter().map(|(item, _)| item));
+                requested_b.extend(resp.wants().iter().cloned());
 
                 println!("b msg: {resp:?}");
                 if resp.is_end() {
@@ -403,8 +899,11 @@ mod tests {
                 }
 
                 println!("a-----");
-                let (resp, new_objects) = super::respond_to_message(&root_a, &object_store_a, &resp, 3, uniform_split::<2>).unwrap();
-                missing_items_a.extend(new_objects.into_iter().map(|(item, _)| item));
+                round += 1;
+                let (resp, received) = super::respond_to_message(&root_a, &object_store_a, &resp, &requested_a, &Range::full(), 3, &uniform_split::<2>, round).unwrap();
+                prop_assert!(received.rejected().is_empty());
+                missing_items_a.extend(received.into_accepted().into_iter().map(|(item, _)| item));
+                requested_a.extend(resp.wants().iter().cloned());
 
                 msg = resp;
             }
@@ -443,6 +942,39 @@ mod tests {
             prop_assert!(a_eq, "a does not match");
             prop_assert!(b_eq, "a does not match");
         }
+
+        #[test]
+        fn hashed_protocol_correctness(items_a in prop::collection::vec(1..1000u64, 1..100usize), items_b in prop::collection::vec(1..1000u64, 1..100usize)) {
+            let items = [BTreeSet::from_iter(items_a), BTreeSet::from_iter(items_b)];
+            let roots = items.clone().map(|items| {
+                items.into_iter().fold(HashNode::nil(), |root, item| root.insert(item))
+            });
+            let mut stores = items.clone().map(|items| {
+                items.into_iter().map(|item| (item, (item, true))).collect::<BTreeMap<_, _>>()
+            });
+            let mut requested = [BTreeSet::new(), BTreeSet::new()];
+
+            let mut msg: Message<HashMonoid, TestObject> = super::first_message(&roots[0]).unwrap();
+            let mut side = 1;
+            let mut round = 0;
+            while !msg.is_end() {
+                round += 1;
+                let (resp, received) = super::respond_to_message(&roots[side], &stores[side], &msg, &requested[side], &Range::full(), 3, &UniformSplit::<2>, round).unwrap();
+                prop_assert!(received.rejected().is_empty());
+                for obj in received.into_accepted() {
+                    stores[side].insert(obj.0, obj);
+                }
+                requested[side].extend(resp.wants().iter().cloned());
+
+                msg = resp;
+                side = 1 - side;
+            }
+
+            let all: Vec<u64> = items[0].union(&items[1]).copied().collect();
+            for store in &stores {
+                prop_assert_eq!(store.keys().copied().collect::<Vec<_>>(), all.clone());
+            }
+        }
     }
 
     prop_compose! {
@@ -498,11 +1030,58 @@ mod tests {
             }
     }
 
+    type ShortItemSetParts = (Range<LEByteArray<30>>, Vec<u64>, Vec<LEByteArray<30>>, bool);
+
+    prop_compose! {
+        fn arb_short_item_set_parts()
+            (range in arb_range(), ids in proptest::collection::vec(proptest::num::u64::ANY, 0..10), items in proptest::collection::vec(arb_item(), 0..3), want_response in proptest::bool::ANY) -> ShortItemSetParts {
+                (range, ids, items, want_response)
+            }
+    }
+
+    prop_compose! {
+        fn arb_short_id_request_parts()
+            (range in arb_range(), ids in proptest::collection::vec(proptest::num::u64::ANY, 0..10)) -> (Range<LEByteArray<30>>, Vec<u64>) {
+                (range, ids)
+            }
+    }
+
+    prop_compose! {
+        fn arb_sketch_rec()
+            (range in arb_range(), size in 1..20usize, keys in proptest::collection::vec(proptest::num::u64::ANY, 0..10)) -> Sketch<CountingMonoid<MulHashMonoid<Xsk233Point>>> {
+                let mut iblt = Iblt::new(size);
+                for key in keys {
+                    iblt.insert(key);
+                }
+                Sketch::new(range, iblt)
+            }
+    }
+
+    fn arb_direction() -> impl Strategy<Value = Direction> {
+        prop_oneof![
+            Just(Direction::Both),
+            Just(Direction::Pull),
+            Just(Direction::Push)
+        ]
+    }
+
     prop_compose! {
-        fn arb_message()
-            (fps in proptest::collection::vec( arb_fp_rec(), 0..10), item_sets in proptest::collection::vec(arb_item_set_rec(), 0..10)) -> Message<CountingMonoid<MulHashMonoid<Xsk233Point>>, (LEByteArray<30>, bool)>{
+        pub(crate) fn arb_message()
+            (fps in proptest::collection::vec( arb_fp_rec(), 0..10), item_sets in proptest::collection::vec(arb_item_set_rec(), 0..10), more in proptest::bool::ANY, direction in arb_direction(), short_id_len in 0..=8u8, short_item_sets in proptest::collection::vec(arb_short_item_set_parts(), 0..3), short_id_requests in proptest::collection::vec(arb_short_id_request_parts(), 0..3), sketch_cells in proptest::num::u32::ANY, sketches in proptest::collection::vec(arb_sketch_rec(), 0..3), key in proptest::option::of((proptest::num::u64::ANY, proptest::num::u64::ANY))) -> Message<CountingMonoid<MulHashMonoid<Xsk233Point>>, (LEByteArray<30>, bool)>{
+                // the ids need to fit into the short id length
+                let mask = u64::MAX.checked_shr(64 - 8 * short_id_len as u32).unwrap_or(u64::MAX);
+                let mask_ids = |ids: Vec<u64>| ids.into_iter().map(|id| id & mask).collect();
+                let short_item_sets = short_item_sets
+                    .into_iter()
+                    .map(|(range, ids, items, want_response)| ShortItemSet::new(range, mask_ids(ids), items, want_response))
+                    .collect();
+                let short_id_requests = short_id_requests
+                    .into_iter()
+                    .map(|(range, ids)| ShortIdRequest::new(range, mask_ids(ids)))
+                    .collect();
                 Message{
-                    fps, item_sets, wants: vec![], provide: vec![]
+                    fps, item_sets, wants: vec![], provide: vec![], not_available: vec![], more, direction, short_id_len, short_item_sets, short_id_requests, sketch_cells, sketches,
+                    short_id_key: key.map_or(ShortIdKey::default(), |(k0, k1)| ShortIdKey(k0, k1)),
                 }
             }
     }
@@ -0,0 +1,150 @@
+extern crate alloc;
+use alloc::{collections::BTreeSet, vec::Vec};
+
+use crate::{Item, Object};
+
+/// Why we didn't accept an object the peer sent us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// [`Object::validate_self_consistency`] failed.
+    Inconsistent,
+    /// We never asked for the object.
+    NotRequested,
+    /// The object was sent more than once.
+    Duplicate,
+}
+
+impl core::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RejectReason::Inconsistent => f.write_str("object is not self-consistent"),
+            RejectReason::NotRequested => f.write_str("object was not requested"),
+            RejectReason::Duplicate => f.write_str("object was sent more than once"),
+        }
+    }
+}
+
+/// An object sent by the peer that we didn't accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected<O> {
+    object: O,
+    reason: RejectReason,
+}
+
+impl<O> Rejected<O> {
+    pub fn object(&self) -> &O {
+        &self.object
+    }
+
+    pub fn reason(&self) -> RejectReason {
+        self.reason
+    }
+}
+
+/// The objects the peer sent us, sorted into the ones that can be added to the store and the ones
+/// that must not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received<O> {
+    accepted: Vec<O>,
+    rejected: Vec<Rejected<O>>,
+}
+
+impl<O> Default for Received<O> {
+    fn default() -> Self {
+        Self {
+            accepted: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+}
+
+impl<O> Received<O> {
+    /// Checks the objects in `provided` against the items we asked for. Objects are only accepted
+    /// if they are self-consistent, if we requested them, and only the first time they are sent.
+    pub fn check<I>(provided: &[O], requested: &BTreeSet<I>) -> Self
+    where
+        I: Item,
+        O: Object<I>,
+    {
+        let mut received = Self::default();
+        let mut seen = BTreeSet::new();
+
+        for obj in provided {
+            let item = obj.to_item();
+
+            let reason = if !requested.contains(&item) {
+                Some(RejectReason::NotRequested)
+            } else if !obj.validate_self_consistency() {
+                Some(RejectReason::Inconsistent)
+            } else if !seen.insert(item) {
+                Some(RejectReason::Duplicate)
+            } else {
+                None
+            };
+
+            match reason {
+                None => received.accepted.push(obj.clone()),
+                Some(reason) => received.rejected.push(Rejected {
+                    object: obj.clone(),
+                    reason,
+                }),
+            }
+        }
+
+        received
+    }
+
+    /// The objects that passed validation and can be added to the store.
+    pub fn accepted(&self) -> &[O] {
+        &self.accepted
+    }
+
+    /// The objects that failed validation, together with the reason.
+    pub fn rejected(&self) -> &[Rejected<O>] {
+        &self.rejected
+    }
+
+    pub fn into_accepted(self) -> Vec<O> {
+        self.accepted
+    }
+
+    pub fn into_parts(self) -> (Vec<O>, Vec<Rejected<O>>) {
+        (self.accepted, self.rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{collections::BTreeSet, vec};
+
+    use super::*;
+
+    #[test]
+    fn rejects_bad_objects() {
+        let requested = BTreeSet::from_iter([1u64, 2, 3, 4]);
+        let provided = [(1u64, true), (2, false), (5, true), (3, true), (3, true)];
+
+        let received = Received::check(&provided, &requested);
+        let (accepted, rejected) = received.into_parts();
+
+        assert_eq!(accepted, vec![(1, true), (3, true)]);
+        assert_eq!(
+            rejected,
+            vec![
+                Rejected {
+                    object: (2, false),
+                    reason: RejectReason::Inconsistent
+                },
+                Rejected {
+                    object: (5, true),
+                    reason: RejectReason::NotRequested
+                },
+                Rejected {
+                    object: (3, true),
+                    reason: RejectReason::Duplicate
+                },
+            ]
+        );
+    }
+}
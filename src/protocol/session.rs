@@ -0,0 +1,1236 @@
+extern crate alloc;
+use alloc::{collections::BTreeSet, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{range::Range, Node, Object, ObjectStore};
+
+use super::{
+    first_message, first_message_for_range, respond_to_message_with, validate, AdaptiveTuning,
+    Direction, Features, FixedTuning, Message, MessageLimits, Observations, PartSizes,
+    ProtocolMonoid, Received, Rejected, SessionBudget, SessionError, ShortIdKey, SplitStrategy,
+    SyncStats,
+};
+
+/// The number of rounds after which we give up on a session, unless configured otherwise.
+/// Even with binary splits this allows for ranges with far more items than fit into memory.
+pub const DEFAULT_MAX_ROUNDS: usize = 128;
+
+/// Which side of the session we are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// Sends the first message.
+    Initiator,
+    /// Waits for the first message of the peer.
+    Responder,
+}
+
+/// The result of handling a message from the peer.
+#[derive(Debug, Clone)]
+pub struct Outcome<M, O>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    reply: Option<Message<M, O>>,
+    received: Received<O>,
+    unavailable: Vec<M::Item>,
+}
+
+impl<M, O> Outcome<M, O>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    /// The message that needs to be sent to the peer, if any.
+    pub fn reply(&self) -> Option<&Message<M, O>> {
+        self.reply.as_ref()
+    }
+
+    /// The objects the peer sent us that passed validation and can be added to the store.
+    pub fn received(&self) -> &[O] {
+        self.received.accepted()
+    }
+
+    /// The objects the peer sent us that failed validation. These must not be added to the store.
+    pub fn rejected(&self) -> &[Rejected<O>] {
+        self.received.rejected()
+    }
+
+    /// Items we requested, but that the peer doesn't have.
+    pub fn unavailable(&self) -> &[M::Item] {
+        &self.unavailable
+    }
+
+    pub fn into_parts(self) -> (Option<Message<M, O>>, Received<O>) {
+        (self.reply, self.received)
+    }
+}
+
+/// Keeps the state of one side of a sync session, so callers don't have to drive
+/// [`first_message`] and [`respond_to_message`] themselves.
+///
+/// The session doesn't own the tree or the object store, they are passed to every call. That way
+/// the caller can add received objects while the session is still running.
+///
+/// Replies that exceed the configured [`MessageLimits`] are split up and sent over the following
+/// rounds; the session only ends once neither side has anything left to send.
+#[derive(Debug, Clone)]
+pub struct Session<M, O, Sp>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    role: Role,
+    threshold: usize,
+    split: Sp,
+    budget: SessionBudget,
+    limits: MessageLimits,
+    range: Range<M::Item>,
+    direction: Direction,
+    short_id_len: u8,
+    short_id_key: Option<ShortIdKey>,
+    sketch_cells: u32,
+    adaptive: bool,
+    part_sizes: bool,
+    observations: Observations,
+    round: usize,
+    started: bool,
+    finished: bool,
+    requested: BTreeSet<M::Item>,
+    advertised: BTreeSet<M::Item>,
+    backlog: Message<M, O>,
+    stats: SyncStats,
+}
+
+impl<M, O, Sp> Session<M, O, Sp>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    Sp: SplitStrategy<M>,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    pub fn new(role: Role, threshold: usize, split: Sp) -> Self {
+        Self {
+            role,
+            threshold,
+            split,
+            budget: SessionBudget::default(),
+            limits: MessageLimits::default(),
+            range: Range::full(),
+            direction: Direction::Both,
+            short_id_len: 0,
+            short_id_key: None,
+            sketch_cells: 0,
+            adaptive: false,
+            part_sizes: false,
+            observations: Observations::default(),
+            round: 0,
+            started: false,
+            finished: false,
+            requested: BTreeSet::new(),
+            advertised: BTreeSet::new(),
+            backlog: Message::new(Vec::new(), Vec::new(), Vec::new(), Vec::new()),
+            stats: SyncStats::default(),
+        }
+    }
+
+    /// Sets the number of rounds after which the session is aborted. This is the
+    /// [`SessionBudget::max_rounds`] of the budget, so it only sticks if it comes after
+    /// [`Session::with_budget`].
+    pub fn with_max_rounds(mut self, max_rounds: usize) -> Self {
+        self.budget.max_rounds = max_rounds;
+        self
+    }
+
+    /// Limits how much work the peer can make us do, see [`SessionBudget`]. This replaces the
+    /// whole budget, including a number of rounds set with [`Session::with_max_rounds`] before.
+    pub fn with_budget(mut self, budget: SessionBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn budget(&self) -> &SessionBudget {
+        &self.budget
+    }
+
+    /// Sets the limits for the messages we send. Parts of a reply that don't fit are deferred to
+    /// later messages.
+    pub fn with_limits(mut self, limits: MessageLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Restricts the session to the items in `range`. The peer has to be configured with the same
+    /// range; if it sends anything reaching outside of it, the session fails.
+    pub fn with_range(mut self, range: Range<M::Item>) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// The limits for the messages we send, including the format they are encoded in.
+    pub fn limits(&self) -> &MessageLimits {
+        &self.limits
+    }
+
+    /// The range of items that is synced.
+    pub fn range(&self) -> &Range<M::Item> {
+        &self.range
+    }
+
+    /// Sets which way items flow, from our point of view. The initiator picks the direction, the
+    /// responder follows what the first message asks for, as long as this direction
+    /// [allows](Direction::allows) it. So a responder with [`Direction::Push`] refuses peers that
+    /// want to hand out items.
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Which way items flow, from our point of view.
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Lists the items of small ranges by short IDs of `len` bytes, see [`super::short_id`]. Like
+    /// the direction, this is picked by the initiator. Peers refuse IDs shorter than
+    /// [`super::short_id::MIN_SHORT_ID_LEN`] bytes.
+    pub fn with_short_ids(mut self, len: u8) -> Self {
+        self.short_id_len = len;
+        self
+    }
+
+    /// Sets the key of the short IDs instead of picking a random one when the session starts,
+    /// see [`super::short_id`]. Only the initiator picks the key.
+    pub fn with_short_id_key(mut self, key: ShortIdKey) -> Self {
+        self.short_id_key = Some(key);
+        self
+    }
+
+    pub(crate) fn set_short_id_key(&mut self, key: ShortIdKey) {
+        self.short_id_key = Some(key);
+    }
+
+    /// The key of the short IDs, once the session started.
+    pub fn short_id_key(&self) -> Option<ShortIdKey> {
+        self.short_id_key
+    }
+
+    /// Lets the peer send sketches with `cells` cells instead of splitting ranges, see
+    /// [`super::sketch`]. Like the direction, this is picked by the initiator. Peers refuse more
+    /// than [`super::sketch::MAX_CELLS`] cells.
+    pub fn with_sketches(mut self, cells: u32) -> Self {
+        self.sketch_cells = cells;
+        self
+    }
+
+    /// Adapts the threshold and the split of each range to the differences observed so far,
+    /// instead of using the ones passed to [`Session::new`] for every range. See
+    /// [`super::tuning`].
+    pub fn with_adaptive_tuning(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Measures the encoded size of each part of the messages we send, in
+    /// [`SyncStats::bytes_sent`]. This encodes every part once more, so it is off by default.
+    pub fn with_part_sizes(mut self, part_sizes: bool) -> Self {
+        self.part_sizes = part_sizes;
+        self
+    }
+
+    /// What we learned about the differences so far. Only collected with adaptive tuning.
+    pub fn observations(&self) -> &Observations {
+        &self.observations
+    }
+
+    /// The features the peer needs to support for this session, to be checked during the
+    /// handshake, see [`super::Hello`].
+    pub fn required_features(&self) -> Features {
+        let mut features = Features::NONE;
+        if !self.limits.is_unlimited() {
+            features = features.union(Features::CHUNKING);
+        }
+        if self.direction != Direction::Both {
+            features = features.union(Features::DIRECTION);
+        }
+        if !self.range.is_full() {
+            features = features.union(Features::RANGE);
+        }
+        if self.short_id_len > 0 {
+            features = features.union(Features::SHORT_IDS);
+        }
+        if self.sketch_cells > 0 {
+            features = features.union(Features::SKETCHES);
+        }
+        features
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The number of messages exchanged so far.
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    pub fn stats(&self) -> &SyncStats {
+        &self.stats
+    }
+
+    /// Items we asked the peer for, but that haven't arrived yet.
+    pub fn requested(&self) -> &BTreeSet<M::Item> {
+        &self.requested
+    }
+
+    /// Whether the session is over. No further messages need to be sent or received.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the first message of the session. Only valid for the initiator.
+    pub fn start<N>(&mut self, root: &N) -> Result<Message<M, O>, SessionError<M>>
+    where
+        N: Node<M>,
+    {
+        if self.role != Role::Initiator {
+            return Err(SessionError::NotInitiator);
+        }
+
+        if self.started {
+            return Err(SessionError::AlreadyStarted);
+        }
+
+        let msg = if self.range.is_full() {
+            first_message(root)?
+        } else {
+            first_message_for_range(root, &self.range)?
+        }
+        .with_direction(self.direction)
+        .with_short_ids(self.short_id_len)
+        .with_sketches(self.sketch_cells);
+
+        // without short IDs, the key is never used
+        let key = self.short_id_key.unwrap_or_else(|| {
+            if self.short_id_len > 0 || self.sketch_cells > 0 {
+                ShortIdKey::random()
+            } else {
+                ShortIdKey::default()
+            }
+        });
+        self.short_id_key = Some(key);
+        let msg = msg.with_short_id_key(key);
+        self.started = true;
+        self.record_sent(&msg);
+
+        Ok(msg)
+    }
+
+    /// Processes a message from the peer. If the returned outcome contains a reply, it needs to
+    /// be sent to the peer.
+    pub fn handle<N, S>(
+        &mut self,
+        root: &N,
+        object_store: &S,
+        msg: &Message<M, O>,
+    ) -> Result<Outcome<M, O>, SessionError<M>>
+    where
+        N: Node<M>,
+        S: ObjectStore<M::Item, O>,
+    {
+        if self.finished {
+            return Err(SessionError::Finished);
+        }
+
+        if self.role == Role::Initiator && !self.started {
+            return Err(SessionError::NotInitiator);
+        }
+
+        match self.role {
+            Role::Responder if !self.started => {
+                let direction = msg.direction().reverse();
+                if !self.direction.allows(direction) {
+                    self.finished = true;
+                    return Err(SessionError::DirectionMismatch);
+                }
+                self.direction = direction;
+                self.short_id_key = Some(msg.short_id_key());
+            }
+            _ if msg.direction() != self.direction.reverse() => {
+                self.finished = true;
+                return Err(SessionError::DirectionMismatch);
+            }
+            _ if Some(msg.short_id_key()) != self.short_id_key => {
+                self.finished = true;
+                return Err(SessionError::ShortIdKeyMismatch);
+            }
+            _ => {}
+        }
+
+        self.started = true;
+        self.round += 1;
+        self.stats.rounds += 1;
+        self.stats.messages_received += 1;
+
+        if msg.is_end() && self.backlog.is_empty() {
+            self.finished = true;
+            return Ok(Outcome {
+                reply: None,
+                received: Received::default(),
+                unavailable: Vec::new(),
+            });
+        }
+
+        // the peer can keep sending mismatching fingerprints forever, so we need to cut it off
+        // at some point.
+        if self.round > self.budget.max_rounds {
+            self.finished = true;
+            return Err(SessionError::TooManyRounds(self.budget.max_rounds));
+        }
+
+        // we only hand out objects we listed to the peer
+        if let Err(e) = validate::check_wants(msg, &self.advertised) {
+            self.finished = true;
+            return Err(SessionError::RespondError(e.into()));
+        }
+
+        let result = if self.adaptive {
+            let mut tuning =
+                AdaptiveTuning::new(self.threshold, &self.split, &mut self.observations);
+            respond_to_message_with(
+                root,
+                object_store,
+                msg,
+                &self.requested,
+                &self.range,
+                &mut tuning,
+                self.round,
+                &self.budget,
+                &mut self.stats,
+            )
+        } else {
+            respond_to_message_with(
+                root,
+                object_store,
+                msg,
+                &self.requested,
+                &self.range,
+                &mut FixedTuning::new(self.threshold, &self.split),
+                self.round,
+                &self.budget,
+                &mut self.stats,
+            )
+        };
+        let (reply, received) = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.finished = true;
+                return Err(e.into());
+            }
+        };
+
+        for obj in received.accepted() {
+            self.requested.remove(&obj.to_item());
+        }
+        self.stats.objects_received += received.accepted().len();
+        self.stats.objects_rejected += received.rejected().len();
+
+        // the peer can't give us these, so there is no point in waiting for them.
+        let unavailable: Vec<_> = msg
+            .not_available()
+            .iter()
+            .filter(|item| self.requested.remove(item))
+            .cloned()
+            .collect();
+        self.stats.objects_unavailable += unavailable.len();
+
+        // send what fits now and keep the rest for the next rounds
+        self.backlog.append(reply);
+        let chunk = self.backlog.take_chunk(&self.limits);
+
+        self.record_sent(&chunk);
+        if chunk.is_end() && !msg.has_more() {
+            self.finished = true;
+        }
+
+        Ok(Outcome {
+            reply: Some(chunk),
+            received,
+            unavailable,
+        })
+    }
+
+    fn record_sent(&mut self, msg: &Message<M, O>) {
+        self.round += 1;
+        self.stats.rounds += 1;
+        self.stats.messages_sent += 1;
+        self.stats.fingerprints_sent += msg.fingerprints().len();
+        self.stats.item_sets_sent += msg.item_sets().len() + msg.short_item_sets().len();
+        self.stats.items_sent += msg
+            .item_sets()
+            .iter()
+            .map(|set| set.items().len())
+            .sum::<usize>();
+        self.stats.items_sent += msg
+            .short_item_sets()
+            .iter()
+            .map(|set| set.ids().len() + set.items().len())
+            .sum::<usize>();
+        self.stats.items_requested += msg.wants().len();
+        self.stats.objects_sent += msg.provide().len();
+        if self.part_sizes {
+            self.stats.bytes_sent += PartSizes::of(self.limits.format, msg);
+        }
+        self.requested.extend(msg.wants().iter().cloned());
+        for item_set in msg.item_sets() {
+            self.advertised.extend(item_set.items().iter().cloned());
+        }
+        for short_item_set in msg.short_item_sets() {
+            self.advertised
+                .extend(short_item_set.items().iter().cloned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+    use proptest::{prelude::prop, prop_assert, prop_assert_eq, proptest};
+    use serde::Serialize;
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{
+            encode_message, first_message, split::UniformSplit, Direction, Format, ItemSet,
+            Message, MessageLimits, PartSizes, RejectReason, Resource, RespondError, SessionBudget,
+            SessionError, ShortIdKey, SplitStrategy, SyncStats, ValidationError,
+        },
+        range::Range,
+        tree::mem_rc::Node,
+    };
+
+    use super::{Role, Session};
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+    type TestMessage = Message<TestMonoid, TestObject>;
+    type TestNode = Node<TestMonoid>;
+
+    fn encoded_len<T: Serialize>(value: &T) -> usize {
+        Format::default().encode(value).unwrap().len()
+    }
+
+    fn setup(items: &[u64]) -> (TestNode, BTreeMap<u64, TestObject>) {
+        let mut root = TestNode::nil();
+        let mut object_store = BTreeMap::new();
+        for item in items {
+            // the tree doesn't deduplicate
+            if object_store.insert(*item, (*item, true)).is_none() {
+                root = root.insert(*item);
+            }
+        }
+
+        (root, object_store)
+    }
+
+    /// A session together with the tree and objects it syncs.
+    struct Peer<Sp: SplitStrategy<TestMonoid>> {
+        session: Session<TestMonoid, TestObject, Sp>,
+        root: TestNode,
+        store: BTreeMap<u64, TestObject>,
+    }
+
+    impl<Sp: SplitStrategy<TestMonoid>> Peer<Sp> {
+        fn new(session: Session<TestMonoid, TestObject, Sp>, items: &[u64]) -> Self {
+            let (root, store) = setup(items);
+            Self {
+                session,
+                root,
+                store,
+            }
+        }
+
+        fn items(&self) -> Vec<u64> {
+            self.store.keys().copied().collect()
+        }
+
+        /// Handles `msg` and keeps the objects it brings. Returns the reply.
+        fn handle(&mut self, msg: &TestMessage) -> Option<TestMessage> {
+            let outcome = self.session.handle(&self.root, &self.store, msg).unwrap();
+            let (reply, received) = outcome.into_parts();
+            assert!(received.rejected().is_empty());
+            for obj in received.into_accepted() {
+                self.root = self.root.insert(obj.0);
+                self.store.insert(obj.0, obj);
+            }
+            reply
+        }
+    }
+
+    /// Runs the session between `a`, the initiator, and `b` until both are finished. Returns the
+    /// messages sent by `a` and by `b`.
+    fn drive<Sp: SplitStrategy<TestMonoid>>(
+        a: &mut Peer<Sp>,
+        b: &mut Peer<Sp>,
+    ) -> (Vec<TestMessage>, Vec<TestMessage>) {
+        let mut sent_a = vec![a.session.start(&a.root).unwrap()];
+        let mut sent_b = vec![];
+
+        while !a.session.is_finished() || !b.session.is_finished() {
+            let (receiver, msg, sent) = if a.session.round() > b.session.round() {
+                (&mut *b, sent_a.last().unwrap(), &mut sent_b)
+            } else {
+                (&mut *a, sent_b.last().unwrap(), &mut sent_a)
+            };
+
+            match receiver.handle(msg) {
+                Some(reply) => sent.push(reply),
+                None => break,
+            }
+        }
+
+        (sent_a, sent_b)
+    }
+
+    proptest! {
+        #[test]
+        fn session_correctness(items_a in prop::collection::vec(1..1000u64, 0..100usize), items_b in prop::collection::vec(1..1000u64, 0..100usize)) {
+            let mut a = Peer::new(Session::new(Role::Initiator, 3, UniformSplit::<2>).with_part_sizes(true), &items_a);
+            let mut b = Peer::new(Session::new(Role::Responder, 3, UniformSplit::<2>), &items_b);
+            drive(&mut a, &mut b);
+
+            prop_assert_eq!(a.items(), b.items());
+            prop_assert_eq!(a.root.monoid(), b.root.monoid());
+            prop_assert_eq!(a.session.requested().len(), 0);
+            prop_assert_eq!(b.session.requested().len(), 0);
+            prop_assert_eq!(a.session.stats().rounds, b.session.stats().rounds);
+
+            // everything one side sent was looked at by the other
+            let (stats_a, stats_b) = (a.session.stats(), b.session.stats());
+            prop_assert_eq!(stats_a.fingerprints_sent, stats_b.fingerprints_compared);
+            prop_assert_eq!(stats_b.fingerprints_sent, stats_a.fingerprints_compared);
+            prop_assert!(stats_a.fingerprint_mismatches <= stats_a.fingerprints_compared);
+            prop_assert_eq!(stats_a.objects_sent, stats_b.objects_received);
+            prop_assert_eq!(stats_b.objects_sent, stats_a.objects_received);
+            prop_assert!(stats_a.bytes_sent.total() > 0);
+            prop_assert_eq!(stats_b.bytes_sent, PartSizes::default());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn range_restricted_sync(items_a in prop::collection::vec(1..1000u64, 0..100usize), items_b in prop::collection::vec(1..1000u64, 0..100usize), from in 0..1000u64, to in 0..1000u64) {
+            let range = Range(from, to);
+            let session = |role| Session::new(role, 3, UniformSplit::<2>).with_range(range);
+            let mut a = Peer::new(session(Role::Initiator), &items_a);
+            let mut b = Peer::new(session(Role::Responder), &items_b);
+            let (expected_a, expected_b) = (a.items(), b.items());
+            drive(&mut a, &mut b);
+
+            // inside the range both sides have everything, outside nothing changed
+            let in_range = |items: &[u64]| -> Vec<u64> {
+                items.iter().copied().filter(|item| range.contains(item)).collect()
+            };
+            let out_of_range = |items: &[u64]| -> Vec<u64> {
+                items.iter().copied().filter(|item| !range.contains(item)).collect()
+            };
+            prop_assert_eq!(in_range(&a.items()), in_range(&b.items()));
+            prop_assert_eq!(out_of_range(&a.items()), out_of_range(&expected_a));
+            prop_assert_eq!(out_of_range(&b.items()), out_of_range(&expected_b));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn short_id_sync(items_a in prop::collection::vec(1..1000u64, 0..100usize), items_b in prop::collection::vec(1..1000u64, 0..100usize)) {
+            // a large threshold, so most items are compared by their short ids
+            let mut a = Peer::new(Session::new(Role::Initiator, 16, UniformSplit::<2>).with_short_ids(4), &items_a);
+            let mut b = Peer::new(Session::new(Role::Responder, 16, UniformSplit::<2>), &items_b);
+            let (sent_a, sent_b) = drive(&mut a, &mut b);
+
+            for msg in sent_a.iter().chain(&sent_b) {
+                prop_assert_eq!(msg.short_id_len(), 4);
+            }
+            for msg in sent_a.iter().skip(1).chain(&sent_b) {
+                prop_assert!(msg.item_sets().iter().all(|set| !set.want_response()));
+            }
+
+            prop_assert_eq!(a.items(), b.items());
+            prop_assert_eq!(a.root.monoid(), b.root.monoid());
+            prop_assert_eq!(a.session.requested().len(), 0);
+            prop_assert_eq!(b.session.requested().len(), 0);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn adaptive_sync(items_a in prop::collection::vec(1..10000u64, 0..300usize), items_b in prop::collection::vec(1..10000u64, 0..300usize)) {
+            // only one side adapts, which must not confuse the other
+            let mut a = Peer::new(Session::new(Role::Initiator, 3, UniformSplit::<2>).with_adaptive_tuning(true), &items_a);
+            let mut b = Peer::new(Session::new(Role::Responder, 3, UniformSplit::<2>), &items_b);
+            drive(&mut a, &mut b);
+
+            prop_assert_eq!(a.items(), b.items());
+            prop_assert_eq!(a.root.monoid(), b.root.monoid());
+            prop_assert_eq!(b.session.observations(), &Default::default());
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_outside_of_sync_range() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4, 50, 60, 70]);
+        let (root_b, _) = setup(&[1, 2, 3, 50, 61, 71]);
+
+        let mut session =
+            Session::new(Role::Responder, 3, UniformSplit::<2>).with_range(Range(0, 10));
+
+        // the peer tries to sync everything
+        let msg: Message<TestMonoid, TestObject> = first_message(&root_b).unwrap();
+        let err = session.handle(&root_a, &store_a, &msg).unwrap_err();
+        assert!(matches!(
+            err,
+            SessionError::RespondError(RespondError::OutOfRange(_))
+        ));
+
+        // items outside the range are never listed, so the peer can't want them
+        let mut session =
+            Session::new(Role::Responder, 3, UniformSplit::<2>).with_range(Range(0, 10));
+        let msg: Message<TestMonoid, TestObject> = Message::new(vec![], vec![], vec![50], vec![]);
+        let err = session.handle(&root_a, &store_a, &msg).unwrap_err();
+        assert!(matches!(
+            err,
+            SessionError::RespondError(RespondError::Invalid(ValidationError::UnknownWant(50)))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_wants() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4]);
+        let (root_b, store_b) = setup(&[1, 2, 3]);
+
+        let mut session_a = Session::new(Role::Initiator, 3, UniformSplit::<2>);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>);
+
+        // b learns about 4 from the item set a sends it
+        let msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+        let reply = session_b.handle(&root_b, &store_b, &msg).unwrap();
+        let reply = reply.reply().unwrap();
+        let reply = session_a.handle(&root_a, &store_a, reply).unwrap();
+        let reply = reply.reply().unwrap();
+        assert!(reply.item_sets().iter().any(|set| set.items().contains(&4)));
+
+        // a only hands out what it listed
+        let mut greedy = session_a.clone();
+        let msg = Message::new(vec![], vec![], vec![4], vec![]);
+        assert!(greedy.handle(&root_a, &store_a, &msg).is_ok());
+        let msg = Message::new(vec![], vec![], vec![4, 5], vec![]);
+        let err = session_a.handle(&root_a, &store_a, &msg).unwrap_err();
+        assert!(matches!(
+            err,
+            SessionError::RespondError(RespondError::Invalid(ValidationError::UnknownWant(5)))
+        ));
+        assert!(session_a.is_finished());
+    }
+
+    #[test]
+    fn rejects_large_sketches() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4]);
+        let (root_b, _) = setup(&[1, 2, 3]);
+
+        let msg: Message<TestMonoid, TestObject> = first_message(&root_b).unwrap();
+        let msg = msg.with_sketches(u32::MAX);
+        let mut session = Session::new(Role::Responder, 3, UniformSplit::<2>);
+        let err = session.handle(&root_a, &store_a, &msg).unwrap_err();
+        assert!(matches!(
+            err,
+            SessionError::RespondError(RespondError::SketchTooLarge(_))
+        ));
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn rejects_short_short_ids() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4]);
+        let (root_b, _) = setup(&[1, 2, 3]);
+
+        let msg: Message<TestMonoid, TestObject> = first_message(&root_b).unwrap();
+        for (len, accepted) in [(1, false), (3, false), (4, true), (8, true)] {
+            let msg = msg.clone().with_short_ids(len);
+            let mut session = Session::new(Role::Responder, 3, UniformSplit::<2>);
+            let result = session.handle(&root_a, &store_a, &msg);
+            if accepted {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(SessionError::RespondError(RespondError::ShortIdTooShort(l))) if l == len
+                ));
+            }
+        }
+    }
+
+    /// Syncs `items_a` with `items_b`, where b only does as much work as `budget` allows.
+    fn sync_with_budget(
+        items_a: &[u64],
+        items_b: &[u64],
+        budget: SessionBudget,
+    ) -> Result<SyncStats, SessionError<TestMonoid>> {
+        let (root_a, store_a) = setup(items_a);
+        let (root_b, store_b) = setup(items_b);
+
+        let mut session_a = Session::new(Role::Initiator, 3, UniformSplit::<2>);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>).with_budget(budget);
+
+        let mut msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+        loop {
+            let outcome = session_b.handle(&root_b, &store_b, &msg).inspect_err(|_| {
+                assert!(session_b.is_finished());
+            })?;
+            let Some(reply) = outcome.reply() else { break };
+
+            let outcome = session_a.handle(&root_a, &store_a, reply).unwrap();
+            let Some(reply) = outcome.reply() else { break };
+            msg = reply.clone();
+        }
+
+        Ok(session_b.stats().clone())
+    }
+
+    fn over_budget(result: Result<SyncStats, SessionError<TestMonoid>>) -> Option<Resource> {
+        match result {
+            Err(SessionError::RespondError(RespondError::OverBudget { resource, .. })) => {
+                Some(resource)
+            }
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn enforces_budgets() {
+        let items_a: Vec<u64> = (0..100).collect();
+        let items_b: Vec<u64> = (0..200).filter(|i| i % 7 != 0).collect();
+
+        // what the sync costs b without any limits
+        let spent = sync_with_budget(&items_a, &items_b, SessionBudget::unlimited()).unwrap();
+        assert!(spent.items_enumerated > 0);
+        assert!(spent.objects_looked_up > 0);
+        assert!(spent.decodes > 0);
+
+        let exact = SessionBudget {
+            max_ranges: usize::MAX,
+            max_items: spent.items_enumerated,
+            max_objects: spent.objects_looked_up,
+            max_rounds: spent.rounds,
+            max_decodes: spent.decodes,
+        };
+        assert!(sync_with_budget(&items_a, &items_b, exact).is_ok());
+
+        let cases = [
+            (
+                Resource::Ranges,
+                SessionBudget {
+                    max_ranges: 1,
+                    ..exact
+                },
+            ),
+            (
+                Resource::Items,
+                SessionBudget {
+                    max_items: spent.items_enumerated - 1,
+                    ..exact
+                },
+            ),
+            (
+                Resource::Objects,
+                SessionBudget {
+                    max_objects: spent.objects_looked_up - 1,
+                    ..exact
+                },
+            ),
+            (
+                Resource::Decodes,
+                SessionBudget {
+                    max_decodes: spent.decodes - 1,
+                    ..exact
+                },
+            ),
+        ];
+        for (resource, budget) in cases {
+            let result = sync_with_budget(&items_a, &items_b, budget);
+            assert_eq!(over_budget(result), Some(resource));
+        }
+
+        let budget = SessionBudget {
+            max_rounds: 2,
+            ..exact
+        };
+        assert!(matches!(
+            sync_with_budget(&items_a, &items_b, budget),
+            Err(SessionError::TooManyRounds(2))
+        ));
+    }
+
+    #[test]
+    fn stops_before_listing_too_many_items() {
+        let (root, store) = setup(&(0..1000).collect::<Vec<_>>());
+        let budget = SessionBudget {
+            max_items: 10,
+            ..Default::default()
+        };
+        let mut session = Session::new(Role::Responder, 3, UniformSplit::<2>).with_budget(budget);
+
+        // the peer asks for all our items at once
+        let msg: Message<TestMonoid, TestObject> = Message::new(
+            vec![],
+            vec![ItemSet::new(Range(0, 0), vec![], true)],
+            vec![],
+            vec![],
+        );
+        let err = session.handle(&root, &store, &msg).unwrap_err();
+        assert!(matches!(
+            err,
+            SessionError::RespondError(RespondError::OverBudget {
+                resource: Resource::Items,
+                limit: 10
+            })
+        ));
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn rejects_direction_change() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4]);
+        let (root_b, store_b) = setup(&[1, 2, 3]);
+
+        let mut session_a =
+            Session::new(Role::Initiator, 3, UniformSplit::<2>).with_direction(Direction::Pull);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>);
+
+        let msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+        assert_eq!(msg.direction(), Direction::Pull);
+
+        let outcome = session_b.handle(&root_b, &store_b, &msg).unwrap();
+        assert_eq!(session_b.direction(), Direction::Push);
+        let reply = outcome.reply().unwrap().clone();
+        assert_eq!(reply.direction(), Direction::Push);
+
+        // the responder suddenly wants alice's items, too
+        let reply = reply.with_direction(Direction::Both);
+        assert!(matches!(
+            session_a.handle(&root_a, &store_a, &reply),
+            Err(SessionError::DirectionMismatch)
+        ));
+        assert!(session_a.is_finished());
+    }
+
+    #[test]
+    fn rejects_short_id_key_change() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4]);
+        let (root_b, store_b) = setup(&[1, 2, 3]);
+
+        let mut session_a = Session::new(Role::Initiator, 3, UniformSplit::<2>).with_short_ids(4);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>);
+
+        let msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+        let key = session_a.short_id_key().unwrap();
+        assert_eq!(msg.short_id_key(), key);
+        assert_ne!(key, ShortIdKey::default());
+
+        let outcome = session_b.handle(&root_b, &store_b, &msg).unwrap();
+        assert_eq!(session_b.short_id_key(), Some(key));
+        let reply = outcome.reply().unwrap().clone();
+        assert_eq!(reply.short_id_key(), key);
+
+        // the responder picks a key of its own, e.g. to make our items collide
+        let reply = reply.with_short_id_key(ShortIdKey(1, 2));
+        assert!(matches!(
+            session_a.handle(&root_a, &store_a, &reply),
+            Err(SessionError::ShortIdKeyMismatch)
+        ));
+        assert!(session_a.is_finished());
+    }
+
+    #[test]
+    fn responder_sticks_to_first_message() {
+        let (root_a, _) = setup(&[1, 2, 3, 4]);
+        let (root_b, store_b) = setup(&[1, 2, 3]);
+
+        let mut session_a = Session::new(Role::Initiator, 3, UniformSplit::<2>)
+            .with_direction(Direction::Pull)
+            .with_short_ids(4);
+        let msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+
+        // the initiator asks for another direction in a later message
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>);
+        session_b.handle(&root_b, &store_b, &msg).unwrap();
+        let changed = msg.clone().with_direction(Direction::Both);
+        assert!(matches!(
+            session_b.handle(&root_b, &store_b, &changed),
+            Err(SessionError::DirectionMismatch)
+        ));
+        assert_eq!(session_b.direction(), Direction::Push);
+        assert!(session_b.is_finished());
+
+        // or for another short ID key
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>);
+        session_b.handle(&root_b, &store_b, &msg).unwrap();
+        let changed = msg.clone().with_short_id_key(ShortIdKey(1, 2));
+        assert!(matches!(
+            session_b.handle(&root_b, &store_b, &changed),
+            Err(SessionError::ShortIdKeyMismatch)
+        ));
+        assert_eq!(session_b.short_id_key(), Some(msg.short_id_key()));
+        assert!(session_b.is_finished());
+    }
+
+    #[test]
+    fn read_only_responder_refuses_pushes() {
+        let (root_a, _) = setup(&[1, 2, 3, 4]);
+        let (root_b, store_b) = setup(&[1, 2, 3]);
+        let mirror =
+            || Session::new(Role::Responder, 3, UniformSplit::<2>).with_direction(Direction::Push);
+
+        for (direction, allowed) in [
+            (Direction::Pull, true),
+            (Direction::Both, false),
+            (Direction::Push, false),
+        ] {
+            let mut session_a =
+                Session::new(Role::Initiator, 3, UniformSplit::<2>).with_direction(direction);
+            let msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+
+            let mut session_b = mirror();
+            let outcome = session_b.handle(&root_b, &store_b, &msg);
+            if allowed {
+                assert!(outcome.is_ok());
+                assert_eq!(session_b.direction(), Direction::Push);
+            } else {
+                assert!(matches!(outcome, Err(SessionError::DirectionMismatch)));
+                assert!(session_b.is_finished());
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_large_replies() {
+        let items_a: Vec<u64> = (0..300).map(|i| i * 2).collect();
+        let items_b: Vec<u64> = (0..300).map(|i| i * 3).collect();
+        let (mut root_a, mut store_a) = setup(&items_a);
+        let (mut root_b, mut store_b) = setup(&items_b);
+
+        let limits = MessageLimits {
+            max_bytes: 256,
+            max_objects: 4,
+            ..Default::default()
+        };
+        let mut session_a = Session::new(Role::Initiator, 3, UniformSplit::<4>)
+            .with_limits(limits)
+            .with_max_rounds(10_000);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<4>)
+            .with_limits(limits)
+            .with_max_rounds(10_000);
+
+        let mut msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+        let mut chunked = 0;
+        while !session_a.is_finished() || !session_b.is_finished() {
+            let (receiver, root, store, session) = if session_a.round() > session_b.round() {
+                (&mut session_b, &mut root_b, &mut store_b, "b")
+            } else {
+                (&mut session_a, &mut root_a, &mut store_a, "a")
+            };
+
+            let (reply, received) = receiver.handle(root, store, &msg).unwrap().into_parts();
+            assert!(received.rejected().is_empty(), "{session}");
+            for obj in received.into_accepted() {
+                *root = root.insert(obj.0);
+                store.insert(obj.0, obj);
+            }
+
+            match reply {
+                Some(reply) => msg = reply,
+                None => break,
+            }
+
+            assert!(encode_message(limits.format, &msg).unwrap().len() <= limits.max_bytes);
+            assert!(msg.provide().len() <= limits.max_objects);
+            if msg.has_more() {
+                chunked += 1;
+            }
+        }
+
+        assert!(chunked > 0);
+        assert!(session_a.is_finished() && session_b.is_finished());
+        let keys_a: Vec<_> = store_a.keys().collect();
+        let keys_b: Vec<_> = store_b.keys().collect();
+        assert_eq!(keys_a, keys_b);
+        assert_eq!(root_a.monoid(), root_b.monoid());
+        assert!(session_a.requested().is_empty());
+        assert!(session_b.requested().is_empty());
+    }
+
+    #[test]
+    fn terminates_with_misbehaving_peer() {
+        let (root_a, store_a) = setup(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let (root_b, _) = setup(&[10, 20, 30, 40, 50, 60, 70, 80]);
+
+        let mut session = Session::new(Role::Responder, 3, UniformSplit::<2>).with_max_rounds(10);
+
+        // a peer that keeps restarting the sync will never send an end message
+        let msg: Message<TestMonoid, TestObject> = first_message(&root_b).unwrap();
+        let mut handled = 0;
+        let err = loop {
+            match session.handle(&root_a, &store_a, &msg) {
+                Ok(_) => handled += 1,
+                Err(err) => break err,
+            }
+        };
+
+        assert!(matches!(err, SessionError::TooManyRounds(10)));
+        assert_eq!(handled, 5);
+        assert!(session.is_finished());
+        assert!(matches!(
+            session.handle(&root_a, &store_a, &msg),
+            Err(SessionError::Finished)
+        ));
+        assert_eq!(session.stats().messages_received, 6);
+        assert_eq!(session.stats().messages_sent, 5);
+    }
+
+    #[test]
+    fn counts_what_is_sent() {
+        let (root_a, store_a) = setup(&(0..100).collect::<Vec<_>>());
+        let (root_b, store_b) = setup(&(0..100).filter(|i| i % 7 != 0).collect::<Vec<_>>());
+
+        let mut session_a =
+            Session::new(Role::Initiator, 3, UniformSplit::<2>).with_part_sizes(true);
+        let mut session_b =
+            Session::new(Role::Responder, 3, UniformSplit::<2>).with_part_sizes(true);
+
+        let mut sent_a: Vec<Message<TestMonoid, TestObject>> =
+            vec![session_a.start(&root_a).unwrap()];
+        let mut sent_b = vec![];
+        loop {
+            let outcome = session_b
+                .handle(&root_b, &store_b, sent_a.last().unwrap())
+                .unwrap();
+            let Some(reply) = outcome.reply() else { break };
+            sent_b.push(reply.clone());
+
+            let outcome = session_a.handle(&root_a, &store_a, reply).unwrap();
+            let Some(reply) = outcome.reply() else { break };
+            sent_a.push(reply.clone());
+        }
+
+        for (session, sent) in [(&session_a, &sent_a), (&session_b, &sent_b)] {
+            let stats = session.stats();
+            let sum = |f: fn(&Message<TestMonoid, TestObject>) -> usize| {
+                sent.iter().map(f).sum::<usize>()
+            };
+
+            assert_eq!(stats.messages_sent, sent.len());
+            assert_eq!(stats.fingerprints_sent, sum(|msg| msg.fingerprints().len()));
+            assert_eq!(stats.item_sets_sent, sum(|msg| msg.item_sets().len()));
+            assert_eq!(
+                stats.items_sent,
+                sum(|msg| msg.item_sets().iter().map(|set| set.items().len()).sum())
+            );
+            assert_eq!(stats.objects_sent, sum(|msg| msg.provide().len()));
+            assert_eq!(
+                stats.bytes_sent.fingerprints,
+                sum(|msg| encoded_len(&msg.fps))
+            );
+            assert_eq!(
+                stats.bytes_sent.item_sets,
+                sum(|msg| encoded_len(&msg.item_sets))
+            );
+            assert_eq!(
+                stats.bytes_sent.provide,
+                sum(|msg| encoded_len(&msg.provide))
+            );
+            assert!(stats.bytes_sent.total() < sum(encoded_len));
+        }
+
+        let (stats_a, stats_b) = (session_a.stats(), session_b.stats());
+        assert_eq!(stats_a.fingerprints_compared, stats_b.fingerprints_sent);
+        assert_eq!(stats_b.fingerprints_compared, stats_a.fingerprints_sent);
+        assert!(stats_b.fingerprint_mismatches > 0);
+        assert!(stats_a.fingerprint_mismatches < stats_a.fingerprints_compared);
+        assert_eq!(stats_a.objects_received, 0);
+        assert_eq!(stats_b.objects_received, 15);
+    }
+
+    #[test]
+    fn rejects_unrequested_objects() {
+        let (root, store) = setup(&[1, 2, 3]);
+        let mut session = Session::new(Role::Responder, 3, UniformSplit::<2>);
+
+        let msg: Message<TestMonoid, TestObject> =
+            Message::new(vec![], vec![], vec![], vec![(5, true)]);
+        let outcome = session.handle(&root, &store, &msg).unwrap();
+
+        assert!(outcome.received().is_empty());
+        assert_eq!(outcome.rejected().len(), 1);
+        assert_eq!(outcome.rejected()[0].object(), &(5, true));
+        assert_eq!(outcome.rejected()[0].reason(), RejectReason::NotRequested);
+        assert_eq!(session.stats().objects_rejected, 1);
+    }
+
+    #[test]
+    fn reports_unavailable_objects() {
+        let (root_a, mut store_a) = setup(&[1, 2, 3, 4]);
+        let (root_b, store_b) = setup(&[1, 2, 3]);
+
+        let mut session_a = Session::new(Role::Initiator, 3, UniformSplit::<2>);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<2>);
+
+        // exchange messages until b asks for the item it lacks
+        let mut msg: Message<TestMonoid, TestObject> = session_a.start(&root_a).unwrap();
+        loop {
+            let outcome = session_b.handle(&root_b, &store_b, &msg).unwrap();
+            msg = outcome.reply().unwrap().clone();
+            if !msg.wants().is_empty() {
+                break;
+            }
+
+            let outcome = session_a.handle(&root_a, &store_a, &msg).unwrap();
+            msg = outcome.reply().unwrap().clone();
+        }
+        assert_eq!(msg.wants(), &vec![4]);
+
+        // a deletes the item before it gets to respond
+        store_a.remove(&4);
+        let outcome = session_a.handle(&root_a, &store_a, &msg).unwrap();
+        let msg = outcome.reply().unwrap();
+        assert!(msg.provide().is_empty());
+        assert_eq!(msg.not_available(), &vec![4]);
+
+        let outcome = session_b.handle(&root_b, &store_b, msg).unwrap();
+        assert!(outcome.received().is_empty());
+        assert_eq!(outcome.unavailable(), &[4]);
+        assert!(session_b.requested().is_empty());
+        assert_eq!(session_b.stats().objects_unavailable, 1);
+    }
+}
@@ -0,0 +1,357 @@
+//! Item sets that list truncated hashes of the items instead of the items themselves, similar to
+//! compact block relay. For large items this makes the leaves of the reconciliation a lot
+//! cheaper, at the cost of an extra round for the items the receiver doesn't know.
+//!
+//! The receiver of a [`ShortItemSet`] matches the IDs against its own items in the range. Items
+//! whose ID it doesn't find are sent back in full in a regular [`ItemSet`], and IDs it can't
+//! match are asked for with a [`ShortIdRequest`], which is answered with the full items. If
+//! several items on one side share an ID, that side can't tell which of them the peer has, so it
+//! sends all of them in full.
+//!
+//! Two different items on either side that happen to share an ID can't be detected, and neither
+//! side learns about the other's item. Short IDs need to be long enough for that to be unlikely,
+//! given the number of items in a leaf, so peers refuse IDs shorter than [`MIN_SHORT_ID_LEN`].
+//!
+//! Short IDs are keyed hashes, like in BIP 152. The initiator of a session picks a random
+//! [`ShortIdKey`] and sends it along with every message, so nobody can prepare items whose IDs
+//! collide with those of someone else's items ahead of a session.
+
+extern crate alloc;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::hash::Hasher;
+
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+
+use crate::{item::Item, range::Range};
+
+use super::{canonical, ItemSet, ProtocolMonoid};
+
+/// The shortest short ID, in bytes. With two items in a leaf, the chance that their IDs collide
+/// is one in four billion; with shorter IDs, peers would miss items too often.
+pub const MIN_SHORT_ID_LEN: u8 = 4;
+
+/// The longest short ID, in bytes. Longer IDs don't fit into a `u64`.
+pub const MAX_SHORT_ID_LEN: u8 = 8;
+
+/// The key of the SipHash behind the short IDs of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ShortIdKey(pub u64, pub u64);
+
+impl ShortIdKey {
+    pub fn random() -> Self {
+        Self(rand::random(), rand::random())
+    }
+}
+
+/// Returns the first `len` bytes of the SipHash-2-4 of the item under `key`. The item is hashed
+/// in its canonical encoding, see [`canonical`], so the ID doesn't depend on the wire format.
+/// `len` is capped at [`MAX_SHORT_ID_LEN`].
+pub fn short_id<I: Item + Serialize>(item: &I, key: &ShortIdKey, len: u8) -> u64 {
+    let len = len.min(MAX_SHORT_ID_LEN) as u32;
+    let mut hasher = SipHasher24::new_with_keys(key.0, key.1);
+    // items we can hash are the only ones we can send, so this doesn't fail in practice. if it
+    // does, what was written so far still makes for a deterministic ID.
+    let _ = canonical::write(item, &mut hasher);
+    hasher.finish().checked_shr(64 - 8 * len).unwrap_or(0)
+}
+
+/// All items in a range, mostly listed by their short IDs.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(bound = "M::Item: Serialize, for<'de2> M::Item: Deserialize<'de2>")]
+pub struct ShortItemSet<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    range: Range<M::Item>,
+    ids: Vec<u64>,
+    /// Items whose ID is shared with another item in the range.
+    items: Vec<M::Item>,
+    want_response: bool,
+}
+
+impl<M> ShortItemSet<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    pub fn new(
+        range: Range<M::Item>,
+        ids: Vec<u64>,
+        items: Vec<M::Item>,
+        want_response: bool,
+    ) -> Self {
+        Self {
+            range,
+            ids,
+            items,
+            want_response,
+        }
+    }
+
+    /// Lists `items` by their IDs of length `len` under `key`. Items that share an ID are listed
+    /// in full, in the order of the range.
+    pub fn from_items(
+        range: Range<M::Item>,
+        items: Vec<M::Item>,
+        key: &ShortIdKey,
+        len: u8,
+        want_response: bool,
+    ) -> Self {
+        let mut by_id = by_id(items.iter(), key, len);
+        let mut ids = Vec::new();
+        let mut ambiguous = Vec::new();
+        for item in &items {
+            let id = short_id(item, key, len);
+            match by_id.remove(&id) {
+                Some(group) if group.len() == 1 => ids.push(id),
+                Some(group) => ambiguous.extend(group.into_iter().cloned()),
+                None => {}
+            }
+        }
+        ambiguous.sort_by(|a, b| range.position(a).cmp(&range.position(b)));
+
+        Self::new(range, ids, ambiguous, want_response)
+    }
+
+    pub fn range(&self) -> &Range<M::Item> {
+        &self.range
+    }
+
+    pub fn ids(&self) -> &Vec<u64> {
+        &self.ids
+    }
+
+    /// The items that are listed in full.
+    pub fn items(&self) -> &Vec<M::Item> {
+        &self.items
+    }
+
+    pub fn want_response(&self) -> bool {
+        self.want_response
+    }
+
+    /// Compares the set with `ours`, our items in the range. Returns the IDs we don't have an item
+    /// for, and our items the sender doesn't seem to have, in the order of the range. The items
+    /// listed in full are not considered here, they are handled like a regular [`ItemSet`].
+    pub(crate) fn compare<'a>(
+        &self,
+        ours: &'a [M::Item],
+        key: &ShortIdKey,
+        len: u8,
+    ) -> (Vec<u64>, Vec<&'a M::Item>)
+    where
+        M::Item: 'a,
+    {
+        let theirs: BTreeSet<u64> = self.ids.iter().copied().collect();
+        let their_items: BTreeSet<&M::Item> = self.items.iter().collect();
+        let ours_by_id = by_id(ours.iter(), key, len);
+
+        let unknown = theirs
+            .iter()
+            .filter(|id| !ours_by_id.contains_key(id))
+            .copied()
+            .collect();
+
+        // if several of our items match an ID, we don't know which one the peer has
+        let mut missing: Vec<_> = ours_by_id
+            .into_iter()
+            .filter(|(id, group)| !theirs.contains(id) || group.len() > 1)
+            .flat_map(|(_, group)| group)
+            .filter(|item| !their_items.contains(item))
+            .collect();
+        missing.sort_by(|a, b| self.range.position(a).cmp(&self.range.position(b)));
+
+        (unknown, missing)
+    }
+}
+
+/// Asks the peer for the full items behind short IDs it sent in a [`ShortItemSet`] for the range.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(bound = "M::Item: Serialize, for<'de2> M::Item: Deserialize<'de2>")]
+pub struct ShortIdRequest<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    range: Range<M::Item>,
+    ids: Vec<u64>,
+}
+
+impl<M> ShortIdRequest<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    pub fn new(range: Range<M::Item>, ids: Vec<u64>) -> Self {
+        Self { range, ids }
+    }
+
+    pub fn range(&self) -> &Range<M::Item> {
+        &self.range
+    }
+
+    pub fn ids(&self) -> &Vec<u64> {
+        &self.ids
+    }
+
+    /// Answers the request with those of `ours`, our items in the range, that match one of the
+    /// IDs. Returns `None` if none match.
+    pub(crate) fn resolve(
+        &self,
+        ours: Vec<M::Item>,
+        key: &ShortIdKey,
+        len: u8,
+    ) -> Option<ItemSet<M>> {
+        let ids: BTreeSet<u64> = self.ids.iter().copied().collect();
+        let items: Vec<_> = ours
+            .into_iter()
+            .filter(|item| ids.contains(&short_id(item, key, len)))
+            .collect();
+
+        if items.is_empty() {
+            None
+        } else {
+            Some(ItemSet::new(self.range.clone(), items, false))
+        }
+    }
+}
+
+fn by_id<'a, I: Item + Serialize + 'a>(
+    items: impl Iterator<Item = &'a I>,
+    key: &ShortIdKey,
+    len: u8,
+) -> BTreeMap<u64, Vec<&'a I>> {
+    let mut by_id: BTreeMap<u64, Vec<&I>> = BTreeMap::new();
+    for item in items {
+        by_id
+            .entry(short_id(item, key, len))
+            .or_default()
+            .push(item);
+    }
+    by_id
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{vec, vec::Vec};
+
+    use crate::{
+        easy::tests::TestItem, monoid::hashxor::CountingSha256Xor, protocol::ItemSet, range::Range,
+    };
+
+    use super::{short_id, ShortIdKey, ShortIdRequest, ShortItemSet, MAX_SHORT_ID_LEN};
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+
+    const KEY: ShortIdKey = ShortIdKey(1, 2);
+
+    #[test]
+    fn short_id_length() {
+        for len in 1..=MAX_SHORT_ID_LEN {
+            for item in 0..100u64 {
+                let id = short_id(&item, &KEY, len);
+                assert!(len == MAX_SHORT_ID_LEN || id < 1 << (8 * len));
+                assert_eq!(
+                    short_id(&item, &KEY, MAX_SHORT_ID_LEN) >> (8 * (8 - len as u64)),
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ids_depend_on_the_key() {
+        let ids = |key: &ShortIdKey| -> Vec<u64> {
+            (0..100u64)
+                .map(|item| short_id(&item, key, MAX_SHORT_ID_LEN))
+                .collect()
+        };
+        assert_eq!(ids(&KEY), ids(&KEY));
+        assert_ne!(ids(&KEY), ids(&ShortIdKey(2, 1)));
+        assert_ne!(ids(&KEY), ids(&ShortIdKey::default()));
+    }
+
+    #[test]
+    fn ids_depend_on_the_encoding() {
+        // same debug output, different bytes
+        assert_ne!(
+            short_id(&1u64, &KEY, MAX_SHORT_ID_LEN),
+            short_id(&1u32, &KEY, MAX_SHORT_ID_LEN)
+        );
+    }
+
+    #[test]
+    fn collisions_are_sent_in_full() {
+        // with one byte, 100 items are bound to collide
+        let items: Vec<u64> = (0..100).collect();
+        let set: ShortItemSet<TestMonoid> =
+            ShortItemSet::from_items(Range(0, 100), items.clone(), &KEY, 1, true);
+
+        assert!(!set.items().is_empty());
+        assert!(set.items().windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(set.ids().len() + set.items().len(), items.len());
+        for item in set.items() {
+            let id = short_id(item, &KEY, 1);
+            assert!(!set.ids().contains(&id));
+            assert!(
+                set.items()
+                    .iter()
+                    .filter(|x| short_id(*x, &KEY, 1) == id)
+                    .count()
+                    > 1
+            );
+        }
+    }
+
+    #[test]
+    fn compare_and_resolve() {
+        let len = 2;
+        let theirs: Vec<u64> = (0..40).filter(|i| i % 3 != 0).collect();
+        let ours: Vec<u64> = (0..40).filter(|i| i % 5 != 0).collect();
+
+        let set: ShortItemSet<TestMonoid> =
+            ShortItemSet::from_items(Range(0, 40), theirs.clone(), &KEY, len, true);
+        let (unknown, missing) = set.compare(&ours, &KEY, len);
+
+        // everything of ours they don't have is sent back, maybe with some extra items
+        let missing: Vec<u64> = missing.into_iter().copied().collect();
+        assert!(missing.windows(2).all(|pair| pair[0] < pair[1]));
+        for item in &ours {
+            if !theirs.contains(item) {
+                assert!(missing.contains(item));
+            }
+        }
+
+        // they resolve the IDs we don't know
+        let request: ShortIdRequest<TestMonoid> = ShortIdRequest::new(Range(0, 40), unknown);
+        let resolved = request.resolve(theirs.clone(), &KEY, len).unwrap();
+        let mut learned: Vec<u64> = resolved.items().clone();
+        learned.extend(set.items().iter().copied());
+        for item in &theirs {
+            if !ours.contains(item) {
+                assert!(learned.contains(item));
+            }
+        }
+
+        let none: ShortIdRequest<TestMonoid> = ShortIdRequest::new(Range(0, 40), vec![]);
+        assert_eq!(none.resolve(theirs, &KEY, len), None::<ItemSet<TestMonoid>>);
+    }
+}
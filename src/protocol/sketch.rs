@@ -0,0 +1,63 @@
+//! Reconciling a range in a single round trip with an [`Iblt`] of the short IDs of its items,
+//! instead of splitting it up over many rounds.
+//!
+//! Instead of splitting a range whose fingerprint doesn't match, the receiver can send a
+//! [`Sketch`] of its items in the range. The peer subtracts the sketch of its own items, and if
+//! the difference is small enough to be listed, it sends the items the receiver lacks and asks
+//! for the ones it lacks itself with a [`super::ShortIdRequest`]. If the difference can't be
+//! listed, the peer splits the range as if the fingerprint hadn't matched, and the sketch was
+//! only a waste of bytes.
+//!
+//! Sketches are only sent if the counts of the two sides differ by less than half the number of
+//! cells, since larger differences never decode.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{query::iblt::Iblt, range::Range};
+
+use super::ProtocolMonoid;
+
+/// The most cells a sketch may have. Peers asking for larger sketches, or sending them, are
+/// refused, since we would have to allocate them.
+pub const MAX_CELLS: usize = 1 << 16;
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(bound = "M::Item: Serialize, for<'de2> M::Item: Deserialize<'de2>")]
+pub struct Sketch<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    range: Range<M::Item>,
+    iblt: Iblt,
+}
+
+impl<M> Sketch<M>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    pub fn new(range: Range<M::Item>, iblt: Iblt) -> Self {
+        Self { range, iblt }
+    }
+
+    pub fn range(&self) -> &Range<M::Item> {
+        &self.range
+    }
+
+    pub fn iblt(&self) -> &Iblt {
+        &self.iblt
+    }
+}
+
+/// Whether a sketch with `cells` cells is worth sending for a range in which we have `ours` items
+/// and the peer has `theirs`.
+pub(crate) fn worth_sketching(ours: usize, theirs: usize, cells: usize) -> bool {
+    cells > 0 && ours.abs_diff(theirs).saturating_mul(2) < cells
+}
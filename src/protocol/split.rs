@@ -0,0 +1,97 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::{easy, range::Range};
+
+use super::ProtocolMonoid;
+
+/// Decides how a range with mismatching fingerprints is split into sub-ranges. Returns the number
+/// of items that should go into each bucket; the sizes have to add up to `monoid.count()`.
+/// Empty buckets are dropped, and if fewer than two buckets remain, the range is halved instead.
+///
+/// The strategy gets to see the range that is split, our fingerprint of that range and the round
+/// of the protocol we are in, so it can make decisions based on more than just the item count.
+pub trait SplitStrategy<M: ProtocolMonoid> {
+    fn split(&self, range: &Range<M::Item>, monoid: &M, round: usize) -> Vec<usize>;
+}
+
+/// Plain functions that only look at the item count are also split strategies.
+impl<M, F> SplitStrategy<M> for F
+where
+    M: ProtocolMonoid,
+    F: Fn(usize) -> Vec<usize>,
+{
+    fn split(&self, _range: &Range<M::Item>, monoid: &M, _round: usize) -> Vec<usize> {
+        self(monoid.count())
+    }
+}
+
+/// Splits into `C` buckets of (almost) equal size. A good choice if the items are uniformly
+/// distributed, e.g. because they are hashes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformSplit<const C: usize>;
+
+impl<M: ProtocolMonoid, const C: usize> SplitStrategy<M> for UniformSplit<C> {
+    fn split(&self, _range: &Range<M::Item>, monoid: &M, _round: usize) -> Vec<usize> {
+        easy::uniform::split::<C>(monoid.count())
+    }
+}
+
+/// Splits into `C` buckets whose sizes halve from one bucket to the next, so the newest items end
+/// up in the smallest buckets. This works well for timestamped items, where recent items are the
+/// ones most likely to be missing on the other side.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExponentialSplit<const C: usize>;
+
+impl<M: ProtocolMonoid, const C: usize> SplitStrategy<M> for ExponentialSplit<C> {
+    fn split(&self, _range: &Range<M::Item>, monoid: &M, _round: usize) -> Vec<usize> {
+        easy::timestamped::split::<C>(monoid.count())
+    }
+}
+
+/// Like [`ExponentialSplit`], but keeps halving until the last bucket holds fewer than `THRESH`
+/// items, so the number of buckets grows with the size of the range.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AdaptiveSplit<const THRESH: usize>;
+
+impl<M: ProtocolMonoid, const THRESH: usize> SplitStrategy<M> for AdaptiveSplit<THRESH> {
+    fn split(&self, _range: &Range<M::Item>, monoid: &M, _round: usize) -> Vec<usize> {
+        easy::timestamped::split_dynamic::<THRESH>(monoid.count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::easy::tests::TestMonoid;
+    use crate::monoid::{count::CountingMonoid, sum::SumMonoid};
+
+    #[test]
+    fn split_sizes_add_up() {
+        let monoid: TestMonoid = CountingMonoid::new(1000, SumMonoid(0));
+        let range = Range(0, 0);
+
+        let strategies: [&dyn SplitStrategy<TestMonoid>; 4] = [
+            &UniformSplit::<3>,
+            &ExponentialSplit::<4>,
+            &AdaptiveSplit::<8>,
+            &easy::uniform::split::<2>,
+        ];
+
+        for strategy in strategies {
+            let sizes = strategy.split(&range, &monoid, 0);
+            assert_eq!(sizes.iter().sum::<usize>(), 1000, "{sizes:?}");
+        }
+
+        assert_eq!(UniformSplit::<3>.split(&range, &monoid, 0), [334, 333, 333]);
+        assert_eq!(
+            ExponentialSplit::<4>.split(&range, &monoid, 0),
+            [500, 250, 125, 125]
+        );
+        assert_eq!(
+            AdaptiveSplit::<200>.split(&range, &monoid, 0),
+            [500, 250, 125, 125]
+        );
+    }
+}
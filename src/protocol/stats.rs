@@ -0,0 +1,108 @@
+use core::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Format, Message, ProtocolMonoid};
+use crate::Object;
+
+/// Counters describing what a sync session cost so far.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// Number of messages exchanged in either direction.
+    pub rounds: usize,
+    pub messages_sent: usize,
+    pub messages_received: usize,
+    pub fingerprints_sent: usize,
+    /// Number of the peer's fingerprints we compared with our own.
+    pub fingerprints_compared: usize,
+    /// Number of the peer's fingerprints that didn't match our own.
+    pub fingerprint_mismatches: usize,
+    /// Number of item sets sent, including the ones listing short IDs.
+    pub item_sets_sent: usize,
+    /// Number of items listed in the item sets we sent, including short IDs.
+    pub items_sent: usize,
+    /// Number of items we asked the peer to send us.
+    pub items_requested: usize,
+    pub objects_sent: usize,
+    pub objects_received: usize,
+    /// Number of objects the peer sent us that failed validation.
+    pub objects_rejected: usize,
+    /// Number of objects we asked for, but the peer didn't have.
+    pub objects_unavailable: usize,
+    /// The encoded size of the messages we sent, by part. Only measured with
+    /// [`Session::with_part_sizes`](super::Session::with_part_sizes).
+    pub bytes_sent: PartSizes,
+    /// Number of items we listed from our tree, to send them or to compare them with the peer's.
+    pub items_enumerated: usize,
+    /// Number of objects the peer wanted from us, whether we had them or not.
+    pub objects_looked_up: usize,
+    /// Number of the peer's fingerprints and sketches we decoded.
+    pub decodes: usize,
+    /// Time spent decoding the peer's fingerprints into monoid values.
+    pub decode_time: Duration,
+    /// Time spent encoding our fingerprints.
+    pub encode_time: Duration,
+}
+
+/// The encoded size of each part of one or more messages. Every part is encoded on its own, so
+/// the sizes don't add up to the size of the messages exactly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PartSizes {
+    pub fingerprints: usize,
+    pub item_sets: usize,
+    pub wants: usize,
+    pub provide: usize,
+    pub not_available: usize,
+    pub short_item_sets: usize,
+    pub short_id_requests: usize,
+    pub sketches: usize,
+}
+
+impl PartSizes {
+    /// Measures the parts of `msg` when encoded in `format`.
+    pub fn of<M, O>(format: Format, msg: &Message<M, O>) -> Self
+    where
+        M: ProtocolMonoid,
+        O: Object<M::Item> + Serialize,
+        M::Item: Serialize,
+        M::Encoded: Serialize,
+        for<'de2> M::Item: Deserialize<'de2>,
+        for<'de2> M::Encoded: Deserialize<'de2>,
+        for<'de2> O: Deserialize<'de2>,
+    {
+        Self {
+            fingerprints: format.encoded_len(&msg.fps),
+            item_sets: format.encoded_len(&msg.item_sets),
+            wants: format.encoded_len(&msg.wants),
+            provide: format.encoded_len(&msg.provide),
+            not_available: format.encoded_len(&msg.not_available),
+            short_item_sets: format.encoded_len(&msg.short_item_sets),
+            short_id_requests: format.encoded_len(&msg.short_id_requests),
+            sketches: format.encoded_len(&msg.sketches),
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.fingerprints
+            + self.item_sets
+            + self.wants
+            + self.provide
+            + self.not_available
+            + self.short_item_sets
+            + self.short_id_requests
+            + self.sketches
+    }
+}
+
+impl core::ops::AddAssign for PartSizes {
+    fn add_assign(&mut self, other: Self) {
+        self.fingerprints += other.fingerprints;
+        self.item_sets += other.item_sets;
+        self.wants += other.wants;
+        self.provide += other.provide;
+        self.not_available += other.not_available;
+        self.short_item_sets += other.short_item_sets;
+        self.short_id_requests += other.short_id_requests;
+        self.sketches += other.sketches;
+    }
+}
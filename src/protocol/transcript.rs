@@ -0,0 +1,411 @@
+//! Recording what went over the wire during a session, and replaying it.
+//!
+//! A [`Recorder`] wraps a [`Session`] and keeps every message we received or sent in a
+//! [`Transcript`], exactly as encoded, along with when it happened. The transcript can be saved,
+//! e.g. by encoding it in a [`Format`], and attached to a bug report.
+//!
+//! [`Transcript::replay`] feeds the received messages to a fresh session, set up like the recorded
+//! one and working on a given set of items, and checks that it sends the same bytes that were
+//! recorded. If the set matches the one the recording was made with, any difference points to a
+//! change in behavior.
+
+extern crate alloc;
+extern crate std;
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Node, Object, ObjectStore};
+
+use super::{
+    encode_message, Format, Message, Outcome, ProtocolMonoid, Role, Session, SplitStrategy,
+    TranscriptError,
+};
+
+/// Whether a message was sent or received by the side that made the recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Flow {
+    Sent,
+    Received,
+}
+
+/// A single message in a transcript.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    flow: Flow,
+    /// The time since the recording started.
+    at: Duration,
+    bytes: Vec<u8>,
+}
+
+impl Entry {
+    pub fn flow(&self) -> Flow {
+        self.flow
+    }
+
+    /// When the message was sent or received, relative to the start of the recording.
+    pub fn at(&self) -> Duration {
+        self.at
+    }
+
+    /// The message, encoded in the format of the transcript.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// The messages of one side of a session, in the order they were sent and received.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transcript {
+    role: Role,
+    format: Format,
+    entries: Vec<Entry>,
+}
+
+impl Transcript {
+    pub fn new(role: Role, format: Format) -> Self {
+        Self {
+            role,
+            format,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The role of the side that made the recording.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The format the messages are encoded in.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, flow: Flow, at: Duration, bytes: Vec<u8>) {
+        self.entries.push(Entry { flow, at, bytes });
+    }
+
+    /// Decodes the message of every entry.
+    #[allow(clippy::type_complexity)]
+    pub fn messages<M, O>(&self) -> Result<Vec<(Flow, Message<M, O>)>, TranscriptError<M>>
+    where
+        M: ProtocolMonoid,
+        O: Object<M::Item> + Serialize,
+        M::Item: Serialize,
+        M::Encoded: Serialize,
+        for<'de2> M::Item: Deserialize<'de2>,
+        for<'de2> M::Encoded: Deserialize<'de2>,
+        for<'de2> O: Deserialize<'de2>,
+    {
+        self.entries
+            .iter()
+            .map(|entry| Ok((entry.flow, self.format.decode(&entry.bytes)?)))
+            .collect()
+    }
+
+    /// Feeds the received messages to `session` and checks that it sends exactly the recorded
+    /// messages in response. The session has to be new, and configured like the one that made
+    /// the recording. An initiator without a short ID key takes the one of the first recorded
+    /// message, instead of picking a random one.
+    ///
+    /// Fails with [`TranscriptError::Diverged`] at the first entry where the replay differs.
+    pub fn replay<M, O, Sp, N, S>(
+        &self,
+        session: &mut Session<M, O, Sp>,
+        root: &N,
+        object_store: &S,
+    ) -> Result<(), TranscriptError<M>>
+    where
+        M: ProtocolMonoid,
+        O: Object<M::Item> + Serialize,
+        Sp: SplitStrategy<M>,
+        N: Node<M>,
+        S: ObjectStore<M::Item, O>,
+        M::Item: Serialize,
+        M::Encoded: Serialize,
+        for<'de2> M::Item: Deserialize<'de2>,
+        for<'de2> M::Encoded: Deserialize<'de2>,
+        for<'de2> O: Deserialize<'de2>,
+    {
+        if session.role() != self.role {
+            return Err(TranscriptError::RoleMismatch(self.role));
+        }
+
+        let format = self.format;
+        if self.role == Role::Initiator && session.short_id_key().is_none() {
+            if let Some(entry) = self.entries.iter().find(|entry| entry.flow == Flow::Sent) {
+                let first: Message<M, O> = format.decode(&entry.bytes)?;
+                session.set_short_id_key(first.short_id_key());
+            }
+        }
+
+        let mut pending = match self.role {
+            Role::Initiator => Some(encode_message(format, &session.start(root)?)?),
+            Role::Responder => None,
+        };
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry.flow {
+                Flow::Sent if pending.as_deref() == Some(entry.bytes()) => pending = None,
+                Flow::Received if pending.is_none() => {
+                    let msg = format.decode(&entry.bytes)?;
+                    let outcome = session.handle(root, object_store, &msg)?;
+                    pending = match outcome.reply() {
+                        Some(reply) => Some(encode_message(format, reply)?),
+                        None => None,
+                    };
+                }
+                flow => {
+                    return Err(TranscriptError::Diverged {
+                        index,
+                        recorded: (flow == Flow::Sent).then(|| entry.bytes.clone()),
+                        replayed: pending,
+                    })
+                }
+            }
+        }
+
+        match pending {
+            None => Ok(()),
+            replayed => Err(TranscriptError::Diverged {
+                index: self.entries.len(),
+                recorded: None,
+                replayed,
+            }),
+        }
+    }
+}
+
+/// Wraps a session and records the messages it receives and sends.
+#[derive(Debug)]
+pub struct Recorder<M, O, Sp>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    session: Session<M, O, Sp>,
+    transcript: Transcript,
+    started_at: Instant,
+}
+
+impl<M, O, Sp> Recorder<M, O, Sp>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    Sp: SplitStrategy<M>,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    /// Starts recording. Messages are recorded in the format of the session's limits.
+    pub fn new(session: Session<M, O, Sp>) -> Self {
+        let transcript = Transcript::new(session.role(), session.limits().format);
+        Self {
+            session,
+            transcript,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn session(&self) -> &Session<M, O, Sp> {
+        &self.session
+    }
+
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    pub fn into_parts(self) -> (Session<M, O, Sp>, Transcript) {
+        (self.session, self.transcript)
+    }
+
+    /// Like [`Session::start`], and returns the message encoded, ready to be sent.
+    pub fn start<N: Node<M>>(&mut self, root: &N) -> Result<Vec<u8>, TranscriptError<M>> {
+        let msg = self.session.start(root)?;
+        let bytes = encode_message(self.transcript.format, &msg)?;
+        self.record(Flow::Sent, bytes.clone());
+        Ok(bytes)
+    }
+
+    /// Like [`Session::handle`], for a message as it came over the wire. It's recorded even if it
+    /// can't be decoded. The reply is recorded too, and [`Recorder::last_sent`] returns it
+    /// encoded.
+    pub fn handle<N, S>(
+        &mut self,
+        root: &N,
+        object_store: &S,
+        bytes: &[u8],
+    ) -> Result<Outcome<M, O>, TranscriptError<M>>
+    where
+        N: Node<M>,
+        S: ObjectStore<M::Item, O>,
+    {
+        self.record(Flow::Received, bytes.to_vec());
+
+        let msg = self.transcript.format.decode(bytes)?;
+        let outcome = self.session.handle(root, object_store, &msg)?;
+        if let Some(reply) = outcome.reply() {
+            let bytes = encode_message(self.transcript.format, reply)?;
+            self.record(Flow::Sent, bytes);
+        }
+
+        Ok(outcome)
+    }
+
+    /// The last reply we recorded, ready to be sent.
+    pub fn last_sent(&self) -> Option<&[u8]> {
+        self.transcript
+            .entries
+            .last()
+            .filter(|entry| entry.flow == Flow::Sent)
+            .map(Entry::bytes)
+    }
+
+    fn record(&mut self, flow: Flow, bytes: Vec<u8>) {
+        let at = self.started_at.elapsed();
+        self.transcript.push(flow, at, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{collections::BTreeMap, vec::Vec};
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{split::UniformSplit, Format, MessageLimits, Role, Session, TranscriptError},
+        tree::mem_rc::Node,
+    };
+
+    use super::{Flow, Recorder, Transcript};
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+    type TestNode = Node<TestMonoid>;
+    type TestSession = Session<TestMonoid, TestObject, UniformSplit<4>>;
+
+    fn setup(items: impl Iterator<Item = u64>) -> (TestNode, BTreeMap<u64, TestObject>) {
+        let mut root = TestNode::nil();
+        let mut object_store = BTreeMap::new();
+        for item in items {
+            root = root.insert(item);
+            object_store.insert(item, (item, true));
+        }
+
+        (root, object_store)
+    }
+
+    fn session(role: Role) -> TestSession {
+        let limits = MessageLimits {
+            max_bytes: 2000,
+            ..Default::default()
+        };
+        Session::new(role, 3, UniformSplit::<4>).with_limits(limits)
+    }
+
+    /// Runs a session between two recorders and returns both transcripts.
+    fn record(
+        (root_a, store_a): &(TestNode, BTreeMap<u64, TestObject>),
+        (root_b, store_b): &(TestNode, BTreeMap<u64, TestObject>),
+    ) -> (Transcript, Transcript) {
+        let mut alice = Recorder::new(session(Role::Initiator));
+        let mut bob = Recorder::new(session(Role::Responder));
+
+        let mut next = Some(alice.start(root_a).unwrap());
+        let mut to_bob = true;
+        while let Some(bytes) = next {
+            let (recorder, root, store) = if to_bob {
+                (&mut bob, root_b, store_b)
+            } else {
+                (&mut alice, root_a, store_a)
+            };
+            let outcome = recorder.handle(root, store, &bytes).unwrap();
+            next = outcome
+                .reply()
+                .map(|_| recorder.last_sent().unwrap().to_vec());
+            to_bob = !to_bob;
+        }
+
+        let (alice, alice_transcript) = alice.into_parts();
+        let (bob, bob_transcript) = bob.into_parts();
+        assert!(alice.is_finished() && bob.is_finished());
+        (alice_transcript, bob_transcript)
+    }
+
+    #[test]
+    fn replays_recorded_sessions() {
+        let alice = setup((0..1000).filter(|i| i % 7 != 0));
+        let bob = setup((0..1000).filter(|i| i % 11 != 0));
+        let (alice_transcript, bob_transcript) = record(&alice, &bob);
+
+        // both sides saw the same messages, the other way around
+        let flipped: Vec<_> = bob_transcript
+            .entries()
+            .iter()
+            .map(|entry| (entry.flow() == Flow::Received, entry.bytes()))
+            .collect();
+        let entries: Vec<_> = alice_transcript
+            .entries()
+            .iter()
+            .map(|entry| (entry.flow() == Flow::Sent, entry.bytes()))
+            .collect();
+        assert_eq!(entries, flipped);
+        assert!(entries.len() > 4);
+        assert!(alice_transcript
+            .entries()
+            .windows(2)
+            .all(|pair| pair[0].at() <= pair[1].at()));
+
+        // transcripts survive being saved
+        let format = Format::default();
+        let saved = format.encode(&alice_transcript).unwrap();
+        let loaded: Transcript = format.decode(&saved).unwrap();
+        assert_eq!(loaded, alice_transcript);
+        let msgs = loaded.messages::<TestMonoid, TestObject>().unwrap();
+        assert_eq!(msgs.len(), entries.len());
+
+        loaded
+            .replay(&mut session(Role::Initiator), &alice.0, &alice.1)
+            .unwrap();
+        bob_transcript
+            .replay(&mut session(Role::Responder), &bob.0, &bob.1)
+            .unwrap();
+    }
+
+    #[test]
+    fn detects_divergence() {
+        let alice = setup((0..1000).filter(|i| i % 7 != 0));
+        let bob = setup((0..1000).filter(|i| i % 11 != 0));
+        let (alice_transcript, bob_transcript) = record(&alice, &bob);
+
+        // a different set leads to different fingerprints at some point
+        let other = setup((0..1000).filter(|i| i % 13 != 0));
+        let result = bob_transcript.replay(&mut session(Role::Responder), &other.0, &other.1);
+        assert!(matches!(result, Err(TranscriptError::Diverged { index, .. }) if index > 0));
+
+        // so does a different configuration
+        let mut split_wide = Session::new(Role::Initiator, 3, UniformSplit::<8>);
+        let result = alice_transcript.replay(&mut split_wide, &alice.0, &alice.1);
+        assert!(matches!(result, Err(TranscriptError::Diverged { .. })));
+
+        let result = alice_transcript.replay(&mut session(Role::Responder), &alice.0, &alice.1);
+        assert!(matches!(
+            result,
+            Err(TranscriptError::RoleMismatch(Role::Initiator))
+        ));
+    }
+}
@@ -0,0 +1,267 @@
+//! Choosing, per range, whether to list its items or split it further, and into how many parts.
+//!
+//! [`respond_to_message`](super::respond_to_message) uses a fixed threshold and split strategy
+//! for the whole session. [`AdaptiveTuning`] instead looks at how the session went so far: if
+//! most of the items the peer lists turn out to be new to us, the differences are dense, and
+//! ranges are listed while they are still large. If only a few of the fingerprints at some depth
+//! mismatch, the differences are sparse, and a mismatching range most likely holds a single one
+//! of them, so it's split into many parts to find it in fewer rounds.
+//!
+//! The depth of a range is the number of times the items of the whole synced range need to be
+//! halved to get down to the number of items in the range, no matter how they were split.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::{easy, range::Range};
+
+use super::{ProtocolMonoid, SplitStrategy};
+
+/// The factor by which [`AdaptiveTuning`] raises the threshold at most.
+pub const MAX_THRESHOLD_FACTOR: usize = 8;
+
+/// The largest number of parts [`AdaptiveTuning`] splits a range into.
+pub const MAX_FANOUT: usize = 16;
+
+/// Decides when a range is listed item by item and how ranges are split. It is told about the
+/// fingerprints and items the peer sends, so it can adapt to them.
+pub trait Tuning<M: ProtocolMonoid> {
+    /// Ranges at `depth` with fewer items than this are listed item by item.
+    fn threshold(&self, depth: usize) -> usize;
+
+    /// Splits a range at `depth`, see [`SplitStrategy::split`].
+    fn split(&self, range: &Range<M::Item>, monoid: &M, depth: usize, round: usize) -> Vec<usize>;
+
+    /// Called for every fingerprint of the peer we compare with ours.
+    fn observe_fingerprint(&mut self, _depth: usize, _mismatch: bool) {}
+
+    /// Called for every set of items the peer lists, with the number of items listed and how
+    /// many of them we don't have.
+    fn observe_items(&mut self, _listed: usize, _new: usize) {}
+}
+
+/// Returns the depth of a range with `count` items, in a synced range with `total` items.
+pub(crate) fn depth(total: usize, count: usize) -> usize {
+    (total / count.max(1)).max(1).ilog2() as usize
+}
+
+/// The same threshold and split strategy for every range.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTuning<'a, Sp: ?Sized> {
+    threshold: usize,
+    split: &'a Sp,
+}
+
+impl<'a, Sp: ?Sized> FixedTuning<'a, Sp> {
+    pub fn new(threshold: usize, split: &'a Sp) -> Self {
+        Self { threshold, split }
+    }
+}
+
+impl<M, Sp> Tuning<M> for FixedTuning<'_, Sp>
+where
+    M: ProtocolMonoid,
+    Sp: SplitStrategy<M> + ?Sized,
+{
+    fn threshold(&self, _depth: usize) -> usize {
+        self.threshold
+    }
+
+    fn split(&self, range: &Range<M::Item>, monoid: &M, _depth: usize, round: usize) -> Vec<usize> {
+        self.split.split(range, monoid, round)
+    }
+}
+
+/// What [`AdaptiveTuning`] has learned about the differences between the two sides.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Observations {
+    /// The number of fingerprints compared and mismatched, by depth.
+    fingerprints: Vec<(usize, usize)>,
+    items_listed: usize,
+    items_new: usize,
+}
+
+impl Observations {
+    /// The number of the peer's fingerprints at `depth` we compared with ours.
+    pub fn compared(&self, depth: usize) -> usize {
+        self.fingerprints
+            .get(depth)
+            .map_or(0, |(compared, _)| *compared)
+    }
+
+    /// The number of the peer's fingerprints at `depth` that didn't match ours.
+    pub fn mismatched(&self, depth: usize) -> usize {
+        self.fingerprints
+            .get(depth)
+            .map_or(0, |(_, mismatched)| *mismatched)
+    }
+
+    /// The number of items the peer listed to us.
+    pub fn items_listed(&self) -> usize {
+        self.items_listed
+    }
+
+    /// The number of items the peer listed that we didn't have.
+    pub fn items_new(&self) -> usize {
+        self.items_new
+    }
+}
+
+/// Adapts the threshold and the number of parts ranges are split into to the observed
+/// differences, see the [module documentation](self). Falls back to the given threshold and
+/// split strategy as long as there is nothing to go by.
+#[derive(Debug)]
+pub struct AdaptiveTuning<'a, Sp: ?Sized> {
+    threshold: usize,
+    split: &'a Sp,
+    observations: &'a mut Observations,
+}
+
+impl<'a, Sp: ?Sized> AdaptiveTuning<'a, Sp> {
+    /// The observations are updated as messages are handled, and should be kept for the whole
+    /// session.
+    pub fn new(threshold: usize, split: &'a Sp, observations: &'a mut Observations) -> Self {
+        Self {
+            threshold,
+            split,
+            observations,
+        }
+    }
+}
+
+impl<M, Sp> Tuning<M> for AdaptiveTuning<'_, Sp>
+where
+    M: ProtocolMonoid,
+    Sp: SplitStrategy<M> + ?Sized,
+{
+    fn threshold(&self, _depth: usize) -> usize {
+        let listed = self.observations.items_listed;
+        let known = listed - self.observations.items_new;
+
+        // if half of the listed items are new, listing twice as many items is as efficient
+        let factor = match known {
+            0 if listed == 0 => 1,
+            0 => MAX_THRESHOLD_FACTOR,
+            known => (listed / known).clamp(1, MAX_THRESHOLD_FACTOR),
+        };
+
+        self.threshold.saturating_mul(factor)
+    }
+
+    fn split(&self, range: &Range<M::Item>, monoid: &M, depth: usize, round: usize) -> Vec<usize> {
+        let base = self.split.split(range, monoid, round);
+        let compared = self.observations.compared(depth);
+        let mismatched = self.observations.mismatched(depth);
+        if mismatched == 0 {
+            return base;
+        }
+
+        // if only one in `k` ranges differs, we split into about `2 * k` parts
+        let fanout = (2 * compared / mismatched).min(MAX_FANOUT);
+        if fanout <= base.len() {
+            base
+        } else {
+            easy::uniform::split_into(monoid.count(), fanout)
+        }
+    }
+
+    fn observe_fingerprint(&mut self, depth: usize, mismatch: bool) {
+        let fingerprints = &mut self.observations.fingerprints;
+        if fingerprints.len() <= depth {
+            fingerprints.resize(depth + 1, (0, 0));
+        }
+
+        fingerprints[depth].0 += 1;
+        if mismatch {
+            fingerprints[depth].1 += 1;
+        }
+    }
+
+    fn observe_items(&mut self, listed: usize, new: usize) {
+        self.observations.items_listed += listed;
+        self.observations.items_new += new.min(listed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec;
+
+    use super::*;
+
+    use crate::easy::tests::TestMonoid;
+    use crate::monoid::{count::CountingMonoid, sum::SumMonoid};
+    use crate::protocol::split::UniformSplit;
+
+    #[test]
+    fn depths() {
+        assert_eq!(depth(1000, 1000), 0);
+        assert_eq!(depth(1000, 2000), 0);
+        assert_eq!(depth(1000, 500), 1);
+        assert_eq!(depth(1000, 300), 1);
+        assert_eq!(depth(1000, 250), 2);
+        assert_eq!(depth(1000, 0), 9);
+        assert_eq!(depth(0, 0), 0);
+    }
+
+    #[test]
+    fn dense_differences_raise_the_threshold() {
+        let mut observations = Observations::default();
+        let mut tuning = AdaptiveTuning::new(4, &UniformSplit::<2>, &mut observations);
+        let threshold = |tuning: &AdaptiveTuning<_>| Tuning::<TestMonoid>::threshold(tuning, 3);
+        assert_eq!(threshold(&tuning), 4);
+
+        // most of what we are sent is known already
+        Tuning::<TestMonoid>::observe_items(&mut tuning, 10, 1);
+        assert_eq!(threshold(&tuning), 4);
+
+        // half of it is new
+        Tuning::<TestMonoid>::observe_items(&mut tuning, 10, 9);
+        assert_eq!(threshold(&tuning), 8);
+
+        Tuning::<TestMonoid>::observe_items(&mut tuning, 100, 100);
+        assert_eq!(threshold(&tuning), 4 * MAX_THRESHOLD_FACTOR);
+        assert_eq!(observations.items_listed(), 120);
+        assert_eq!(observations.items_new(), 110);
+    }
+
+    #[test]
+    fn sparse_differences_split_wide() {
+        let monoid: TestMonoid = CountingMonoid::new(1000, SumMonoid(0));
+        let range = Range(0, 0);
+        let mut observations = Observations::default();
+        let mut tuning = AdaptiveTuning::new(4, &UniformSplit::<2>, &mut observations);
+
+        // nothing observed yet
+        assert_eq!(tuning.split(&range, &monoid, 2, 0), vec![500, 500]);
+
+        // everything differs
+        for _ in 0..4 {
+            Tuning::<TestMonoid>::observe_fingerprint(&mut tuning, 2, true);
+        }
+        assert_eq!(tuning.split(&range, &monoid, 2, 0), vec![500, 500]);
+
+        // one in four differs
+        for _ in 0..12 {
+            Tuning::<TestMonoid>::observe_fingerprint(&mut tuning, 3, false);
+        }
+        for _ in 0..4 {
+            Tuning::<TestMonoid>::observe_fingerprint(&mut tuning, 3, true);
+        }
+        let sizes = tuning.split(&range, &monoid, 3, 0);
+        assert_eq!(sizes.len(), 8);
+        assert_eq!(sizes.iter().sum::<usize>(), 1000);
+
+        // almost nothing differs
+        for _ in 0..100 {
+            Tuning::<TestMonoid>::observe_fingerprint(&mut tuning, 5, false);
+        }
+        Tuning::<TestMonoid>::observe_fingerprint(&mut tuning, 5, true);
+        assert_eq!(tuning.split(&range, &monoid, 5, 0).len(), MAX_FANOUT);
+
+        assert_eq!(observations.compared(3), 16);
+        assert_eq!(observations.mismatched(3), 4);
+        assert_eq!(observations.compared(4), 0);
+    }
+}
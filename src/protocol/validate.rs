@@ -0,0 +1,356 @@
+//! Checks on the structure of the messages we receive, before we spend any work on them.
+//!
+//! The rest of the protocol relies on the peer to stick to a few rules: the ranges it asks us to
+//! compare don't overlap and lie inside the synced range, the items it lists for a range are in
+//! that range and sorted in its order, and it only asks for items we listed. A peer that breaks
+//! them is either broken or malicious, so [`validate`] fails the response instead of muddling
+//! through. The last rule needs to know what we listed in earlier messages, so only
+//! [`super::Session`] checks it, with [`check_wants`].
+
+extern crate alloc;
+use alloc::{collections::BTreeSet, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{item::Item, range::Range, Object};
+
+use super::{
+    short_id::MIN_SHORT_ID_LEN, sketch, Fingerprint, ItemSet, Message, ProtocolMonoid,
+    RespondError, ShortIdRequest, ShortItemSet, Sketch, ValidationError,
+};
+
+/// The largest number of items a fingerprint may claim to cover. Nobody syncs sets this large,
+/// and larger counts would only throw off how we compare and split the range.
+pub const MAX_COUNT: usize = 1 << 40;
+
+/// Checks everything about `msg` that doesn't need a look at our tree:
+///
+/// - sketches are no larger than [`sketch::MAX_CELLS`],
+/// - short IDs, if used, are at least [`MIN_SHORT_ID_LEN`] bytes long,
+/// - all ranges lie inside `sync_range`,
+/// - the ranges we are asked to compare, i.e. fingerprints, sketches, short item sets and item
+///   sets that want a response, don't overlap,
+/// - the items listed for a range are in the range, sorted in its order and without duplicates.
+pub fn validate<M, O>(
+    msg: &Message<M, O>,
+    sync_range: &Range<M::Item>,
+) -> Result<(), RespondError<M>>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let too_large = msg
+        .sketches()
+        .iter()
+        .map(|sketch| sketch.iblt().len())
+        .chain([msg.sketch_cells() as usize])
+        .find(|cells| *cells > sketch::MAX_CELLS);
+    if let Some(cells) = too_large {
+        return Err(RespondError::SketchTooLarge(cells));
+    }
+
+    if msg.short_id_len() != 0 && msg.short_id_len() < MIN_SHORT_ID_LEN {
+        return Err(RespondError::ShortIdTooShort(msg.short_id_len()));
+    }
+
+    let out_of_range = msg
+        .item_sets()
+        .iter()
+        .map(ItemSet::range)
+        .chain(msg.fingerprints().iter().map(Fingerprint::range))
+        .chain(msg.short_item_sets().iter().map(ShortItemSet::range))
+        .chain(msg.short_id_requests().iter().map(ShortIdRequest::range))
+        .chain(msg.sketches().iter().map(Sketch::range))
+        .find(|range| !sync_range.contains_range(range));
+    if let Some(range) = out_of_range {
+        return Err(RespondError::OutOfRange(range.clone()));
+    }
+
+    let compared = msg
+        .fingerprints()
+        .iter()
+        .map(Fingerprint::range)
+        .chain(msg.sketches().iter().map(Sketch::range))
+        .chain(msg.short_item_sets().iter().map(ShortItemSet::range))
+        .chain(
+            msg.item_sets()
+                .iter()
+                .filter(|item_set| item_set.want_response())
+                .map(ItemSet::range),
+        );
+    check_disjoint(compared)?;
+
+    for item_set in msg.item_sets() {
+        check_items(item_set.range(), item_set.items())?;
+    }
+    for short_item_set in msg.short_item_sets() {
+        check_items(short_item_set.range(), short_item_set.items())?;
+    }
+
+    Ok(())
+}
+
+/// Checks that none of the fingerprints, decoded into `theirs`, claims to cover more than
+/// [`MAX_COUNT`] items.
+///
+/// How many fingerprints the peer may send is up to the [`super::SessionBudget`], which
+/// [`super::respond_to_message_with`] checks before decoding any of them.
+pub fn check_counts<M>(fps: &[Fingerprint<M>], theirs: &[M]) -> Result<(), ValidationError<M>>
+where
+    M: ProtocolMonoid,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+{
+    match fps.iter().zip(theirs).find(|(_, m)| m.count() > MAX_COUNT) {
+        Some((fp, m)) => Err(ValidationError::ImplausibleCount {
+            range: fp.range().clone(),
+            count: m.count(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the peer only wants items we listed to it, in `advertised`.
+///
+/// [`super::respond_to_message`] doesn't know what we listed in earlier messages, so it hands out
+/// every object the peer wants. Only [`super::Session`] keeps track of that and calls this.
+pub fn check_wants<M, O>(
+    msg: &Message<M, O>,
+    advertised: &BTreeSet<M::Item>,
+) -> Result<(), ValidationError<M>>
+where
+    M: ProtocolMonoid,
+    O: Object<M::Item> + Serialize,
+    M::Item: Serialize,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    match msg.wants().iter().find(|item| !advertised.contains(item)) {
+        Some(item) => Err(ValidationError::UnknownWant(item.clone())),
+        None => Ok(()),
+    }
+}
+
+fn check_items<M: ProtocolMonoid>(
+    range: &Range<M::Item>,
+    items: &[M::Item],
+) -> Result<(), ValidationError<M>> {
+    if let Some(item) = items.iter().find(|item| !range.contains(item)) {
+        return Err(ValidationError::ItemOutOfRange {
+            range: range.clone(),
+            item: item.clone(),
+        });
+    }
+
+    let sorted = items
+        .windows(2)
+        .all(|pair| range.position(&pair[0]) < range.position(&pair[1]));
+    if !sorted {
+        return Err(ValidationError::UnsortedItems(range.clone()));
+    }
+
+    Ok(())
+}
+
+/// Checks that no two of `ranges` share an item.
+fn check_disjoint<'a, M>(
+    ranges: impl Iterator<Item = &'a Range<M::Item>>,
+) -> Result<(), ValidationError<M>>
+where
+    M: ProtocolMonoid,
+    M::Item: 'a,
+{
+    let ranges: Vec<&Range<M::Item>> = ranges.collect();
+
+    // wrapping ranges are cut in two at the end of the item space, which `None` stands for
+    let mut parts: Vec<(M::Item, Option<M::Item>, usize)> = Vec::with_capacity(ranges.len());
+    for (i, Range(from, to)) in ranges.iter().enumerate() {
+        if from < to {
+            parts.push((from.clone(), Some(to.clone()), i));
+        } else if from == to {
+            parts.push((M::Item::zero(), None, i));
+        } else {
+            parts.push((from.clone(), None, i));
+            if *to > M::Item::zero() {
+                parts.push((M::Item::zero(), Some(to.clone()), i));
+            }
+        }
+    }
+    parts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for pair in parts.windows(2) {
+        let ((_, end, i), (start, _, j)) = (&pair[0], &pair[1]);
+        if end.as_ref().is_none_or(|end| end > start) {
+            return Err(ValidationError::OverlappingRanges(
+                ranges[*i].clone(),
+                ranges[*j].clone(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{
+        collections::{BTreeMap, BTreeSet},
+        vec,
+        vec::Vec,
+    };
+
+    use proptest::{prop_assert_eq, proptest};
+
+    use crate::{
+        easy::tests::{TestMonoid, TestNode, TestObject},
+        monoid::{count::CountingMonoid, sum::SumMonoid},
+        protocol::{
+            respond_to_message_with, split::UniformSplit, Encodable, Fingerprint, FixedTuning,
+            ItemSet, Message, Resource, RespondError, SessionBudget, SyncStats, ValidationError,
+        },
+        range::Range,
+        Monoid,
+    };
+
+    use super::*;
+
+    type TestMessage = Message<TestMonoid, TestObject>;
+
+    fn item_set(from: u64, to: u64, items: Vec<u64>) -> ItemSet<TestMonoid> {
+        ItemSet::new(Range(from, to), items, true)
+    }
+
+    fn invalid(msg: &TestMessage) -> Option<ValidationError<TestMonoid>> {
+        match validate(msg, &Range(0, 0)) {
+            Err(RespondError::Invalid(e)) => Some(e),
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(()) => None,
+        }
+    }
+
+    #[test]
+    fn item_sets() {
+        let ok = |items| {
+            let msg = Message::new(vec![], vec![item_set(10, 20, items)], vec![], vec![]);
+            invalid(&msg)
+        };
+        assert!(ok(vec![]).is_none());
+        assert!(ok(vec![10, 15, 19]).is_none());
+        assert!(matches!(
+            ok(vec![15, 12]),
+            Some(ValidationError::UnsortedItems(_))
+        ));
+        assert!(matches!(
+            ok(vec![12, 12]),
+            Some(ValidationError::UnsortedItems(_))
+        ));
+        assert!(matches!(
+            ok(vec![12, 20]),
+            Some(ValidationError::ItemOutOfRange { item: 20, .. })
+        ));
+
+        // wrapping ranges list the items before `from` last
+        let wrapping = |items| {
+            let msg = Message::new(vec![], vec![item_set(20, 10, items)], vec![], vec![]);
+            invalid(&msg)
+        };
+        assert!(wrapping(vec![25, 30, 1, 5]).is_none());
+        assert!(matches!(
+            wrapping(vec![1, 5, 25, 30]),
+            Some(ValidationError::UnsortedItems(_))
+        ));
+    }
+
+    #[test]
+    fn implausible_counts() {
+        let mut theirs = vec![TestMonoid::lift(&1), TestMonoid::neutral()];
+        let fps: Vec<Fingerprint<TestMonoid>> = [Range(0, 10), Range(10, 20)]
+            .into_iter()
+            .zip(&theirs)
+            .map(|(range, m)| Fingerprint::new(range, m.to_encoded().unwrap()))
+            .collect();
+        assert!(check_counts(&fps, &theirs).is_ok());
+
+        theirs[1] = CountingMonoid::new(MAX_COUNT + 1, SumMonoid(0));
+        assert!(matches!(
+            check_counts(&fps, &theirs),
+            Err(ValidationError::ImplausibleCount { count, .. }) if count == MAX_COUNT + 1
+        ));
+    }
+
+    #[test]
+    fn limits_fingerprints_before_decoding() {
+        let root = TestNode::nil().insert(1);
+        let store = BTreeMap::from([(1, (1, true))]);
+        let fp = Fingerprint::new(Range(0, 10), TestMonoid::lift(&2).to_encoded().unwrap());
+        let msg: TestMessage = Message::new(vec![fp; 3], vec![], vec![], vec![]);
+
+        let budget = SessionBudget {
+            max_ranges: 2,
+            ..SessionBudget::unlimited()
+        };
+        let mut stats = SyncStats::default();
+        let result = respond_to_message_with(
+            &root,
+            &store,
+            &msg,
+            &BTreeSet::new(),
+            &Range(0, 0),
+            &mut FixedTuning::new(3, &UniformSplit::<2>),
+            1,
+            &budget,
+            &mut stats,
+        );
+        assert!(matches!(
+            result,
+            Err(RespondError::OverBudget {
+                resource: Resource::Ranges,
+                limit: 2
+            })
+        ));
+        assert_eq!(stats.decodes, 0);
+    }
+
+    #[test]
+    fn unknown_wants() {
+        let msg: TestMessage = Message::new(vec![], vec![], vec![1, 2], vec![]);
+        let advertised = BTreeSet::from([1, 2, 3]);
+        assert!(check_wants(&msg, &advertised).is_ok());
+        assert!(matches!(
+            check_wants(&msg, &BTreeSet::from([1])),
+            Err(ValidationError::UnknownWant(2))
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn overlap_correctness(a in (0..20u64, 0..20u64), b in (0..20u64, 0..20u64), response in proptest::bool::ANY) {
+            let (a, b) = (Range(a.0, a.1), Range(b.0, b.1));
+
+            // all endpoints are below 20, so larger items behave like 20
+            let overlap = (0..=20).any(|item| a.contains(&item) && b.contains(&item));
+
+            let msg: TestMessage = Message::new(
+                vec![Fingerprint::new(a, TestMonoid::neutral().to_encoded().unwrap())],
+                vec![ItemSet::new(b, vec![], response)],
+                vec![],
+                vec![],
+            );
+            let expected = overlap && response;
+            prop_assert_eq!(
+                matches!(invalid(&msg), Some(ValidationError::OverlappingRanges(..))),
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Invertible Bloom lookup tables over the short IDs of items. Subtracting the tables of two
+//! sets leaves a table of their symmetric difference, which can be listed again as long as it is
+//! small compared to the number of cells.
+
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    monoid::Monoid,
+    protocol::short_id::{short_id, ShortIdKey},
+    Node, NonNilNodeRef,
+};
+
+use super::Accumulator;
+
+/// The number of cells every key is added to.
+pub const HASH_COUNT: usize = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cell {
+    count: i64,
+    key_sum: u64,
+    hash_sum: u64,
+}
+
+impl Cell {
+    fn add(&mut self, key: u64, count: i64) {
+        // the peer controls the counts of the tables we decode
+        self.count = self.count.wrapping_add(count);
+        self.key_sum ^= key;
+        self.hash_sum ^= checksum(key);
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the key and whether it was added or removed, if the cell holds exactly one key.
+    fn pure(&self) -> Option<(u64, i64)> {
+        let pure = (self.count == 1 || self.count == -1) && self.hash_sum == checksum(self.key_sum);
+        pure.then_some((self.key_sum, self.count))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Iblt {
+    cells: Vec<Cell>,
+}
+
+impl Iblt {
+    /// Returns an empty table with at least `size` cells. The size is rounded up to a multiple of
+    /// [`HASH_COUNT`].
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1).div_ceil(HASH_COUNT) * HASH_COUNT;
+        Self {
+            cells: vec![Cell::default(); size],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Whether the table holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.cells.iter().all(Cell::is_empty)
+    }
+
+    pub fn insert(&mut self, key: u64) {
+        self.add(key, 1);
+    }
+
+    /// Returns the table of the keys in `self` but not in `other` (counted positive) and the keys
+    /// in `other` but not in `self` (counted negative). Both tables need to have the same size.
+    pub fn subtract(&self, other: &Self) -> Option<Self> {
+        if self.len() != other.len() || !self.len().is_multiple_of(HASH_COUNT) {
+            return None;
+        }
+
+        let cells = self
+            .cells
+            .iter()
+            .zip(&other.cells)
+            .map(|(a, b)| Cell {
+                count: a.count.wrapping_sub(b.count),
+                key_sum: a.key_sum ^ b.key_sum,
+                hash_sum: a.hash_sum ^ b.hash_sum,
+            })
+            .collect();
+
+        Some(Self { cells })
+    }
+
+    /// Lists the keys of a table returned by [`Iblt::subtract`], as the keys only on the left side
+    /// and the keys only on the right side. Returns `None` if the difference is too large.
+    pub fn decode(mut self) -> Option<(Vec<u64>, Vec<u64>)> {
+        if self.cells.is_empty() || !self.len().is_multiple_of(HASH_COUNT) {
+            return None;
+        }
+
+        let mut left = vec![];
+        let mut right = vec![];
+
+        let mut candidates: Vec<usize> = (0..self.len()).collect();
+        while let Some(i) = candidates.pop() {
+            let Some((key, count)) = self.cells[i].pure() else {
+                continue;
+            };
+
+            if count == 1 {
+                left.push(key);
+            } else {
+                right.push(key);
+            }
+
+            // a table of a difference this large can't be listed, so the peer made it up
+            if left.len() + right.len() > self.len() {
+                return None;
+            }
+
+            self.add(key, -count);
+            candidates.extend(self.indices(key));
+        }
+
+        self.is_empty().then_some((left, right))
+    }
+
+    fn add(&mut self, key: u64, count: i64) {
+        for i in self.indices(key) {
+            self.cells[i].add(key, count);
+        }
+    }
+
+    /// Every key goes into one cell of each of the [`HASH_COUNT`] equally sized parts of the
+    /// table, so its cells never coincide.
+    fn indices(&self, key: u64) -> [usize; HASH_COUNT] {
+        let part = (self.len() / HASH_COUNT) as u64;
+        let mut indices = [0; HASH_COUNT];
+        for (j, index) in indices.iter_mut().enumerate() {
+            *index = (j as u64 * part + mix(key, j as u64 + 1) % part) as usize;
+        }
+        indices
+    }
+}
+
+fn checksum(key: u64) -> u64 {
+    mix(key, 0)
+}
+
+/// The finalizer of splitmix64, seeded.
+fn mix(key: u64, seed: u64) -> u64 {
+    let mut z = key ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Builds an [`Iblt`] of the short IDs of all items in the queried range.
+#[derive(Debug, Clone)]
+pub struct IbltAccumulator<M: Monoid> {
+    iblt: Iblt,
+    key: ShortIdKey,
+    id_len: u8,
+    _monoid: core::marker::PhantomData<M>,
+}
+
+impl<M: Monoid> IbltAccumulator<M> {
+    /// `size` is the number of cells, and `key` and `id_len` the key and length of the short
+    /// IDs, see [`short_id`].
+    pub fn new(size: usize, key: ShortIdKey, id_len: u8) -> Self {
+        Self {
+            iblt: Iblt::new(size),
+            key,
+            id_len,
+            _monoid: core::marker::PhantomData,
+        }
+    }
+
+    pub fn into_result(self) -> Iblt {
+        self.iblt
+    }
+}
+
+impl<M> Accumulator<M> for IbltAccumulator<M>
+where
+    M: Monoid,
+    M::Item: Serialize,
+{
+    fn add_node<N: Node<M>>(&mut self, node: &N) {
+        let non_nil_node = if let Some(non_nil_node) = node.node_contents() {
+            non_nil_node
+        } else {
+            return;
+        };
+
+        for (child, item) in non_nil_node.children() {
+            self.add_node(child);
+            self.add_item(item);
+        }
+
+        self.add_node(non_nil_node.last_child())
+    }
+
+    fn add_item(&mut self, item: &M::Item) {
+        self.iblt.insert(short_id(item, &self.key, self.id_len));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use proptest::{prelude::prop, prop_assert, prop_assert_eq, proptest};
+
+    use crate::{
+        easy::tests::TestItem,
+        monoid::hashxor::CountingSha256Xor,
+        protocol::short_id::{short_id, ShortIdKey},
+        query::{items::ItemsAccumulator, Accumulator},
+        range::Range,
+        tree::mem_rc::Node,
+        Node as _,
+    };
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::{Iblt, IbltAccumulator};
+
+    proptest! {
+        #[test]
+        fn decode_correctness(keys in prop::collection::btree_set(proptest::num::u64::ANY, 0..200), left_only in 0..10usize, right_only in 0..10usize) {
+            let keys: Vec<u64> = keys.into_iter().collect();
+            let (left_keys, rest) = keys.split_at(left_only.min(keys.len()));
+            let (right_keys, shared) = rest.split_at(right_only.min(rest.len()));
+
+            let mut left = Iblt::new(60);
+            let mut right = Iblt::new(60);
+            for key in shared.iter().chain(left_keys) {
+                left.insert(*key);
+            }
+            for key in shared.iter().chain(right_keys) {
+                right.insert(*key);
+            }
+
+            // small tables fail every now and then, but they must never list the wrong keys
+            let Some((mut decoded_left, mut decoded_right)) = left.subtract(&right).unwrap().decode() else {
+                return Ok(());
+            };
+            decoded_left.sort();
+            decoded_right.sort();
+            let (mut expected_left, mut expected_right) = (left_keys.to_vec(), right_keys.to_vec());
+            expected_left.sort();
+            expected_right.sort();
+            prop_assert_eq!(decoded_left, expected_left);
+            prop_assert_eq!(decoded_right, expected_right);
+        }
+    }
+
+    #[test]
+    fn decode_success_rate() {
+        let mut rng = ChaCha8Rng::from_seed([7u8; 32]);
+        let mut decoded = 0;
+        for _ in 0..1000 {
+            let mut left = Iblt::new(60);
+            let mut right = Iblt::new(60);
+            for _ in 0..100 {
+                let key = rng.gen();
+                left.insert(key);
+                right.insert(key);
+            }
+            for _ in 0..10 {
+                left.insert(rng.gen());
+                right.insert(rng.gen());
+            }
+
+            if let Some((l, r)) = left.subtract(&right).unwrap().decode() {
+                assert_eq!((l.len(), r.len()), (10, 10));
+                decoded += 1;
+            }
+        }
+
+        assert!(decoded > 950, "only decoded {decoded} of 1000");
+    }
+
+    #[test]
+    fn large_differences_fail() {
+        let mut left = Iblt::new(12);
+        for key in 0..100 {
+            left.insert(key);
+        }
+
+        assert!(left.subtract(&Iblt::new(12)).unwrap().decode().is_none());
+        assert!(left.subtract(&Iblt::new(15)).is_none());
+    }
+
+    #[test]
+    fn bogus_counts_fail() {
+        // a table of size 3 puts every key into all of its cells
+        let mut table = Iblt::new(3);
+        table.insert(42);
+        table.cells[1].count = i64::MIN;
+
+        assert!(table.decode().is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn accumulator_matches_items(items in prop::collection::btree_set(0..1000u64, 0..100), from in 0..1000u64, to in 0..1000u64) {
+            let range = Range(from, to);
+            let mut root = Node::<CountingSha256Xor<TestItem>>::nil();
+            for item in &items {
+                root = root.insert(*item);
+            }
+
+            let mut acc = IbltAccumulator::new(30, ShortIdKey(1, 2), 8);
+            root.query(&range, &mut acc);
+            let mut items_acc = ItemsAccumulator::new();
+            root.query(&range, &mut items_acc);
+
+            let mut expected = Iblt::new(30);
+            for item in items_acc.results() {
+                expected.insert(short_id(item, &ShortIdKey(1, 2), 8));
+            }
+            prop_assert_eq!(acc.into_result(), expected);
+
+            let mut empty_acc = IbltAccumulator::<CountingSha256Xor<TestItem>>::new(30, ShortIdKey(1, 2), 8);
+            empty_acc.add_item(&from);
+            prop_assert!(!empty_acc.into_result().is_empty());
+        }
+    }
+}
@@ -7,6 +7,9 @@ use crate::{monoid::Monoid, Item, Node, NonNilNodeRef, Range};
 
 use super::Accumulator;
 
+/// Finds the items of a list that are not in the tree. The items need to be sorted in the order of
+/// [`ItemFilterAccumulator::query_range`], which wraps around if the last item is smaller than the
+/// first.
 #[derive(Debug, Clone)]
 pub struct ItemFilterAccumulator<'a, M: Monoid> {
     items: &'a [M::Item],
@@ -48,6 +51,12 @@ impl<'a, M: Monoid> ItemFilterAccumulator<'a, M> {
     fn cur_item(&self) -> Option<&M::Item> {
         self.items.get(self.cur)
     }
+
+    /// Whether `item` comes after `other` in the query range.
+    fn is_after(&self, item: &M::Item, other: &M::Item) -> bool {
+        let first = &self.items[0];
+        (item < first, item) > (other < first, other)
+    }
 }
 
 impl<'a, M> Accumulator<M> for ItemFilterAccumulator<'a, M>
@@ -70,7 +79,7 @@ where
     }
 
     fn add_item(&mut self, item: &<M as Monoid>::Item) {
-        while matches!(self.cur_item(), Some(cur_item) if item > cur_item) {
+        while matches!(self.cur_item(), Some(cur_item) if self.is_after(item, cur_item)) {
             self.bits_is_new[self.cur] = true;
             self.cur += 1;
         }
@@ -131,4 +140,21 @@ mod tests {
 
         assert!(new.is_empty())
     }
+
+    #[test]
+    fn wrapping_items() {
+        let mut node = TestNode::nil();
+        for item in [5, 200, 250] {
+            node = node.insert(item);
+        }
+        let items = [200, 210, 5, 7];
+
+        let mut acc = ItemFilterAccumulator::new(&items);
+        let range = acc.query_range().unwrap();
+        assert_eq!(range, Range(200, 8));
+        node.query(&range, &mut acc);
+
+        let new: Vec<_> = acc.result().cloned().collect();
+        assert_eq!(new, vec![210, 7]);
+    }
 }
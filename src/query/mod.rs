@@ -1,3 +1,4 @@
+pub mod iblt;
 pub mod item_filter;
 pub mod items;
 pub mod simple;
@@ -2,7 +2,7 @@ extern crate alloc;
 use alloc::{vec, vec::Vec};
 
 use super::Accumulator;
-use crate::{protocol::ProtocolMonoid, range::Range, Node, NonNilNodeRef};
+use crate::{protocol::ProtocolMonoid, range::Range, Item, Node, NonNilNodeRef};
 
 #[derive(Debug, Clone)]
 pub struct SplitAccumulator<'a, M>
@@ -14,6 +14,10 @@ where
     pub(crate) ranges: Vec<Range<M::Item>>,
     current_offset: usize,
     update_ranges: bool,
+    /// The last item added to a bucket, used to find a short boundary to the next bucket.
+    last_item: Option<M::Item>,
+    /// Set if items were added after all buckets were full.
+    overflowed: bool,
 }
 
 impl<'a, M> SplitAccumulator<'a, M>
@@ -27,6 +31,8 @@ where
             ranges: vec![query_range.clone(); split_sizes.len()],
             current_offset: 0,
             update_ranges: false,
+            last_item: None,
+            overflowed: false,
         };
 
         state.advance_bucket();
@@ -39,7 +45,7 @@ where
             && self.split_sizes[self.current_offset] <= self.results[self.current_offset].count()
         {
             // the actual result split should never exceed the target split size
-            assert_eq!(
+            debug_assert_eq!(
                 self.split_sizes[self.current_offset],
                 self.results[self.current_offset].count()
             );
@@ -48,6 +54,21 @@ where
         }
     }
 
+    /// Sets the boundary between the bucket that was just completed and the current one.
+    /// `next_item` is the first item of the current bucket.
+    fn update_boundary(&mut self, next_item: &M::Item) {
+        // any item after the last one of the previous bucket will do. in wrapping queries, the
+        // previous bucket may end with a larger item, then we stick to the next item.
+        let boundary = match &self.last_item {
+            Some(last_item) if last_item < next_item => M::Item::separator(last_item, next_item),
+            _ => next_item.clone(),
+        };
+
+        self.ranges[self.current_offset - 1].1 = boundary.clone();
+        self.ranges[self.current_offset].0 = boundary;
+        self.update_ranges = false;
+    }
+
     fn is_done(&self) -> bool {
         self.current_offset >= self.split_sizes.len()
     }
@@ -71,6 +92,12 @@ where
     pub fn into_results(self) -> Vec<M> {
         self.results
     }
+
+    /// Whether the range held more items than the split sizes add up to. The items that didn't
+    /// fit are not part of any result, so the results and ranges must not be used.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
 }
 impl<'a, M> Accumulator<M> for SplitAccumulator<'a, M>
 where
@@ -83,17 +110,13 @@ where
             return;
         };
 
-        // I think this assertion should always hold if the caller doesn't mess up.
-        assert!(
-            !self.is_done(),
-            "current state: {self:#?}\n  node to be added: {node:#?}",
-        );
+        if self.is_done() {
+            self.overflowed = true;
+            return;
+        }
 
         if self.update_ranges {
-            let next_item = non_nil_node.min();
-            self.ranges[self.current_offset - 1].1 = next_item.clone();
-            self.ranges[self.current_offset].0 = next_item.clone();
-            self.update_ranges = false;
+            self.update_boundary(non_nil_node.min());
         }
 
         let current_split_size = self.current_split_size();
@@ -103,8 +126,10 @@ where
         let node_monoid = node.monoid();
         if node_monoid.count() < space_left {
             *current_result = current_result.combine(&node_monoid);
+            self.last_item = Some(non_nil_node.max().clone());
         } else if node_monoid.count() == space_left {
             *current_result = current_result.combine(&node_monoid);
+            self.last_item = Some(non_nil_node.max().clone());
             self.advance_bucket();
         } else {
             for (child, item) in non_nil_node.children() {
@@ -117,19 +142,18 @@ where
     }
 
     fn add_item(&mut self, item: &M::Item) {
-        assert!(
-            !self.is_done(),
-            "current state: {self:#?}\nitem to be added: {item:#?}"
-        );
+        if self.is_done() {
+            self.overflowed = true;
+            return;
+        }
 
         if self.update_ranges {
-            self.ranges[self.current_offset - 1].1 = item.clone();
-            self.ranges[self.current_offset].0 = item.clone();
-            self.update_ranges = false;
+            self.update_boundary(item);
         }
 
         let current_result = self.current_result();
         *current_result = current_result.combine(&M::lift(item));
+        self.last_item = Some(item.clone());
 
         self.advance_bucket();
     }
@@ -146,8 +170,11 @@ mod test {
     use super::*;
 
     use crate::easy::tests::TestNode;
+    use crate::item::le_byte_array::LEByteArray;
+    use crate::monoid::hashxor::CountingSha256Xor;
     use crate::query::simple::SimpleAccumulator;
-    use crate::Node;
+    use crate::tree::mem_rc::Node;
+    use crate::Node as _;
 
     use proptest::{prelude::*, prop_assert_eq, prop_assume, proptest};
 
@@ -203,5 +230,55 @@ mod test {
 
             prop_assert_eq!((simple1.result(),simple2.result()), (&acc.results()[0], &acc.results()[1]));
         }
+
+        #[test]
+        fn split_boundaries_are_short(items in prop::collection::btree_set(prop::array::uniform4(0u8..=255u8), 4..50usize)) {
+            let mut root = Node::<CountingSha256Xor<LEByteArray<4>>>::nil();
+            for item in &items {
+                root = root.insert(LEByteArray(*item));
+            }
+
+            let query_range = Range(LEByteArray::zero(), LEByteArray::zero());
+            let count = items.len();
+            let split_sizes = &[count / 3, count / 3, count - 2 * (count / 3)];
+            let mut acc = SplitAccumulator::new(&query_range, split_sizes);
+            root.query(&query_range, &mut acc);
+
+            let mut sorted: Vec<_> = items.iter().map(|item| LEByteArray(*item)).collect();
+            sorted.sort();
+
+            let mut offset = 0;
+            for (i, range) in acc.ranges().iter().enumerate() {
+                let mut simple = SimpleAccumulator::new();
+                root.query(range, &mut simple);
+                prop_assert_eq!(simple.result(), &acc.results()[i]);
+
+                // the boundary lies between the buckets, but is never longer than the first item
+                // of the bucket
+                if i > 0 {
+                    let (last, first) = (&sorted[offset - 1], &sorted[offset]);
+                    prop_assert!(last < range.from() && range.from() <= first);
+
+                    let significant = |item: &LEByteArray<4>| 4 - item.0.iter().take_while(|b| **b == 0).count();
+                    prop_assert!(significant(range.from()) <= significant(first));
+                }
+                offset += split_sizes[i];
+            }
+        }
+    }
+
+    #[test]
+    fn reports_overflow() {
+        let mut root = TestNode::nil();
+        for item in 1..=10u64 {
+            root = root.insert(item);
+        }
+
+        let query_range = Range(0, 0);
+        for (split_sizes, overflowed) in [(&[5, 5][..], false), (&[3, 3], true), (&[], true)] {
+            let mut acc = SplitAccumulator::new(&query_range, split_sizes);
+            root.query(&query_range, &mut acc);
+            assert_eq!(acc.overflowed(), overflowed, "{split_sizes:?}");
+        }
     }
 }
@@ -6,6 +6,17 @@ use serde::{Deserialize, Serialize};
 pub struct Range<T: Item>(pub(crate) T, pub(crate) T);
 
 impl<T: Item> Range<T> {
+    /// The items from `from` (inclusive) up to `to` (exclusive). If `to` is not greater than
+    /// `from`, the range wraps around; if both are equal, it contains all items.
+    pub fn new(from: T, to: T) -> Self {
+        Self(from, to)
+    }
+
+    /// The range containing all items.
+    pub fn full() -> Self {
+        Self(T::zero(), T::zero())
+    }
+
     pub fn reverse(&self) -> Self {
         let Self(from, to) = self;
         Self(to.clone(), from.clone())
@@ -49,6 +60,30 @@ impl<T: Item> Range<T> {
         // }
     }
 
+    /// Whether every item in `other` is also in `self`.
+    pub(crate) fn contains_range(&self, other: &Self) -> bool {
+        if self.is_full() {
+            return true;
+        }
+
+        if other.is_full() || !self.contains(other.from()) {
+            return false;
+        }
+
+        if other.to() == self.to() {
+            return true;
+        }
+
+        self.contains(other.to()) && self.position(other.to()) > self.position(other.from())
+    }
+
+    /// The position of `item` when walking the range from its start. In wrapping ranges, the
+    /// items before `from` come last. Item sets list their items in this order.
+    #[inline]
+    pub(crate) fn position<'a>(&self, item: &'a T) -> (bool, &'a T) {
+        (item < self.from(), item)
+    }
+
     #[inline]
     pub(crate) fn partially_contains(&self, min: &T, max: &T) -> bool {
         let node_bounds_around_query_range = min < self.from() && self.to() <= max;
@@ -124,6 +159,17 @@ mod tests {
     use proptest::{prop_assert_eq, proptest};
 
     proptest! {
+        #[test]
+        fn contains_range_correctness(a in (0..20u64, 0..20u64), b in (0..20u64, 0..20u64)) {
+            let outer = Range(b.0, b.1);
+            let inner = Range(a.0, a.1);
+
+            // all endpoints are below 20, so larger items behave like 20
+            let expected = (0..=20).all(|item| !inner.contains(&item) || outer.contains(&item));
+            prop_assert_eq!(outer.contains_range(&inner), expected);
+        }
+
+
         #[test]
         fn serialize_correctness(from in proptest::array::uniform30(0u8..=255u8), to in proptest::array::uniform30(0u8..=255u8)) {
             let from_item = LEByteArray(from);
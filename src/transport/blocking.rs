@@ -0,0 +1,393 @@
+//! Running a session over blocking I/O, such as a [`std::net::TcpStream`].
+
+extern crate alloc;
+extern crate std;
+use alloc::{vec, vec::Vec};
+use std::io::{ErrorKind, Read, Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    protocol::{Features, Format, Hello, ProtocolMonoid, Role, Session, SplitStrategy, WireId},
+    Node, Object, ObjectStore,
+};
+
+use super::{FrameError, Synced, TransportConfig, TransportError, LENGTH_PREFIX_LEN};
+
+/// Writes `value` as a single frame.
+pub fn write_frame<W, T>(
+    writer: &mut W,
+    format: Format,
+    max_frame_size: usize,
+    value: &T,
+) -> Result<(), FrameError>
+where
+    W: Write,
+    T: Serialize,
+{
+    let payload = format.encode(value)?;
+    write_payload(writer, max_frame_size, &payload)
+}
+
+/// Reads a single frame. Returns `None` if the stream ends before the frame starts. The frame is
+/// rejected before reading it if it's larger than `max_frame_size`.
+pub fn read_frame<R, T>(
+    reader: &mut R,
+    format: Format,
+    max_frame_size: usize,
+) -> Result<Option<T>, FrameError>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    match read_payload(reader, max_frame_size)? {
+        Some(payload) => Ok(Some(format.decode(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+/// Writes `payload` as a single frame, as it is.
+fn write_payload<W: Write>(
+    writer: &mut W,
+    max_frame_size: usize,
+    payload: &[u8],
+) -> Result<(), FrameError> {
+    let size = payload.len();
+    let prefix = u32::try_from(size)
+        .ok()
+        .filter(|_| size <= max_frame_size)
+        .ok_or(FrameError::TooLarge {
+            size,
+            max: max_frame_size,
+        })?;
+
+    writer.write_all(&prefix.to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the payload of a single frame, see [`read_frame`].
+fn read_payload<R: Read>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut prefix = [0u8; LENGTH_PREFIX_LEN];
+
+    // read the first byte on its own, so we can tell a closed stream from a truncated frame
+    loop {
+        match reader.read(&mut prefix[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    reader.read_exact(&mut prefix[1..])?;
+
+    let size = u32::from_be_bytes(prefix) as usize;
+    if size > max_frame_size {
+        return Err(FrameError::TooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+
+    let mut payload = vec![0u8; size];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Runs a complete session over `io`, starting with the handshake. The initiator sends the first
+/// message, the responder waits for it.
+///
+/// Once the session is over, everything we sent has been flushed, and the stream can be closed.
+/// Timeouts need to be set on the stream itself; reads and writes that time out fail with
+/// [`TransportError::Timeout`].
+pub fn sync_over<T, M, O, N, S, Sp>(
+    mut io: T,
+    session: &mut Session<M, O, Sp>,
+    root: &N,
+    object_store: &S,
+    config: &TransportConfig,
+) -> Result<Synced<M::Item, O>, TransportError<M>>
+where
+    T: Read + Write,
+    M: ProtocolMonoid + WireId,
+    O: Object<M::Item> + Serialize,
+    N: Node<M>,
+    S: ObjectStore<M::Item, O>,
+    Sp: SplitStrategy<M>,
+    M::Item: Serialize + WireId,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let format = session.limits().format;
+    let max = config.max_frame_size;
+
+    // the responder always answers with its hello, so the initiator can tell what went wrong
+    let hello = Hello::new::<M>().with_format(format);
+    let theirs = match session.role() {
+        Role::Initiator => {
+            send_hello(&mut io, &hello, max)?;
+            recv_hello(&mut io, max)?
+        }
+        Role::Responder => {
+            let theirs = recv_hello(&mut io, max)?;
+            send_hello(&mut io, &hello, max)?;
+            theirs
+        }
+    };
+    let features: Features = hello.check(&theirs)?;
+    features.require(session.required_features())?;
+
+    let mut synced = Synced {
+        features,
+        received: Vec::new(),
+        rejected: Vec::new(),
+        unavailable: Vec::new(),
+    };
+
+    if session.role() == Role::Initiator {
+        let msg = session.start(root)?;
+        send(&mut io, &msg, format, max)?;
+    }
+
+    while !session.is_finished() {
+        let msg = recv(&mut io, format, max)?;
+        let outcome = session.handle(root, object_store, &msg)?;
+        synced
+            .unavailable
+            .extend(outcome.unavailable().iter().cloned());
+
+        let (reply, received) = outcome.into_parts();
+        let (accepted, rejected) = received.into_parts();
+        synced.received.extend(accepted);
+        synced.rejected.extend(rejected);
+
+        if let Some(reply) = reply {
+            send(&mut io, &reply, format, max)?;
+        }
+    }
+
+    Ok(synced)
+}
+
+fn send<W, T, M>(
+    writer: &mut W,
+    value: &T,
+    format: Format,
+    max: usize,
+) -> Result<(), TransportError<M>>
+where
+    W: Write,
+    T: Serialize,
+    M: ProtocolMonoid,
+{
+    write_frame(writer, format, max, value).map_err(lift)
+}
+
+fn recv<R, T, M>(reader: &mut R, format: Format, max: usize) -> Result<T, TransportError<M>>
+where
+    R: Read,
+    T: DeserializeOwned,
+    M: ProtocolMonoid,
+{
+    read_frame(reader, format, max)
+        .map_err(lift)?
+        .ok_or(TransportError::Closed)
+}
+
+/// Hellos are written in their own encoding, not in the format of the session.
+fn send_hello<W, M>(writer: &mut W, hello: &Hello, max: usize) -> Result<(), TransportError<M>>
+where
+    W: Write,
+    M: ProtocolMonoid,
+{
+    write_payload(writer, max, &hello.encode()).map_err(lift)
+}
+
+fn recv_hello<R, M>(reader: &mut R, max: usize) -> Result<Hello, TransportError<M>>
+where
+    R: Read,
+    M: ProtocolMonoid,
+{
+    let payload = read_payload(reader, max)
+        .map_err(lift)?
+        .ok_or(TransportError::Closed)?;
+    Ok(Hello::decode(&payload)?)
+}
+
+/// Tells timeouts apart from other I/O errors.
+fn lift<M: ProtocolMonoid>(err: FrameError) -> TransportError<M> {
+    match err {
+        FrameError::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            TransportError::Timeout
+        }
+        err => TransportError::Frame(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    extern crate std;
+    use alloc::{collections::BTreeMap, vec, vec::Vec};
+    use std::{
+        io::{Cursor, ErrorKind, Read},
+        net::{Shutdown, TcpListener, TcpStream},
+        thread,
+    };
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{split::UniformSplit, Features, Format, ItemSet, Message, Role, Session},
+        range::Range,
+        transport::{FrameError, TransportConfig, TransportError},
+        tree::mem_rc::Node,
+    };
+
+    use super::{read_frame, sync_over, write_frame};
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+    type TestNode = Node<TestMonoid>;
+    type TestMessage = Message<TestMonoid, TestObject>;
+
+    fn setup(items: impl Iterator<Item = u64>) -> (TestNode, BTreeMap<u64, TestObject>) {
+        let mut root = TestNode::nil();
+        let mut object_store = BTreeMap::new();
+        for item in items {
+            root = root.insert(item);
+            object_store.insert(item, (item, true));
+        }
+
+        (root, object_store)
+    }
+
+    /// Hands out at most three bytes per read.
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn msg(i: u64) -> TestMessage {
+        let items = (i * 1000..i * 1000 + 100 * i).collect();
+        let item_sets = vec![ItemSet::new(Range(i * 1000, i * 1000 + 1000), items, true)];
+        Message::new(vec![], item_sets, vec![i], vec![])
+    }
+
+    #[test]
+    fn reads_frames_from_short_reads() {
+        let msgs: Vec<TestMessage> = (0..5).map(msg).collect();
+        let mut encoded = vec![];
+        for msg in &msgs {
+            write_frame(&mut encoded, Format::default(), 1 << 20, msg).unwrap();
+        }
+
+        let mut reader = Trickle(Cursor::new(encoded));
+        let mut decoded: Vec<TestMessage> = vec![];
+        while let Some(msg) = read_frame(&mut reader, Format::default(), 1 << 20).unwrap() {
+            decoded.push(msg);
+        }
+        assert_eq!(decoded, msgs);
+    }
+
+    #[test]
+    fn rejects_large_and_truncated_frames() {
+        let mut encoded = vec![];
+        write_frame(&mut encoded, Format::default(), 1 << 20, &msg(3)).unwrap();
+
+        assert!(matches!(
+            write_frame(&mut vec![], Format::default(), 16, &msg(3)),
+            Err(FrameError::TooLarge { max: 16, .. })
+        ));
+
+        // the length alone is enough to reject it
+        let result: Result<Option<TestMessage>, _> =
+            read_frame(&mut Cursor::new(&encoded[..4]), Format::default(), 16);
+        assert!(matches!(result, Err(FrameError::TooLarge { max: 16, .. })));
+
+        for len in [2, 4, encoded.len() - 1] {
+            let result: Result<Option<TestMessage>, _> = read_frame(
+                &mut Cursor::new(&encoded[..len]),
+                Format::default(),
+                1 << 20,
+            );
+            assert!(matches!(
+                result,
+                Err(FrameError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+
+    #[test]
+    fn syncs_over_tcp() {
+        // enough that even the most compact format sends more than a u16 length can describe
+        const ITEMS: u64 = 20_000;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the trees aren't Send, so each side builds its own
+        let responder = thread::spawn(move || {
+            let (root, store) = setup((0..ITEMS).filter(|i| i % 11 != 0));
+            let (stream, _) = listener.accept().unwrap();
+            let mut session = Session::new(Role::Responder, 3, UniformSplit::<8>);
+            let config = TransportConfig::default();
+            let synced = sync_over(&stream, &mut session, &root, &store, &config).unwrap();
+            stream.shutdown(Shutdown::Both).unwrap();
+            synced.into_received().iter().map(|obj| obj.0).collect()
+        });
+
+        let (root, store) = setup((0..ITEMS).filter(|i| i % 7 != 0));
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut session = Session::new(Role::Initiator, 3, UniformSplit::<8>).with_part_sizes(true);
+        let synced = sync_over(
+            &stream,
+            &mut session,
+            &root,
+            &store,
+            &TransportConfig::default(),
+        )
+        .unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+
+        let mut learned_a: Vec<u64> = synced.received().iter().map(|obj| obj.0).collect();
+        let mut learned_b: Vec<u64> = responder.join().unwrap();
+        learned_a.sort();
+        learned_b.sort();
+        let expected_a: Vec<_> = (0..ITEMS).filter(|i| i % 7 == 0 && i % 11 != 0).collect();
+        let expected_b: Vec<_> = (0..ITEMS).filter(|i| i % 11 == 0 && i % 7 != 0).collect();
+        assert_eq!(learned_a, expected_a);
+        assert_eq!(learned_b, expected_b);
+
+        assert_eq!(synced.features(), Features::SUPPORTED);
+        assert!(session.is_finished());
+        assert!(session.stats().bytes_sent.total() > u16::MAX as usize);
+    }
+
+    #[test]
+    fn reports_closed_connections() {
+        let (root, store) = setup(0..10);
+        let mut session: Session<_, TestObject, _> =
+            Session::new(Role::Responder, 3, UniformSplit::<2>);
+
+        // a stream that ends right away
+        let mut io = Cursor::new(vec![]);
+        let result = sync_over(
+            &mut io,
+            &mut session,
+            &root,
+            &store,
+            &TransportConfig::default(),
+        );
+        assert!(matches!(result, Err(TransportError::Closed)));
+    }
+}
@@ -0,0 +1,173 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::protocol::{Format, Message};
+
+use super::{FrameError, LENGTH_PREFIX_LEN};
+
+/// Splits a byte stream into frames, each holding one value encoded in a [`Format`] and prefixed
+/// with its length as a big-endian `u32`. Frames larger than the maximum frame size are rejected
+/// as soon as their length is read, before buffering them.
+#[derive(Debug)]
+pub struct FrameCodec<T> {
+    format: Format,
+    max_frame_size: usize,
+    _value: core::marker::PhantomData<fn() -> T>,
+}
+
+/// The codec for the messages of a session.
+pub type MessageCodec<M, O> = FrameCodec<Message<M, O>>;
+
+impl<T> FrameCodec<T> {
+    pub fn new(format: Format, max_frame_size: usize) -> Self {
+        Self {
+            format,
+            max_frame_size,
+            _value: core::marker::PhantomData,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Returns a codec for another type of value, with the same settings.
+    pub fn cast<U>(&self) -> FrameCodec<U> {
+        FrameCodec::new(self.format, self.max_frame_size)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder for FrameCodec<T> {
+    type Item = T;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<T>, FrameError> {
+        let Some(prefix) = src.get(..LENGTH_PREFIX_LEN) else {
+            return Ok(None);
+        };
+
+        let size = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        if size > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+
+        let frame_len = LENGTH_PREFIX_LEN + size;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_PREFIX_LEN);
+        let payload = src.split_to(size);
+        Ok(Some(self.format.decode(&payload)?))
+    }
+}
+
+impl<T: Serialize> Encoder<&T> for FrameCodec<T> {
+    type Error = FrameError;
+
+    fn encode(&mut self, value: &T, dst: &mut BytesMut) -> Result<(), FrameError> {
+        let payload: Vec<u8> = self.format.encode(value)?;
+        let size = payload.len();
+        let Some(prefix) = u32::try_from(size)
+            .ok()
+            .filter(|_| size <= self.max_frame_size)
+        else {
+            return Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        };
+
+        dst.reserve(LENGTH_PREFIX_LEN + size);
+        dst.put_u32(prefix);
+        dst.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{vec, vec::Vec};
+
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{Format, ItemSet, Message},
+        range::Range,
+        transport::FrameError,
+    };
+
+    use super::{FrameCodec, MessageCodec};
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+
+    #[test]
+    fn decodes_frames_split_anywhere() {
+        let msgs: Vec<Message<TestMonoid, TestObject>> = (0..5u64)
+            .map(|i| {
+                let items = (i * 1000..i * 1000 + 100 * i).collect();
+                let item_sets = vec![ItemSet::new(Range(i * 1000, i * 1000 + 1000), items, true)];
+                Message::new(vec![], item_sets, vec![i], vec![])
+            })
+            .collect();
+
+        let mut codec: MessageCodec<_, _> = FrameCodec::new(Format::default(), 1 << 20);
+        let mut encoded = BytesMut::new();
+        for msg in &msgs {
+            codec.encode(msg, &mut encoded).unwrap();
+        }
+
+        // feed the stream in pieces of all kinds of sizes
+        let mut decoded = vec![];
+        let mut buf = BytesMut::new();
+        for piece in encoded[..3].chunks(1).chain(encoded[3..].chunks(7)) {
+            buf.extend_from_slice(piece);
+            while let Some(msg) = codec.decode(&mut buf).unwrap() {
+                decoded.push(msg);
+            }
+        }
+
+        assert_eq!(decoded, msgs);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn rejects_large_frames() {
+        let msg: Message<TestMonoid, TestObject> =
+            Message::new(vec![], vec![], (0..100).collect(), vec![]);
+
+        let mut large: MessageCodec<_, _> = FrameCodec::new(Format::default(), 1 << 20);
+        let mut encoded = BytesMut::new();
+        large.encode(&msg, &mut encoded).unwrap();
+
+        let mut small: MessageCodec<TestMonoid, TestObject> =
+            FrameCodec::new(Format::default(), 16);
+        assert!(matches!(
+            small.encode(&msg, &mut BytesMut::new()),
+            Err(FrameError::TooLarge { max: 16, .. })
+        ));
+
+        // the length alone is enough to reject it
+        let mut prefix = BytesMut::from(&encoded[..4]);
+        assert!(matches!(
+            small.decode(&mut prefix),
+            Err(FrameError::TooLarge { max: 16, .. })
+        ));
+    }
+}
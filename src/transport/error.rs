@@ -0,0 +1,111 @@
+extern crate alloc;
+extern crate std;
+use alloc::format;
+
+use crate::protocol::{FormatError, HandshakeError, ProtocolMonoid, SessionError};
+
+/// Why a frame couldn't be read or written.
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    /// The frame is larger than the maximum frame size.
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The contents of the frame failed to encode or decode.
+    Format(FormatError),
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<FormatError> for FrameError {
+    fn from(value: FormatError) -> Self {
+        Self::Format(value)
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            FrameError::Format(e) => Some(e),
+            FrameError::TooLarge { .. } => None,
+        }
+    }
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::Io(e) => f.write_str(&format!("i/o error: {e}")),
+            FrameError::TooLarge { size, max } => f.write_str(&format!(
+                "frame of {size} bytes exceeds the maximum of {max} bytes"
+            )),
+            FrameError::Format(e) => f.write_str(&format!("frame contents: {e}")),
+        }
+    }
+}
+
+/// Why a session over a transport failed.
+#[derive(Debug)]
+pub enum TransportError<M: ProtocolMonoid> {
+    Frame(FrameError),
+    /// Reading or writing a frame took longer than the configured timeout.
+    Timeout,
+    /// The peer closed the connection before the session was over.
+    Closed,
+    /// The peer sent another frame after the session was over.
+    UnexpectedFrame,
+    /// We can't sync with the peer.
+    Handshake(HandshakeError),
+    Session(SessionError<M>),
+}
+
+impl<M: ProtocolMonoid> From<FrameError> for TransportError<M> {
+    fn from(value: FrameError) -> Self {
+        Self::Frame(value)
+    }
+}
+
+impl<M: ProtocolMonoid> From<HandshakeError> for TransportError<M> {
+    fn from(value: HandshakeError) -> Self {
+        Self::Handshake(value)
+    }
+}
+
+impl<M: ProtocolMonoid> From<SessionError<M>> for TransportError<M> {
+    fn from(value: SessionError<M>) -> Self {
+        Self::Session(value)
+    }
+}
+
+impl<M: ProtocolMonoid> std::error::Error for TransportError<M> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Frame(e) => Some(e),
+            TransportError::Handshake(e) => Some(e),
+            TransportError::Session(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl<M: ProtocolMonoid> core::fmt::Display for TransportError<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransportError::Frame(e) => e.fmt(f),
+            TransportError::Timeout => f.write_str("timed out waiting for the peer"),
+            TransportError::Closed => f.write_str("peer closed the connection during the session"),
+            TransportError::UnexpectedFrame => {
+                f.write_str("peer kept sending after the session was over")
+            }
+            TransportError::Handshake(e) => e.fmt(f),
+            TransportError::Session(e) => e.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,367 @@
+extern crate alloc;
+extern crate std;
+use alloc::{vec, vec::Vec};
+use std::io::ErrorKind;
+
+use core::{future::Future, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{
+    protocol::{Features, Hello, ProtocolMonoid, Role, Session, SplitStrategy, WireId},
+    Node, Object, ObjectStore,
+};
+
+use super::{FrameError, MessageCodec, Synced, TransportConfig, TransportError, LENGTH_PREFIX_LEN};
+
+/// Runs a complete session over `io`, starting with the handshake. The initiator sends the first
+/// message, the responder waits for it. Once the session is over, our side of the stream is shut
+/// down, and we wait for the peer to do the same.
+///
+/// If anything goes wrong, the error is returned and `io` is dropped without further ado.
+pub async fn sync_over<T, M, O, N, S, Sp>(
+    mut io: T,
+    session: &mut Session<M, O, Sp>,
+    root: &N,
+    object_store: &S,
+    config: &TransportConfig,
+) -> Result<Synced<M::Item, O>, TransportError<M>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    M: ProtocolMonoid + WireId,
+    O: Object<M::Item> + Serialize,
+    N: Node<M>,
+    S: ObjectStore<M::Item, O>,
+    Sp: SplitStrategy<M>,
+    M::Item: Serialize + WireId,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let features = handshake(&mut io, session, config).await?;
+
+    let codec = MessageCodec::<M, O>::new(session.limits().format, config.max_frame_size);
+    let mut framed = Framed::new(io, codec);
+
+    let mut synced = Synced {
+        features,
+        received: Vec::new(),
+        rejected: Vec::new(),
+        unavailable: Vec::new(),
+    };
+
+    if session.role() == Role::Initiator {
+        let msg = session.start(root)?;
+        send(&mut framed, &msg, config.timeout).await?;
+    }
+
+    while !session.is_finished() {
+        let msg = recv(&mut framed, config.timeout).await?;
+        let outcome = session.handle(root, object_store, &msg)?;
+        synced
+            .unavailable
+            .extend(outcome.unavailable().iter().cloned());
+
+        let (reply, received) = outcome.into_parts();
+        let (accepted, rejected) = received.into_parts();
+        synced.received.extend(accepted);
+        synced.rejected.extend(rejected);
+
+        if let Some(reply) = reply {
+            send(&mut framed, &reply, config.timeout).await?;
+        }
+    }
+
+    // everything has been said. the peer closes its side once it is done with our last message.
+    timeout(config.timeout, framed.close()).await??;
+    match timeout(config.timeout, framed.next()).await? {
+        None => Ok(synced),
+        Some(Ok(_)) => Err(TransportError::UnexpectedFrame),
+        Some(Err(e)) => Err(e.into()),
+    }
+}
+
+/// Exchanges hellos and checks that the peer supports everything the session needs. The
+/// responder always answers with its hello, so the initiator can tell what went wrong.
+///
+/// Hellos are written in their own encoding, not in the format of the session, so this works on
+/// `io` directly and reads exactly the frame of the peer's hello.
+async fn handshake<T, M, O, Sp>(
+    io: &mut T,
+    session: &Session<M, O, Sp>,
+    config: &TransportConfig,
+) -> Result<Features, TransportError<M>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    M: ProtocolMonoid + WireId,
+    O: Object<M::Item> + Serialize,
+    Sp: SplitStrategy<M>,
+    M::Item: Serialize + WireId,
+    M::Encoded: Serialize,
+    for<'de2> M::Item: Deserialize<'de2>,
+    for<'de2> M::Encoded: Deserialize<'de2>,
+    for<'de2> O: Deserialize<'de2>,
+{
+    let hello = Hello::new::<M>().with_format(session.limits().format);
+    let theirs = match session.role() {
+        Role::Initiator => {
+            send_hello(io, &hello, config).await?;
+            recv_hello(io, config).await?
+        }
+        Role::Responder => {
+            let theirs = recv_hello(io, config).await?;
+            send_hello(io, &hello, config).await?;
+            theirs
+        }
+    };
+
+    let features = hello.check(&theirs)?;
+    features.require(session.required_features())?;
+    Ok(features)
+}
+
+async fn send_hello<T, M>(
+    io: &mut T,
+    hello: &Hello,
+    config: &TransportConfig,
+) -> Result<(), TransportError<M>>
+where
+    T: AsyncWrite + Unpin,
+    M: ProtocolMonoid,
+{
+    let payload = hello.encode();
+    let size = payload.len();
+    let max = config.max_frame_size;
+    let prefix = u32::try_from(size)
+        .ok()
+        .filter(|_| size <= max)
+        .ok_or(FrameError::TooLarge { size, max })?;
+
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_LEN + size);
+    frame.extend_from_slice(&prefix.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    timeout(config.timeout, async {
+        io.write_all(&frame).await?;
+        io.flush().await
+    })
+    .await?
+    .map_err(FrameError::from)?;
+    Ok(())
+}
+
+async fn recv_hello<T, M>(io: &mut T, config: &TransportConfig) -> Result<Hello, TransportError<M>>
+where
+    T: AsyncRead + Unpin,
+    M: ProtocolMonoid,
+{
+    let mut prefix = [0u8; LENGTH_PREFIX_LEN];
+    match timeout(config.timeout, io.read_exact(&mut prefix)).await? {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(TransportError::Closed),
+        Err(e) => return Err(FrameError::from(e).into()),
+    }
+
+    let size = u32::from_be_bytes(prefix) as usize;
+    let max = config.max_frame_size;
+    if size > max {
+        return Err(FrameError::TooLarge { size, max }.into());
+    }
+
+    let mut payload = vec![0u8; size];
+    timeout(config.timeout, io.read_exact(&mut payload))
+        .await?
+        .map_err(FrameError::from)?;
+    Ok(Hello::decode(&payload)?)
+}
+
+async fn send<T, C, V, M>(
+    framed: &mut Framed<T, C>,
+    value: &V,
+    limit: Option<Duration>,
+) -> Result<(), TransportError<M>>
+where
+    T: AsyncWrite + Unpin,
+    C: for<'a> Encoder<&'a V, Error = FrameError>,
+    M: ProtocolMonoid,
+{
+    timeout(limit, framed.send(value)).await??;
+    Ok(())
+}
+
+async fn recv<T, C, M>(
+    framed: &mut Framed<T, C>,
+    limit: Option<Duration>,
+) -> Result<C::Item, TransportError<M>>
+where
+    T: AsyncRead + Unpin,
+    C: Decoder<Error = FrameError>,
+    M: ProtocolMonoid,
+{
+    match timeout(limit, framed.next()).await? {
+        Some(value) => Ok(value?),
+        None => Err(TransportError::Closed),
+    }
+}
+
+/// Waits for `future`, for at most `limit`.
+async fn timeout<F, R, M>(limit: Option<Duration>, future: F) -> Result<R, TransportError<M>>
+where
+    F: Future<Output = R>,
+    M: ProtocolMonoid,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future)
+            .await
+            .map_err(|_| TransportError::Timeout),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::{collections::BTreeMap, vec::Vec};
+
+    use core::time::Duration;
+
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use crate::{
+        easy::tests::{TestItem, TestObject},
+        monoid::hashxor::CountingSha256Xor,
+        protocol::{
+            split::UniformSplit, Features, HandshakeError, Hello, MessageLimits, Role, Session,
+        },
+        transport::{FrameError, TransportConfig, TransportError},
+        tree::mem_rc::Node,
+    };
+
+    use super::sync_over;
+
+    type TestMonoid = CountingSha256Xor<TestItem>;
+    type TestNode = Node<TestMonoid>;
+
+    fn setup(items: impl Iterator<Item = u64>) -> (TestNode, BTreeMap<u64, TestObject>) {
+        let mut root = TestNode::nil();
+        let mut object_store = BTreeMap::new();
+        for item in items {
+            root = root.insert(item);
+            object_store.insert(item, (item, true));
+        }
+
+        (root, object_store)
+    }
+
+    fn config(timeout_ms: u64) -> TransportConfig {
+        TransportConfig {
+            timeout: Some(Duration::from_millis(timeout_ms)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn syncs_over_duplex() {
+        // enough that even the most compact format sends more than a u16 length can describe
+        const ITEMS: u64 = 20_000;
+
+        let (root_a, store_a) = setup((0..ITEMS).filter(|i| i % 7 != 0));
+        let (root_b, store_b) = setup((0..ITEMS).filter(|i| i % 11 != 0));
+
+        // the messages are a lot larger than the buffer
+        let (io_a, io_b) = duplex(1024);
+        let mut session_a =
+            Session::new(Role::Initiator, 3, UniformSplit::<8>).with_part_sizes(true);
+        let mut session_b = Session::new(Role::Responder, 3, UniformSplit::<8>);
+
+        let config = config(1000);
+        let (synced_a, synced_b) = tokio::join!(
+            sync_over(io_a, &mut session_a, &root_a, &store_a, &config),
+            sync_over(io_b, &mut session_b, &root_b, &store_b, &config),
+        );
+        let (synced_a, synced_b) = (synced_a.unwrap(), synced_b.unwrap());
+
+        let mut learned_a: Vec<_> = synced_a.received().iter().map(|obj| obj.0).collect();
+        let mut learned_b: Vec<_> = synced_b.received().iter().map(|obj| obj.0).collect();
+        learned_a.sort();
+        learned_b.sort();
+        let expected_a: Vec<_> = (0..ITEMS).filter(|i| i % 7 == 0 && i % 11 != 0).collect();
+        let expected_b: Vec<_> = (0..ITEMS).filter(|i| i % 11 == 0 && i % 7 != 0).collect();
+        assert_eq!(learned_a, expected_a);
+        assert_eq!(learned_b, expected_b);
+
+        assert_eq!(synced_a.features(), Features::SUPPORTED);
+        assert!(synced_a.rejected().is_empty());
+        assert!(session_a.is_finished() && session_b.is_finished());
+        assert!(session_a.stats().bytes_sent.total() > u16::MAX as usize);
+    }
+
+    #[tokio::test]
+    async fn fails_handshake() {
+        let (root, store) = setup(0..10);
+        let (io_a, io_b) = duplex(1024);
+
+        // we need chunking, the peer doesn't support anything
+        let limits = MessageLimits {
+            max_bytes: 1000,
+            ..Default::default()
+        };
+        let mut session: Session<_, TestObject, _> =
+            Session::new(Role::Initiator, 3, UniformSplit::<2>).with_limits(limits);
+        let peer = async move {
+            let mut io_b = io_b;
+            let hello = Hello::new::<TestMonoid>().with_features(Features::NONE);
+            let payload = hello.encode();
+            let prefix = (payload.len() as u32).to_be_bytes();
+            io_b.write_all(&prefix).await.unwrap();
+            io_b.write_all(&payload).await.unwrap();
+            io_b
+        };
+
+        let config = config(1000);
+        let (result, _peer) =
+            tokio::join!(sync_over(io_a, &mut session, &root, &store, &config), peer);
+        assert!(matches!(
+            result,
+            Err(TransportError::Handshake(HandshakeError::MissingFeatures(
+                Features::CHUNKING
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn times_out_on_silent_peer() {
+        let (root, store) = setup(0..10);
+        let (io_a, _io_b) = duplex(1024);
+
+        let mut session: Session<_, TestObject, _> =
+            Session::new(Role::Responder, 3, UniformSplit::<2>);
+        let result = sync_over(io_a, &mut session, &root, &store, &config(50)).await;
+        assert!(matches!(result, Err(TransportError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn reports_closed_connections_and_large_frames() {
+        let (root, store) = setup(0..10);
+
+        let (io_a, io_b) = duplex(1024);
+        drop(io_b);
+        let mut session: Session<_, TestObject, _> =
+            Session::new(Role::Responder, 3, UniformSplit::<2>);
+        let result = sync_over(io_a, &mut session, &root, &store, &config(1000)).await;
+        assert!(matches!(result, Err(TransportError::Closed)));
+
+        let (io_a, mut io_b) = duplex(1024);
+        io_b.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let mut session: Session<_, TestObject, _> =
+            Session::new(Role::Responder, 3, UniformSplit::<2>);
+        let result = sync_over(io_a, &mut session, &root, &store, &config(1000)).await;
+        assert!(matches!(
+            result,
+            Err(TransportError::Frame(FrameError::TooLarge { .. }))
+        ));
+    }
+}
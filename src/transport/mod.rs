@@ -0,0 +1,98 @@
+//! Running a [`Session`](crate::protocol::Session) over a byte stream, e.g. a TCP connection.
+//!
+//! Both peers first exchange a [`Hello`](crate::protocol::Hello) to check they can sync, then the
+//! messages of the session. Every hello and message is sent as a frame: its length as a
+//! big-endian `u32`, followed by the hello in its fixed encoding, or the message encoded in the
+//! [`Format`](crate::protocol::Format) of the session's
+//! [`MessageLimits`](crate::protocol::MessageLimits). Frames larger than the
+//! configured maximum are rejected, so make sure the session's limits keep messages below it.
+//!
+//! The transport only reads from the tree and the object store. The objects received from the
+//! peer are returned once the session is over, and need to be added by the caller.
+//!
+//! [`blocking`] works with any [`std::io::Read`] and [`std::io::Write`]. With the `tokio` feature,
+//! `sync_over` does the same for tokio's `AsyncRead` and `AsyncWrite`.
+
+extern crate alloc;
+extern crate std;
+use alloc::vec::Vec;
+
+use core::time::Duration;
+
+use crate::protocol::{Features, Rejected};
+
+pub mod blocking;
+
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(feature = "tokio")]
+pub use codec::{FrameCodec, MessageCodec};
+
+pub mod error;
+pub use error::{FrameError, TransportError};
+
+#[cfg(feature = "tokio")]
+pub mod framed;
+#[cfg(feature = "tokio")]
+pub use framed::sync_over;
+
+/// The length of the prefix holding the size of a frame.
+pub const LENGTH_PREFIX_LEN: usize = 4;
+
+/// The largest frame accepted by default, 16 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// How a session is run over a byte stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// The largest frame we send or accept, not counting the length prefix.
+    pub max_frame_size: usize,
+    /// How long we wait for a frame to be read or written. `None` waits forever. Blocking streams
+    /// can't be interrupted, so [`blocking`] ignores this; set the timeouts on the stream instead,
+    /// e.g. with [`std::net::TcpStream::set_read_timeout`].
+    pub timeout: Option<Duration>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// What we got out of a session that ran to the end.
+#[derive(Debug, Clone)]
+pub struct Synced<I, O> {
+    features: Features,
+    received: Vec<O>,
+    rejected: Vec<Rejected<O>>,
+    unavailable: Vec<I>,
+}
+
+impl<I, O> Synced<I, O> {
+    /// The features both peers support.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// The objects the peer sent us that passed validation and can be added to the store.
+    pub fn received(&self) -> &[O] {
+        &self.received
+    }
+
+    /// The objects the peer sent us that failed validation.
+    pub fn rejected(&self) -> &[Rejected<O>] {
+        &self.rejected
+    }
+
+    /// Items we requested, but that the peer doesn't have.
+    pub fn unavailable(&self) -> &[I] {
+        &self.unavailable
+    }
+
+    pub fn into_received(self) -> Vec<O> {
+        self.received
+    }
+}
@@ -0,0 +1,164 @@
+//! Looking at the shape of a tree, for debugging and tuning.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::{Monoid, Node, NonNilNodeRef, Range};
+
+/// Counts describing the shape of a tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Shape {
+    /// The number of levels of nodes. All leaves are at the same depth.
+    pub height: usize,
+    /// Nodes holding one item.
+    pub node2s: usize,
+    /// Nodes holding two items.
+    pub node3s: usize,
+    /// Nodes without children.
+    pub leaves: usize,
+    pub items: usize,
+}
+
+impl Shape {
+    /// Measures the tree below `root`.
+    pub fn of<M: Monoid, N: Node<M>>(root: &N) -> Self {
+        let mut shape = Shape::default();
+        shape.visit(root, 1);
+        shape
+    }
+
+    fn visit<M: Monoid, N: Node<M>>(&mut self, node: &N, depth: usize) {
+        let Some(contents) = node.node_contents() else {
+            return;
+        };
+
+        self.height = self.height.max(depth);
+        let mut items = 0;
+        let mut is_leaf = true;
+        for (child, _) in contents.children() {
+            is_leaf &= child.is_nil();
+            self.visit(child, depth + 1);
+            items += 1;
+        }
+        self.visit(contents.last_child(), depth + 1);
+
+        self.items += items;
+        match items {
+            1 => self.node2s += 1,
+            _ => self.node3s += 1,
+        }
+        if is_leaf && contents.last_child().is_nil() {
+            self.leaves += 1;
+        }
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.node2s + self.node3s
+    }
+
+    /// The share of item slots in use. Every node has room for two items.
+    pub fn fill_ratio(&self) -> f64 {
+        if self.nodes() == 0 {
+            return 0.0;
+        }
+
+        self.items as f64 / (2 * self.nodes()) as f64
+    }
+}
+
+/// A subtree, as listed by [`subtrees`].
+#[derive(Debug, Clone)]
+pub struct Subtree<M: Monoid> {
+    /// How far below the root the subtree starts, the root has depth 0.
+    pub depth: usize,
+    pub min: M::Item,
+    pub max: M::Item,
+    /// The monoid of all items in the subtree.
+    pub monoid: M,
+}
+
+/// Lists the subtrees that hold items in `range`, down to `max_depth`, in pre-order.
+pub fn subtrees<M, N>(root: &N, range: &Range<M::Item>, max_depth: usize) -> Vec<Subtree<M>>
+where
+    M: Monoid,
+    N: Node<M>,
+{
+    let mut out = Vec::new();
+    collect(root, range, 0, max_depth, &mut out);
+    out
+}
+
+fn collect<M, N>(
+    node: &N,
+    range: &Range<M::Item>,
+    depth: usize,
+    max_depth: usize,
+    out: &mut Vec<Subtree<M>>,
+) where
+    M: Monoid,
+    N: Node<M>,
+{
+    let Some(contents) = node.node_contents() else {
+        return;
+    };
+
+    let (min, max) = contents.bounds();
+    if !range.partially_contains(min, max) {
+        return;
+    }
+
+    out.push(Subtree {
+        depth,
+        min: min.clone(),
+        max: max.clone(),
+        monoid: node.monoid().clone(),
+    });
+
+    if depth < max_depth {
+        for (child, _) in contents.children() {
+            collect(child, range, depth + 1, max_depth, out);
+        }
+        collect(contents.last_child(), range, depth + 1, max_depth, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::easy::tests::{TestMonoid, TestNode};
+
+    use proptest::{prop_assert, prop_assert_eq, proptest};
+
+    proptest! {
+        #[test]
+        fn shape_matches_items(items in proptest::collection::btree_set(0..1000u64, 0..300)) {
+            let mut root = TestNode::nil();
+            for item in &items {
+                root = root.insert(*item);
+            }
+
+            let shape = Shape::of(&root);
+            prop_assert_eq!(shape.items, items.len());
+            prop_assert!(shape.leaves <= shape.nodes());
+            if !items.is_empty() {
+                // a 2-3 tree is at least as high as one holding two items everywhere
+                prop_assert!(3usize.pow(shape.height as u32) > items.len());
+                prop_assert!(shape.fill_ratio() >= 0.5 && shape.fill_ratio() <= 1.0);
+            }
+
+            // the root covers everything
+            let full = subtrees::<TestMonoid, _>(&root, &Range(0, 0), 0);
+            prop_assert_eq!(full.len(), usize::from(!items.is_empty()));
+            if let Some(top) = full.first() {
+                prop_assert_eq!(&top.monoid, root.monoid());
+            }
+
+            // every subtree listed holds items in the range
+            let range = Range(250, 750);
+            for subtree in subtrees::<TestMonoid, _>(&root, &range, usize::MAX) {
+                prop_assert!(&subtree.min < range.to() && &subtree.max >= range.from());
+            }
+        }
+    }
+}
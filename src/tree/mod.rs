@@ -1,3 +1,5 @@
+pub mod inspect;
+pub mod mem_arc;
 pub mod mem_rc;
 pub mod mem_rc_bounds;
 
@@ -63,8 +65,14 @@ where
         //   they are annoying and doing it this way means we only need to take care of them once.
 
         if max >= range.from() {
-            let high_range = Range(range.from().clone(), max.next());
-            query(root, &high_range, state);
+            // if max is the largest item, there is no item after it to end the range with
+            let end = max.next();
+            if &end > max {
+                let high_range = Range(range.from().clone(), end);
+                query(root, &high_range, state);
+            } else {
+                query_tail(root, range.from(), state);
+            }
         }
 
         if min < range.to() {
@@ -90,6 +98,37 @@ where
     query(node.last_child(), range, state);
 }
 
+/// Adds all items from `from` on, up to and including the largest one.
+fn query_tail<M, N, A>(root: &N, from: &M::Item, state: &mut A)
+where
+    M: Monoid,
+    N: Node<M>,
+    A: Accumulator<M>,
+{
+    let Some(node) = root.node_contents() else {
+        return;
+    };
+
+    let (min, max) = node.bounds();
+    if max < from {
+        return;
+    }
+
+    if min >= from {
+        state.add_node(root);
+        return;
+    }
+
+    for (child, item) in node.children() {
+        query_tail(child, from, state);
+        if item >= from {
+            state.add_item(item);
+        }
+    }
+
+    query_tail(node.last_child(), from, state);
+}
+
 pub trait NonNilNodeRef<'a, M, N>: core::fmt::Debug + Clone
 where
     M: Monoid,
@@ -107,3 +146,38 @@ where
     fn children<'b>(&'b self) -> Self::ChildIter<'b>;
     fn last_child<'b>(&'b self) -> &'b N;
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    use crate::{monoid::hashxor::CountingSha256Xor, query::items::ItemsAccumulator};
+
+    use proptest::{prop_assert_eq, proptest};
+
+    proptest! {
+        #[test]
+        fn wrapping_queries_include_largest_item(items in proptest::collection::btree_set(200..=255u8, 1..40), from in 200..=255u8, to in 200..=255u8) {
+            let mut root = mem_rc::Node::<CountingSha256Xor<u8>>::nil();
+            for item in &items {
+                root = root.insert(*item);
+            }
+
+            let range = Range(from, to);
+            let mut acc = ItemsAccumulator::new();
+            root.query(&range, &mut acc);
+
+            // in query order, so the items before the wrap come first
+            let (high, low): (Vec<u8>, Vec<u8>) = items.iter().partition(|item| **item >= from);
+            let expected: Vec<u8> = high
+                .into_iter()
+                .chain(low)
+                .filter(|item| range.contains(item))
+                .collect();
+            prop_assert_eq!(acc.into_results(), expected);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! Feeds hostile input to the receiving end of the protocol. A peer controls every byte we decode
+//! and every field of the messages we respond to, so none of this may panic. Errors are fine.
+//!
+//! Messages are built in three ways: from arbitrary bytes, by mutating the messages of a real
+//! session, and from arbitrary values for each field. The latter go through [`RawMessage`], which
+//! mirrors the serialized layout of [`Message`] without enforcing anything.
+
+extern crate alloc;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+extern crate std;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use unionize::{
+    easy,
+    easy::uniform::{Item as UniformItem, Monoid as UniformMonoid, Node as UniformNode},
+    monoid::hashxor::CountingSha256Xor,
+    protocol::{
+        compact, decode_message, encode_message, split::UniformSplit, Direction, Format, Message,
+        MessageLimits, Role, Session,
+    },
+    tree::mem_rc::Node,
+    Range,
+};
+
+use proptest::{collection::vec as arb_vec, prelude::*};
+
+type TestMonoid = CountingSha256Xor<u64>;
+type TestObject = (u64, bool);
+type TestMessage = Message<TestMonoid, TestObject>;
+type TestNode = Node<TestMonoid>;
+type Store = BTreeMap<u64, TestObject>;
+type SplitFn = fn(usize) -> Vec<usize>;
+
+#[derive(Debug, Clone, Serialize)]
+struct RawFingerprint {
+    range: Range<u64>,
+    fp: (usize, [u8; 32], ()),
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RawItemSet {
+    range: Range<u64>,
+    items: Vec<u64>,
+    want_response: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RawShortItemSet {
+    range: Range<u64>,
+    ids: Vec<u64>,
+    items: Vec<u64>,
+    want_response: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RawShortIdRequest {
+    range: Range<u64>,
+    ids: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RawCell {
+    count: i64,
+    key_sum: u64,
+    hash_sum: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RawIblt {
+    cells: Vec<RawCell>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RawSketch {
+    range: Range<u64>,
+    iblt: RawIblt,
+}
+
+/// Serializes like a [`Message`], but every field can be set to anything.
+#[derive(Debug, Clone, Serialize)]
+struct RawMessage {
+    fps: Vec<RawFingerprint>,
+    item_sets: Vec<RawItemSet>,
+    wants: Vec<u64>,
+    provide: Vec<TestObject>,
+    not_available: Vec<u64>,
+    more: bool,
+    direction: Direction,
+    short_id_len: u8,
+    short_item_sets: Vec<RawShortItemSet>,
+    short_id_requests: Vec<RawShortIdRequest>,
+    sketch_cells: u32,
+    sketches: Vec<RawSketch>,
+    short_id_key: (u64, u64),
+}
+
+impl RawMessage {
+    fn to_message(&self) -> TestMessage {
+        let format = Format::default();
+        let bytes = format.encode(self).unwrap();
+        decode_message(format, &bytes).expect("RawMessage doesn't match Message")
+    }
+}
+
+/// Mostly items near the ones in the tree, plus the extremes, where ranges wrap and `next`
+/// overflows.
+fn arb_item() -> impl Strategy<Value = u64> {
+    prop_oneof![
+        4 => 0..64u64,
+        1 => Just(0),
+        1 => Just(u64::MAX),
+        1 => Just(u64::MAX - 1),
+        1 => any::<u64>(),
+    ]
+}
+
+fn arb_range() -> impl Strategy<Value = Range<u64>> {
+    prop_oneof![
+        3 => (arb_item(), arb_item()).prop_map(|(from, to)| Range::new(from, to)),
+        1 => arb_item().prop_map(|item| Range::new(item, item)),
+    ]
+}
+
+fn arb_count() -> impl Strategy<Value = usize> {
+    prop_oneof![0..64usize, Just(usize::MAX), any::<usize>()]
+}
+
+fn arb_direction() -> impl Strategy<Value = Direction> {
+    prop_oneof![
+        Just(Direction::Both),
+        Just(Direction::Pull),
+        Just(Direction::Push)
+    ]
+}
+
+prop_compose! {
+    fn arb_fingerprint()
+        (range in arb_range(), count in arb_count(), hash in any::<[u8; 32]>()) -> RawFingerprint {
+        RawFingerprint { range, fp: (count, hash, ()) }
+    }
+}
+
+prop_compose! {
+    fn arb_item_set()
+        (range in arb_range(), items in arb_vec(arb_item(), 0..8), want_response in any::<bool>()) -> RawItemSet {
+        RawItemSet { range, items, want_response }
+    }
+}
+
+prop_compose! {
+    fn arb_short_item_set()
+        (range in arb_range(), ids in arb_vec(any::<u64>(), 0..8), items in arb_vec(arb_item(), 0..4), want_response in any::<bool>()) -> RawShortItemSet {
+        RawShortItemSet { range, ids, items, want_response }
+    }
+}
+
+prop_compose! {
+    fn arb_short_id_request()
+        (range in arb_range(), ids in arb_vec(any::<u64>(), 0..8)) -> RawShortIdRequest {
+        RawShortIdRequest { range, ids }
+    }
+}
+
+fn arb_cell() -> impl Strategy<Value = RawCell> {
+    let count = prop_oneof![-2..=2i64, Just(i64::MAX), Just(i64::MIN), any::<i64>()];
+    (count, any::<u64>(), any::<u64>()).prop_map(|(count, key_sum, hash_sum)| RawCell {
+        count,
+        key_sum,
+        hash_sum,
+    })
+}
+
+prop_compose! {
+    fn arb_sketch()
+        (range in arb_range(), cells in arb_vec(arb_cell(), 0..13)) -> RawSketch {
+        RawSketch { range, iblt: RawIblt { cells } }
+    }
+}
+
+prop_compose! {
+    fn arb_raw_message()
+        (
+            fps in arb_vec(arb_fingerprint(), 0..6),
+            item_sets in arb_vec(arb_item_set(), 0..6),
+            wants in arb_vec(arb_item(), 0..6),
+            provide in arb_vec((arb_item(), any::<bool>()), 0..4),
+            not_available in arb_vec(arb_item(), 0..4),
+            more in any::<bool>(),
+            direction in arb_direction(),
+            short_id_len in prop_oneof![0..=9u8, any::<u8>()],
+            short_item_sets in arb_vec(arb_short_item_set(), 0..3),
+            short_id_requests in arb_vec(arb_short_id_request(), 0..3),
+            sketch_cells in prop_oneof![0..64u32, any::<u32>()],
+            sketches in arb_vec(arb_sketch(), 0..3),
+            short_id_key in any::<(u64, u64)>(),
+        ) -> RawMessage {
+        RawMessage {
+            fps,
+            item_sets,
+            wants,
+            provide,
+            not_available,
+            more,
+            direction,
+            short_id_len,
+            short_item_sets,
+            short_id_requests,
+            sketch_cells,
+            sketches,
+            short_id_key,
+        }
+    }
+}
+
+/// A tree whose items are spread over the small items and the extremes.
+fn test_tree() -> (TestNode, Store) {
+    let mut root = TestNode::nil();
+    let mut store = BTreeMap::new();
+    for item in (1..48).step_by(3).chain([0, u64::MAX - 1, u64::MAX]) {
+        root = root.insert(item);
+        store.insert(item, (item, true));
+    }
+    (root, store)
+}
+
+/// The ways we configure a session, each of which takes a different path through the code.
+fn sessions() -> Vec<Session<TestMonoid, TestObject, SplitFn>> {
+    let uniform = || easy::uniform::split::<2> as SplitFn;
+    let adaptive = || easy::timestamped::split_dynamic::<4> as SplitFn;
+    let limits = MessageLimits {
+        max_bytes: 300,
+        max_objects: 2,
+        ..Default::default()
+    };
+
+    vec![
+        Session::new(Role::Responder, 3, uniform()),
+        Session::new(Role::Responder, 0, adaptive()),
+        Session::new(Role::Responder, 1, uniform()).with_range(Range::new(8, u64::MAX - 1)),
+        Session::new(Role::Responder, 2, uniform()).with_range(Range::new(40, 10)),
+        Session::new(Role::Responder, 3, adaptive()).with_adaptive_tuning(true),
+        Session::new(Role::Responder, 3, uniform()).with_limits(limits),
+        Session::new(Role::Initiator, 3, uniform())
+            .with_short_ids(4)
+            .with_sketches(12),
+        Session::new(Role::Initiator, 3, uniform()).with_direction(Direction::Pull),
+    ]
+}
+
+/// Hands `msg` to every kind of session, twice, and to `respond_to_message` directly.
+fn respond_to(msg: &TestMessage) {
+    let (root, store) = test_tree();
+    for mut session in sessions() {
+        if session.role() == Role::Initiator {
+            session.start(&root).unwrap();
+        }
+        for _ in 0..2 {
+            if session.handle(&root, &store, msg).is_err() {
+                break;
+            }
+        }
+    }
+
+    let requested = msg.provide().iter().map(|(item, _)| *item).collect();
+    for threshold in [0, 1, 4] {
+        let _ = unionize::protocol::respond_to_message(
+            &root,
+            &store,
+            msg,
+            &requested,
+            &Range::new(0, 0),
+            threshold,
+            &UniformSplit::<3>,
+            1,
+        );
+    }
+}
+
+/// Decodes `bytes` in every format we know, and responds to whatever comes out.
+fn decode_and_respond(bytes: &[u8]) {
+    for &format in Format::ALL {
+        if let Ok(msg) = decode_message::<TestMonoid, TestObject>(format, bytes) {
+            respond_to(&msg);
+        }
+        if let Ok(msg) = compact::decode::<TestMonoid, TestObject>(format, bytes) {
+            respond_to(&msg);
+        }
+
+        // points that are not on the curve only show up here
+        let root = UniformNode::nil().insert(UniformItem::default());
+        let store: BTreeMap<UniformItem, (UniformItem, bool)> = BTreeMap::new();
+        let decoded = [
+            decode_message::<UniformMonoid, (UniformItem, bool)>(format, bytes).ok(),
+            compact::decode(format, bytes).ok(),
+        ];
+        for msg in decoded.into_iter().flatten() {
+            let mut session = Session::new(Role::Responder, 3, UniformSplit::<2>);
+            let _ = session.handle(&root, &store, &msg);
+        }
+    }
+}
+
+/// The encoded messages of a session between two trees with a few differences, in both formats.
+fn session_transcript() -> &'static [Vec<u8>] {
+    static TRANSCRIPT: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+    TRANSCRIPT.get_or_init(record_session)
+}
+
+fn record_session() -> Vec<Vec<u8>> {
+    let mut roots = [TestNode::nil(), TestNode::nil()];
+    let mut stores = [Store::new(), Store::new()];
+    for item in 0..200u64 {
+        for side in 0..2 {
+            if item % 7 != side as u64 * 3 {
+                roots[side] = roots[side].insert(item * 1000);
+                stores[side].insert(item * 1000, (item * 1000, true));
+            }
+        }
+    }
+
+    let mut sessions = [
+        Session::new(Role::Initiator, 3, UniformSplit::<2>).with_short_ids(4),
+        Session::new(Role::Responder, 3, UniformSplit::<2>),
+    ];
+    let mut out = vec![];
+    let mut msg: TestMessage = sessions[0].start(&roots[0]).unwrap();
+    for side in [1, 0].into_iter().cycle() {
+        out.push(encode_message(Format::default(), &msg).unwrap());
+        out.push(compact::encode(Format::default(), &msg).unwrap());
+        match sessions[side]
+            .handle(&roots[side], &stores[side], &msg)
+            .unwrap()
+            .into_parts()
+        {
+            (Some(reply), _) => msg = reply,
+            (None, _) => break,
+        }
+        if sessions[side].is_finished() {
+            break;
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+enum Mutation {
+    Flip(usize, u8),
+    Insert(usize, u8),
+    Remove(usize),
+    Truncate(usize),
+}
+
+fn arb_mutation() -> impl Strategy<Value = Mutation> {
+    prop_oneof![
+        4 => (any::<usize>(), 1..=255u8).prop_map(|(at, bits)| Mutation::Flip(at, bits)),
+        1 => (any::<usize>(), any::<u8>()).prop_map(|(at, byte)| Mutation::Insert(at, byte)),
+        1 => any::<usize>().prop_map(Mutation::Remove),
+        1 => any::<usize>().prop_map(Mutation::Truncate),
+    ]
+}
+
+fn mutate(bytes: &mut Vec<u8>, mutation: &Mutation) {
+    if bytes.is_empty() {
+        return;
+    }
+    let len = bytes.len();
+    match *mutation {
+        Mutation::Flip(at, bits) => bytes[at % len] ^= bits,
+        Mutation::Insert(at, byte) => bytes.insert(at % (len + 1), byte),
+        Mutation::Remove(at) => {
+            bytes.remove(at % len);
+        }
+        Mutation::Truncate(at) => bytes.truncate(at % len),
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn arbitrary_bytes(bytes in arb_vec(any::<u8>(), 0..256)) {
+        decode_and_respond(&bytes);
+    }
+
+    #[test]
+    fn mutated_messages(index in any::<prop::sample::Index>(), mutations in arb_vec(arb_mutation(), 1..4)) {
+        let transcript = session_transcript();
+        let mut bytes = index.get(transcript).clone();
+        for mutation in &mutations {
+            mutate(&mut bytes, mutation);
+        }
+        decode_and_respond(&bytes);
+    }
+
+    #[test]
+    fn arbitrary_messages(raw in arb_raw_message()) {
+        let msg = raw.to_message();
+        respond_to(&msg);
+
+        // and the same in the compact format, as far as it can express it
+        if let Ok(bytes) = compact::encode(Format::default(), &msg) {
+            decode_and_respond(&bytes);
+        }
+    }
+}
@@ -2,16 +2,43 @@ extern crate alloc;
 use alloc::{vec, vec::Vec};
 
 extern crate std;
-use std::{collections::BTreeMap, io::Write, print, println};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    print, println,
+};
 
 use unionize::{
-    easy::uniform::{split as uniform_split, Item as UniformItem, Node as UniformNode},
-    protocol::{first_message, respond_to_message, Message},
+    easy::uniform::{Item as UniformItem, Monoid as UniformMonoid, Node as UniformNode},
+    protocol::{
+        compact, encode_message, first_message, respond_to_message,
+        split::{AdaptiveSplit, ExponentialSplit, UniformSplit},
+        Direction, Format, Message, MessageLimits, Role, Session, ShortIdKey, SplitStrategy,
+        SyncStats,
+    },
+    Range,
 };
 
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 
+/// The total size of the messages of a sync in both wire formats.
+#[derive(Debug, Default)]
+struct WireSizes {
+    cbor: usize,
+    compact: usize,
+}
+
+impl WireSizes {
+    fn add(&mut self, msg: &Message<UniformMonoid, (UniformItem, bool)>) {
+        self.cbor += serde_cbor::to_vec(msg).unwrap().len();
+
+        let encoded = compact::encode(Format::default(), msg).unwrap();
+        assert_eq!(&compact::decode(Format::default(), &encoded).unwrap(), msg);
+        self.compact += encoded.len();
+    }
+}
+
 #[test]
 fn sync_10k_msgs() {
     let mut shared_msgs = vec![UniformItem::default(); 6_000];
@@ -62,8 +89,11 @@ fn sync_10k_msgs() {
 
     let mut missing_items_alice = vec![];
     let mut missing_items_bob = vec![];
+    let mut requested_alice = BTreeSet::new();
+    let mut requested_bob = BTreeSet::new();
 
     let mut count = 0;
+    let mut wire_sizes = WireSizes::default();
 
     let loop_start_time = std::time::Instant::now();
     loop {
@@ -74,13 +104,25 @@ fn sync_10k_msgs() {
             msg.fingerprints().len(),
             msg.item_sets().len()
         );
+        wire_sizes.add(&msg);
         if msg.is_end() {
             break;
         }
 
-        let (resp, new_objects) =
-            respond_to_message(&bob_tree, &bob_object_store, &msg, 3, uniform_split::<2>).unwrap();
-        missing_items_bob.extend(new_objects.into_iter().map(|(item, _)| item));
+        let (resp, received) = respond_to_message(
+            &bob_tree,
+            &bob_object_store,
+            &msg,
+            &requested_bob,
+            &Range::full(),
+            3,
+            &UniformSplit::<2>,
+            2 * count - 1,
+        )
+        .unwrap();
+        assert!(received.rejected().is_empty());
+        missing_items_bob.extend(received.into_accepted().into_iter().map(|(item, _)| item));
+        requested_bob.extend(resp.wants().iter().cloned());
 
         // println!("bob msg:   {resp:?}");
         println!(
@@ -88,19 +130,25 @@ fn sync_10k_msgs() {
             resp.fingerprints().len(),
             resp.item_sets().len()
         );
+        wire_sizes.add(&resp);
         if resp.is_end() {
             break;
         }
 
-        let (resp, new_items) = respond_to_message(
+        let (resp, received) = respond_to_message(
             &alice_tree,
             &alice_object_store,
             &resp,
+            &requested_alice,
+            &Range::full(),
             3,
-            uniform_split::<2>,
+            &UniformSplit::<2>,
+            2 * count,
         )
         .unwrap();
-        missing_items_alice.extend(new_items.into_iter().map(|(item, _)| item));
+        assert!(received.rejected().is_empty());
+        missing_items_alice.extend(received.into_accepted().into_iter().map(|(item, _)| item));
+        requested_alice.extend(resp.wants().iter().cloned());
 
         msg = resp;
     }
@@ -110,6 +158,13 @@ fn sync_10k_msgs() {
         loop_start_time.elapsed()
     );
 
+    let WireSizes { cbor, compact } = wire_sizes;
+    println!(
+        "sent {cbor} bytes as cbor, {compact} bytes in the compact format ({}%).",
+        compact * 100 / cbor
+    );
+    assert!(compact < cbor);
+
     println!("alice: # missing items: {}", missing_items_alice.len());
     println!("bob:   # missing items: {}", missing_items_bob.len());
 
@@ -171,3 +226,392 @@ fn sync_10k_msgs() {
     assert!(alice_eq, "a does not match");
     assert!(bob_eq, "a does not match");
 }
+
+struct SyncCost {
+    rounds: usize,
+    bytes: usize,
+    bytes_alice: usize,
+    bytes_bob: usize,
+    largest_message: usize,
+    stats_alice: SyncStats,
+    stats_bob: SyncStats,
+}
+
+/// How a sync in [`sync`] is set up.
+#[derive(Debug, Clone, Copy)]
+struct SyncConfig {
+    limits: MessageLimits,
+    /// From the point of view of alice, who starts the session.
+    direction: Direction,
+    /// If not zero, small ranges are compared by short ids of that length.
+    short_id_len: u8,
+    /// If not zero, ranges with few differences are reconciled with sketches of that many cells.
+    sketch_cells: u32,
+    /// Whether both sides adapt the threshold and the split to the differences.
+    adaptive: bool,
+    /// Alice misses every n-th item.
+    alice_misses_every: usize,
+    /// Bob misses every n-th item, offset from the ones alice misses.
+    bob_misses_every: usize,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            limits: MessageLimits::default(),
+            direction: Direction::Both,
+            short_id_len: 0,
+            sketch_cells: 0,
+            adaptive: false,
+            alice_misses_every: 10,
+            bob_misses_every: 40,
+        }
+    }
+}
+
+fn sync_with_strategy<Sp: SplitStrategy<UniformMonoid> + Copy>(split: Sp) -> SyncCost {
+    sync(split, SyncConfig::default())
+}
+
+fn sync_with_limits<Sp: SplitStrategy<UniformMonoid> + Copy>(
+    split: Sp,
+    limits: MessageLimits,
+) -> SyncCost {
+    sync(
+        split,
+        SyncConfig {
+            limits,
+            ..Default::default()
+        },
+    )
+}
+
+/// Syncs 3000 items between alice and bob, where by default alice misses every 10th item and bob
+/// every 40th.
+fn sync<Sp: SplitStrategy<UniformMonoid> + Copy>(split: Sp, config: SyncConfig) -> SyncCost {
+    let SyncConfig {
+        limits,
+        direction,
+        short_id_len,
+        sketch_cells,
+        adaptive,
+        alice_misses_every,
+        bob_misses_every,
+    } = config;
+
+    let mut alice_tree = UniformNode::nil();
+    let mut alice_object_store = BTreeMap::new();
+    let mut bob_tree = UniformNode::nil();
+    let mut bob_object_store = BTreeMap::new();
+
+    let mut all_items = vec![];
+    let mut rng = ChaCha8Rng::from_seed([42u8; 32]);
+    for i in 0..3_000 {
+        let mut item = UniformItem::default();
+        rng.fill(&mut item.0);
+        all_items.push(item);
+
+        if i % alice_misses_every != 0 {
+            alice_tree = alice_tree.insert(item);
+            alice_object_store.insert(item, (item, true));
+        }
+        if i % bob_misses_every != 5 {
+            bob_tree = bob_tree.insert(item);
+            bob_object_store.insert(item, (item, true));
+        }
+    }
+
+    all_items.sort();
+    let expected_alice: Vec<_> = if direction.learns() {
+        all_items.clone()
+    } else {
+        alice_object_store.keys().cloned().collect()
+    };
+    let expected_bob: Vec<_> = if direction.shares() {
+        all_items.clone()
+    } else {
+        bob_object_store.keys().cloned().collect()
+    };
+
+    // a fixed key, so the costs don't change from run to run
+    let mut alice = Session::new(Role::Initiator, 3, split)
+        .with_limits(limits)
+        .with_max_rounds(1000)
+        .with_direction(direction)
+        .with_short_ids(short_id_len)
+        .with_short_id_key(ShortIdKey(1, 2))
+        .with_sketches(sketch_cells)
+        .with_adaptive_tuning(adaptive)
+        .with_part_sizes(true);
+    let mut bob = Session::new(Role::Responder, 3, split)
+        .with_limits(limits)
+        .with_max_rounds(1000)
+        .with_adaptive_tuning(adaptive)
+        .with_part_sizes(true);
+
+    let mut largest_message = 0;
+    let mut count_bytes = |msg: &Message<UniformMonoid, (UniformItem, bool)>| {
+        let len = encode_message(limits.format, msg).unwrap().len();
+        largest_message = largest_message.max(len);
+        len
+    };
+
+    let msg: Message<_, (UniformItem, bool)> = alice.start(&alice_tree).unwrap();
+    let mut bytes_alice = count_bytes(&msg);
+    let mut bytes_bob = 0;
+    let mut next = Some(msg);
+
+    let mut missing_items_alice = vec![];
+    let mut missing_items_bob = vec![];
+
+    while let Some(msg) = next {
+        let outcome = bob.handle(&bob_tree, &bob_object_store, &msg).unwrap();
+        assert!(outcome.rejected().is_empty());
+        let (reply, received) = outcome.into_parts();
+        missing_items_bob.extend(received.into_accepted().into_iter().map(|(item, _)| item));
+        let Some(reply) = reply else { break };
+        bytes_bob += count_bytes(&reply);
+
+        let outcome = alice
+            .handle(&alice_tree, &alice_object_store, &reply)
+            .unwrap();
+        assert!(outcome.rejected().is_empty());
+        let (reply, received) = outcome.into_parts();
+        missing_items_alice.extend(received.into_accepted().into_iter().map(|(item, _)| item));
+        if let Some(reply) = &reply {
+            bytes_alice += count_bytes(reply);
+        }
+
+        next = reply;
+    }
+
+    assert!(alice.is_finished());
+    assert!(bob.is_finished());
+    assert_eq!(bob.direction(), direction.reverse());
+    let rounds = alice.stats().rounds;
+
+    for (tree, store, missing, expected) in [
+        (
+            &mut alice_tree,
+            &mut alice_object_store,
+            missing_items_alice,
+            expected_alice,
+        ),
+        (
+            &mut bob_tree,
+            &mut bob_object_store,
+            missing_items_bob,
+            expected_bob,
+        ),
+    ] {
+        for item in missing {
+            *tree = tree.insert(item);
+            store.insert(item, (item, true));
+        }
+
+        let synced: Vec<_> = store.keys().cloned().collect();
+        assert_eq!(synced, expected);
+    }
+
+    SyncCost {
+        rounds,
+        bytes: bytes_alice + bytes_bob,
+        bytes_alice,
+        bytes_bob,
+        largest_message,
+        stats_alice: alice.stats().clone(),
+        stats_bob: bob.stats().clone(),
+    }
+}
+
+#[test]
+fn compare_split_strategies() {
+    let costs = [
+        ("uniform/2", sync_with_strategy(UniformSplit::<2>)),
+        ("uniform/8", sync_with_strategy(UniformSplit::<8>)),
+        ("exponential/4", sync_with_strategy(ExponentialSplit::<4>)),
+        ("adaptive/16", sync_with_strategy(AdaptiveSplit::<16>)),
+    ];
+
+    for (name, cost) in &costs {
+        let SyncCost { rounds, bytes, .. } = cost;
+        println!("{name:>16}: {rounds:>3} rounds, {bytes:>8} bytes");
+        for (side, stats) in [("alice", &cost.stats_alice), ("bob", &cost.stats_bob)] {
+            println!(
+                "{side:>16}: {:>5} fps sent, {:>5} of {:>5} mismatched, {:>4} item sets with {:>5} items, {:>4} objects sent, {:?} decoding, {:?} encoding",
+                stats.fingerprints_sent,
+                stats.fingerprint_mismatches,
+                stats.fingerprints_compared,
+                stats.item_sets_sent,
+                stats.items_sent,
+                stats.objects_sent,
+                stats.decode_time,
+                stats.encode_time,
+            );
+            println!("{:>16}  {:?}", "", stats.bytes_sent);
+
+            // the parts are encoded separately, without the framing of the messages
+            assert!(stats.bytes_sent.total() > 0);
+        }
+        assert!(cost.stats_alice.bytes_sent.total() <= cost.bytes_alice);
+        assert!(cost.stats_bob.bytes_sent.total() <= cost.bytes_bob);
+        assert_eq!(
+            cost.stats_alice.fingerprints_sent,
+            cost.stats_bob.fingerprints_compared
+        );
+        assert_eq!(cost.stats_alice.objects_sent, 75);
+        assert_eq!(cost.stats_bob.objects_sent, 300);
+    }
+
+    // wider splits should get us there in fewer rounds
+    let uniform_2 = &costs[0].1;
+    let uniform_8 = &costs[1].1;
+    assert!(uniform_8.rounds < uniform_2.rounds);
+}
+
+#[test]
+fn sync_with_message_limits() {
+    let unlimited = sync_with_limits(UniformSplit::<2>, MessageLimits::default());
+
+    let limits = MessageLimits {
+        max_bytes: 4096,
+        max_objects: 64,
+        ..Default::default()
+    };
+    let limited = sync_with_limits(UniformSplit::<2>, limits);
+
+    println!(
+        "unlimited: {:>3} rounds, largest message {:>6} bytes",
+        unlimited.rounds, unlimited.largest_message
+    );
+    println!(
+        "  limited: {:>3} rounds, largest message {:>6} bytes",
+        limited.rounds, limited.largest_message
+    );
+
+    assert!(unlimited.largest_message > limits.max_bytes);
+    assert!(limited.largest_message <= limits.max_bytes);
+    assert!(limited.rounds > unlimited.rounds);
+}
+
+#[test]
+fn sync_one_direction() {
+    let both = sync(UniformSplit::<2>, SyncConfig::default());
+    let pull = sync(
+        UniformSplit::<2>,
+        SyncConfig {
+            direction: Direction::Pull,
+            ..Default::default()
+        },
+    );
+    let push = sync(
+        UniformSplit::<2>,
+        SyncConfig {
+            direction: Direction::Push,
+            ..Default::default()
+        },
+    );
+
+    for (name, cost) in [("both", &both), ("pull", &pull), ("push", &push)] {
+        println!(
+            "{name}: {:>3} rounds, alice sent {:>7} bytes, bob sent {:>7} bytes",
+            cost.rounds, cost.bytes_alice, cost.bytes_bob
+        );
+    }
+
+    // a single item with its object, as sent in item sets and provides
+    let format = Format::default();
+    let item_bytes = format.encode(&UniformItem::default()).unwrap().len();
+    let object_bytes = format
+        .encode(&(UniformItem::default(), true))
+        .unwrap()
+        .len();
+
+    // when pulling, alice doesn't list her items to bob, and bob doesn't ask for or receive the
+    // 75 items he misses. when pushing, the same goes for the 300 items alice misses.
+    let pull_saved = both.bytes - pull.bytes;
+    let push_saved = both.bytes - push.bytes;
+    assert!(pull_saved >= 75 * (item_bytes + object_bytes));
+    assert!(push_saved >= 300 * (item_bytes + object_bytes));
+    assert!(push_saved > pull_saved);
+
+    // the side that doesn't share only sends fingerprints and requests
+    assert!(pull.bytes_alice < both.bytes_alice);
+    assert!(push.bytes_bob < both.bytes_bob);
+}
+
+#[test]
+fn sync_with_short_ids() {
+    let full = sync(UniformSplit::<8>, SyncConfig::default());
+    let short = sync(
+        UniformSplit::<8>,
+        SyncConfig {
+            short_id_len: 6,
+            ..Default::default()
+        },
+    );
+
+    for (name, cost) in [("full items", &full), ("short ids", &short)] {
+        println!("{name}: {:>3} rounds, {:>7} bytes", cost.rounds, cost.bytes);
+    }
+
+    // resolving the ids we don't know takes an extra round trip
+    assert!(short.bytes < full.bytes);
+    assert!(short.rounds <= full.rounds + 2);
+}
+
+#[test]
+fn sync_with_sketches() {
+    let split = sync(UniformSplit::<2>, SyncConfig::default());
+    let sketched = sync(
+        UniformSplit::<2>,
+        SyncConfig {
+            sketch_cells: 64,
+            ..Default::default()
+        },
+    );
+
+    for (name, cost) in [("splitting", &split), ("sketches", &sketched)] {
+        println!("{name}: {:>3} rounds, {:>7} bytes", cost.rounds, cost.bytes);
+    }
+
+    // ranges with few differences are settled in one round trip instead of being split further
+    assert!(sketched.rounds < split.rounds);
+}
+
+#[test]
+fn sync_with_adaptive_tuning() {
+    let scenarios = [("sparse", 500, 1000), ("default", 10, 40), ("dense", 2, 3)];
+
+    let mut costs = vec![];
+    for (name, alice_misses_every, bob_misses_every) in scenarios {
+        let config = SyncConfig {
+            alice_misses_every,
+            bob_misses_every,
+            ..Default::default()
+        };
+        let fixed = sync(UniformSplit::<2>, config);
+        let adaptive = sync(
+            UniformSplit::<2>,
+            SyncConfig {
+                adaptive: true,
+                ..config
+            },
+        );
+
+        println!(
+            "{name:>8}: fixed {:>3} rounds, {:>7} bytes; adaptive {:>3} rounds, {:>7} bytes",
+            fixed.rounds, fixed.bytes, adaptive.rounds, adaptive.bytes
+        );
+        costs.push((fixed, adaptive));
+    }
+
+    // sparse differences are found with wider splits
+    let (fixed, adaptive) = &costs[0];
+    assert!(adaptive.rounds < fixed.rounds);
+
+    // dense differences are listed early, when most of the listed items are needed anyways
+    let (fixed, adaptive) = &costs[2];
+    assert!(adaptive.rounds <= fixed.rounds);
+    assert!(adaptive.bytes < fixed.bytes);
+}
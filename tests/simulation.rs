@@ -0,0 +1,315 @@
+//! Simulates a network of nodes that sync with each other over unreliable links, and checks that
+//! they all end up with the same set.
+
+extern crate alloc;
+use alloc::{collections::VecDeque, vec, vec::Vec};
+
+extern crate std;
+use std::{collections::BTreeMap, println};
+
+use unionize::{
+    easy::uniform::{Item as UniformItem, Monoid as UniformMonoid, Node as UniformNode},
+    protocol::{
+        decode_message, encode_message, split::UniformSplit, Format, MessageLimits, RejectReason,
+        Role, Session,
+    },
+};
+
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+
+type Object = (UniformItem, bool);
+
+/// How often things go wrong on a link. Each is the probability per message.
+#[derive(Debug, Clone, Copy)]
+struct Faults {
+    loss: f64,
+    duplication: f64,
+    /// The message is delivered after one that was sent later.
+    reordering: f64,
+    /// The connection breaks before the message is delivered, ending the session.
+    disconnect: f64,
+}
+
+/// What happened during a simulation.
+#[derive(Debug, Default)]
+struct Counters {
+    sessions: usize,
+    /// Sessions in which both peers finished.
+    completed: usize,
+    delivered: usize,
+    lost: usize,
+    duplicated: usize,
+    reordered: usize,
+    disconnects: usize,
+    /// Sessions that ended because a peer rejected a message.
+    errors: usize,
+    /// Sessions that ended because no more messages were in flight.
+    stalled: usize,
+    /// Objects a node didn't accept because it didn't ask for them (anymore).
+    rejected: usize,
+}
+
+#[derive(Debug, Clone)]
+struct SimNode {
+    tree: UniformNode,
+    objects: BTreeMap<UniformItem, Object>,
+}
+
+impl SimNode {
+    fn new() -> Self {
+        Self {
+            tree: UniformNode::nil(),
+            objects: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, obj: Object) {
+        // the tree doesn't deduplicate
+        if self.objects.insert(obj.0, obj).is_none() {
+            self.tree = self.tree.insert(obj.0);
+        }
+    }
+}
+
+struct Simulation {
+    rng: ChaCha8Rng,
+    nodes: Vec<SimNode>,
+    /// Every item that was ever added to any node.
+    universe: BTreeMap<UniformItem, Object>,
+    faults: Faults,
+    counters: Counters,
+}
+
+impl Simulation {
+    fn new(seed: u64, nodes: usize, faults: Faults) -> Self {
+        Self {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            nodes: vec![SimNode::new(); nodes],
+            universe: BTreeMap::new(),
+            faults,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Adds up to `max` new items to a random node.
+    fn insert_random(&mut self, max: usize) {
+        let node = self.rng.gen_range(0..self.nodes.len());
+        for _ in 0..self.rng.gen_range(1..=max) {
+            let mut item = UniformItem::default();
+            self.rng.fill(&mut item.0);
+            self.universe.insert(item, (item, true));
+            self.nodes[node].add((item, true));
+        }
+    }
+
+    /// Runs a session between two random nodes.
+    fn sync_random(&mut self) {
+        let a = self.rng.gen_range(0..self.nodes.len());
+        let b = (a + self.rng.gen_range(1..self.nodes.len())) % self.nodes.len();
+        self.sync(a, b);
+    }
+
+    /// Runs a session between `a` and `b`, with messages going through a faulty link. The
+    /// objects a node received are added once the session is over, however it ended.
+    fn sync(&mut self, a: usize, b: usize) {
+        let format = Format::default();
+        let limits = MessageLimits {
+            max_bytes: 2000,
+            format,
+            ..Default::default()
+        };
+        let ids = [a, b];
+        let mut sessions = [
+            Session::new(Role::Initiator, 3, UniformSplit::<4>).with_limits(limits),
+            Session::new(Role::Responder, 3, UniformSplit::<4>).with_limits(limits),
+        ];
+        let mut received: [Vec<Object>; 2] = [vec![], vec![]];
+        self.counters.sessions += 1;
+
+        // the messages on the wire, with the side they are sent to
+        let mut in_flight = VecDeque::new();
+        let first = sessions[0].start(&self.nodes[a].tree).unwrap();
+        in_flight.push_back((1, encode_message(format, &first).unwrap()));
+
+        while let Some((to, bytes)) = in_flight.pop_front() {
+            let faults = self.faults;
+            if self.rng.gen_bool(faults.disconnect) {
+                self.counters.disconnects += 1;
+                break;
+            }
+            if self.rng.gen_bool(faults.loss) {
+                self.counters.lost += 1;
+                continue;
+            }
+            if self.rng.gen_bool(faults.duplication) {
+                self.counters.duplicated += 1;
+                in_flight.push_back((to, bytes.clone()));
+            }
+            if !in_flight.is_empty() && self.rng.gen_bool(faults.reordering) {
+                self.counters.reordered += 1;
+                let later = self.rng.gen_range(0..in_flight.len());
+                in_flight.insert(later + 1, (to, bytes));
+                continue;
+            }
+
+            self.counters.delivered += 1;
+            let msg = decode_message(format, &bytes).unwrap();
+            let node = &self.nodes[ids[to]];
+            let outcome = match sessions[to].handle(&node.tree, &node.objects, &msg) {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    // the peer hangs up on messages that make no sense to it
+                    self.counters.errors += 1;
+                    break;
+                }
+            };
+
+            let (reply, objects) = outcome.into_parts();
+            let (accepted, rejected) = objects.into_parts();
+            // objects delivered twice are no longer requested the second time
+            for rejected in &rejected {
+                assert_ne!(rejected.reason(), RejectReason::Inconsistent);
+            }
+            self.counters.rejected += rejected.len();
+            received[to].extend(accepted);
+            if let Some(reply) = reply {
+                in_flight.push_back((1 - to, encode_message(format, &reply).unwrap()));
+            }
+        }
+
+        if sessions.iter().all(Session::is_finished) {
+            self.counters.completed += 1;
+        } else if in_flight.is_empty() {
+            self.counters.stalled += 1;
+        }
+
+        for (side, objects) in received.into_iter().enumerate() {
+            for obj in objects {
+                // whatever happens on the wire, nodes only learn about items that exist
+                assert_eq!(self.universe.get(&obj.0), Some(&obj));
+                self.nodes[ids[side]].add(obj);
+            }
+        }
+    }
+
+    fn converged(&self) -> bool {
+        self.nodes
+            .iter()
+            .all(|node| node.objects.len() == self.universe.len())
+    }
+
+    /// Checks that all nodes hold every item, in trees with the same root monoid.
+    fn check_converged(&self) {
+        let mut reference = UniformNode::nil();
+        for item in self.universe.keys() {
+            reference = reference.insert(*item);
+        }
+
+        for node in &self.nodes {
+            assert!(node.objects.keys().eq(self.universe.keys()));
+            let monoid: &UniformMonoid = node.tree.monoid();
+            assert_eq!(monoid, reference.monoid());
+        }
+    }
+}
+
+fn simulate(seed: u64, nodes: usize, faults: Faults) -> Counters {
+    let mut sim = Simulation::new(seed, nodes, faults);
+    for _ in 0..nodes {
+        sim.insert_random(200);
+    }
+
+    // nodes keep adding items while they sync
+    for _ in 0..100 {
+        if sim.rng.gen_bool(0.3) {
+            sim.insert_random(20);
+        } else {
+            sim.sync_random();
+        }
+    }
+
+    // once the items stop coming, the links are still as bad, but the nodes get there
+    let mut sessions = 0;
+    while !sim.converged() {
+        assert!(sessions < 1000, "no convergence after {sessions} sessions");
+        sim.sync_random();
+        sessions += 1;
+    }
+
+    sim.check_converged();
+    sim.counters
+}
+
+#[test]
+fn converges_over_unreliable_links() {
+    let faults = Faults {
+        loss: 0.02,
+        duplication: 0.02,
+        reordering: 0.05,
+        disconnect: 0.01,
+    };
+
+    let mut total = Counters::default();
+    for seed in 0..4 {
+        let counters = simulate(seed, 5, faults);
+        println!("seed {seed}: {counters:?}");
+
+        total.sessions += counters.sessions;
+        total.completed += counters.completed;
+        total.lost += counters.lost;
+        total.duplicated += counters.duplicated;
+        total.reordered += counters.reordered;
+        total.disconnects += counters.disconnects;
+        total.errors += counters.errors;
+        total.stalled += counters.stalled;
+        total.rejected += counters.rejected;
+    }
+
+    // make sure every kind of fault actually happened
+    assert!(total.completed > 0 && total.completed < total.sessions);
+    assert!(total.lost > 0 && total.stalled > 0);
+    assert!(total.duplicated > 0 && total.reordered > 0);
+    assert!(total.disconnects > 0);
+}
+
+#[test]
+fn converges_over_reliable_links() {
+    let faults = Faults {
+        loss: 0.0,
+        duplication: 0.0,
+        reordering: 0.0,
+        disconnect: 0.0,
+    };
+
+    let counters = simulate(42, 8, faults);
+    assert_eq!(counters.completed, counters.sessions);
+}
+
+#[test]
+fn is_deterministic() {
+    let faults = Faults {
+        loss: 0.05,
+        duplication: 0.05,
+        reordering: 0.05,
+        disconnect: 0.02,
+    };
+
+    let run = |seed| {
+        let mut sim = Simulation::new(seed, 4, faults);
+        for _ in 0..4 {
+            sim.insert_random(50);
+        }
+        for _ in 0..20 {
+            sim.sync_random();
+        }
+        let sets: Vec<Vec<UniformItem>> = sim
+            .nodes
+            .iter()
+            .map(|node| node.objects.keys().cloned().collect())
+            .collect();
+        (sets, format!("{:?}", sim.counters))
+    };
+
+    assert_eq!(run(7), run(7));
+}
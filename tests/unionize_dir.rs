@@ -0,0 +1,92 @@
+//! Runs the `unionize-dir` binary on temporary directories and checks that both end up with the
+//! files of both.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A fresh directory under the system's temporary directory, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("unionize-dir-test.{}.{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn files(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            (name, fs::read(entry.path()).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn syncs_local_directories() {
+    let a = TempDir::new("a");
+    let b = TempDir::new("b");
+
+    for i in 0..20 {
+        fs::write(a.0.join(format!("shared-{i}")), format!("shared {i}")).unwrap();
+        fs::write(b.0.join(format!("shared-{i}")), format!("shared {i}")).unwrap();
+    }
+    for i in 0..5 {
+        fs::write(a.0.join(format!("a-{i}")), format!("only in a {i}")).unwrap();
+        fs::write(b.0.join(format!("b-{i}")), format!("only in b {i}")).unwrap();
+    }
+    // same name, other contents: neither side may lose its version
+    fs::write(a.0.join("clash"), "a's clash").unwrap();
+    fs::write(b.0.join("clash"), "b's clash").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_unionize-dir"))
+        .arg("local")
+        .arg(&a.0)
+        .arg(&b.0)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let files_a = files(&a.0);
+    let files_b = files(&b.0);
+    assert_eq!(files_a.len(), 20 + 10 + 2);
+
+    // the contents match, even if the clashing file got a new name on each side
+    let contents = |files: &BTreeMap<String, Vec<u8>>| {
+        let mut contents: Vec<Vec<u8>> = files.values().cloned().collect();
+        contents.sort();
+        contents
+    };
+    assert_eq!(contents(&files_a), contents(&files_b));
+    assert_eq!(files_a["clash"], b"a's clash");
+    assert_eq!(files_b["clash"], b"b's clash");
+    assert_eq!(files_a["b-3"], b"only in b 3");
+    assert_eq!(files_b["a-3"], b"only in a 3");
+
+    // syncing again changes nothing
+    let status = Command::new(env!("CARGO_BIN_EXE_unionize-dir"))
+        .arg("local")
+        .arg(&a.0)
+        .arg(&b.0)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(files(&a.0), files_a);
+    assert_eq!(files(&b.0), files_b);
+}